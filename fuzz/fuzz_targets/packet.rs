@@ -0,0 +1,49 @@
+//! Feed arbitrary bytes to everything that parses packets off the network.
+//!
+//! Run with `cargo +nightly fuzz run packet` from this directory.
+
+#![no_main]
+
+use std::time::Duration;
+
+use internet_packet::InternetPacket;
+use libfuzzer_sys::fuzz_target;
+use mitmproxy::packet;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = packet::IpHeader::parse(data);
+    let _ = packet::locate_transport(data);
+    let _ = packet::tcp_seq(data);
+    let _ = packet::tcp_ack(data);
+    let _ = packet::tcp_window(data);
+    let _ = packet::tcp_options(data);
+    let _ = packet::validate_checksums(data);
+    if let Ok(icmp) = packet::IcmpPacket::parse(data) {
+        let _ = icmp.quoted_connection_id(data);
+    }
+    if let Ok(offset) = packet::payload_offset(data) {
+        let payload = &data[offset..];
+        let _ = packet::tls_sni(payload);
+        let _ = packet::dns_qname(payload);
+        let _ = packet::is_quic(payload);
+    }
+
+    let mut copy = data.to_vec();
+    let _ = packet::recompute_checksums(&mut copy);
+    let mut copy = data.to_vec();
+    let _ = packet::decrement_ttl(&mut copy);
+    let mut copy = data.to_vec();
+    let _ = packet::set_payload(&mut copy, b"fuzz");
+
+    let mut fragments = packet::FragmentReassembler::new(Duration::from_secs(30));
+    let _ = fragments.process(data.to_vec(), ());
+
+    if let Ok(mut packet) = InternetPacket::try_from(data.to_vec()) {
+        let _ = packet.connection_id();
+        let _ = packet.payload();
+        let _ = packet.tcp_flag_str();
+        packet.recalculate_ip_checksum();
+        packet.recalculate_tcp_checksum();
+        packet.recalculate_udp_checksum();
+    }
+});
@@ -18,9 +18,9 @@ use tun::AbstractDevice;
 use prost::Message;
 use tokio::io::AsyncReadExt;
 use tokio::signal::unix::{signal, SignalKind};
-use mitmproxy::ipc::{PacketWithMeta, from_proxy};
+use mitmproxy::ipc::{PacketWithMeta, from_proxy, from_redirector, FromRedirector, Hello};
 use mitmproxy::ipc::FromProxy;
-use mitmproxy::packet_sources::IPC_BUF_SIZE;
+use mitmproxy::packet_sources::{IPC_BUF_SIZE, PROTOCOL_VERSION};
 use mitmproxy_linux_ebpf_common::{Action, INTERCEPT_CONF_LEN};
 
 // We can't implement aya::Pod in mitmproxy-linux-ebpf-common, so we do it on a newtype.
@@ -98,6 +98,11 @@ async fn main() -> anyhow::Result<()> {
     fs::set_permissions(&redirector_addr, Permissions::from_mode(0o777))?;
     println!("{}", redirector_addr.to_string_lossy());
 
+    let hello = FromRedirector {
+        message: Some(from_redirector::Message::Hello(Hello { version: PROTOCOL_VERSION, lz4: false, json: false, pid: None, connection_events: false })),
+    };
+    ipc.send(&hello.encode_to_vec()).await.context("failed to send IPC handshake")?;
+
     // Exit cleanly on SIGINT/SIGTERM
     tokio::spawn(async {
         let mut sigint = signal(SignalKind::interrupt()).context("failed to register SIGINT listener").unwrap();
@@ -124,6 +129,29 @@ async fn main() -> anyhow::Result<()> {
                         // debug!("Received IPC message: {message:?}");
 
                         match message {
+                            from_proxy::Message::Hello(Hello { version, .. }) => {
+                                if version != PROTOCOL_VERSION {
+                                    return Err(anyhow!(
+                                        "mitmproxy speaks IPC protocol version {version}, but the redirector expects version {PROTOCOL_VERSION}."
+                                    ));
+                                }
+                            }
+                            // We never send pings.
+                            from_proxy::Message::Pong(_) => {}
+                            // Packet counters and the connection table only exist in the Windows redirector.
+                            from_proxy::Message::StatsRequest(_) => {}
+                            from_proxy::Message::ConnectionTableRequest(_) => {}
+                            from_proxy::Message::ResetConnection(_) => {}
+                            from_proxy::Message::SetDryRun(_) => {}
+                            // We never rewrite destinations.
+                            from_proxy::Message::OriginalDstRequest(_) => {}
+                            // mitmproxy always sends us the whole intercept conf.
+                            from_proxy::Message::AddInterceptPid(_) => {}
+                            from_proxy::Message::RemoveInterceptPid(_) => {}
+                            from_proxy::Message::Shutdown(_) => {
+                                info!("Shutting down.");
+                                std::process::exit(0);
+                            }
                             from_proxy::Message::Packet(packet) => {
                                 // debug!("Forwarding Packet to device: {}", packet.data.len());
                                 device.send(&packet.data).await.context("failed to send packet")?;
@@ -155,9 +183,11 @@ async fn main() -> anyhow::Result<()> {
             r = device.read_buf(&mut dev_buf) => {
                 r.context("TUN read() failed")?;
 
-                let packet = PacketWithMeta {
-                    data: dev_buf.split().freeze(),
-                    tunnel_info: None,
+                let packet = FromRedirector {
+                    message: Some(from_redirector::Message::Packet(PacketWithMeta {
+                        data: dev_buf.split().freeze(),
+                        tunnel_info: None,
+                    })),
                 };
 
                 packet.encode(&mut ipc_buf)?;
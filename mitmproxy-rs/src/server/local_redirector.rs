@@ -30,20 +30,28 @@ impl LocalRedirector {
     }
 }
 
+/// Parse an intercept spec, rejecting rules the redirector for this platform does not support.
+fn parse_spec(spec: &str) -> anyhow::Result<InterceptConf> {
+    let conf = InterceptConf::try_from(spec)?;
+    #[cfg(not(windows))]
+    conf.ensure_process_rules_only()?;
+    Ok(conf)
+}
+
 #[pymethods]
 impl LocalRedirector {
     /// Return a textual description of the given spec,
     /// or raise a ValueError if the spec is invalid.
     #[staticmethod]
     fn describe_spec(spec: &str) -> PyResult<String> {
-        InterceptConf::try_from(spec)
+        parse_spec(spec)
             .map(|conf| conf.description())
             .map_err(|e| pyo3::exceptions::PyValueError::new_err(e.to_string()))
     }
 
     /// Set a new intercept spec.
     pub fn set_intercept(&mut self, spec: String) -> PyResult<()> {
-        let conf = InterceptConf::try_from(spec.as_str())?;
+        let conf = parse_spec(spec.as_str())?;
         self.spec = spec;
         self.conf_tx
             .send(conf)
@@ -1,46 +1,754 @@
 
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::env;
+use std::fmt::Write as _;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write as _};
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
-use std::time::Duration;
-use std::{env, thread};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 
 use anyhow::{anyhow, Context, Result};
+use clap::Parser;
 use internet_packet::{ConnectionId, InternetPacket, TransportProtocol};
-use log::{debug, error, info, warn};
 use lru_time_cache::LruCache;
-use mitmproxy::intercept_conf::{InterceptConf, ProcessInfo};
+use mitmproxy::intercept_conf::{ConnectionContext, InterceptConf, ProcessInfo};
 use mitmproxy::ipc;
-use mitmproxy::ipc::FromProxy;
-use mitmproxy::packet_sources::IPC_BUF_SIZE;
-use mitmproxy::windows::network::network_table;
-use mitmproxy::processes::get_process_name;
-use mitmproxy::MAX_PACKET_SIZE;
-use prost::Message;
-use std::io::Cursor;
+use mitmproxy::ipc::redirector::{handle_ipc, handshake, load_conf_cache, Disconnect};
+use mitmproxy::packet;
+use mitmproxy::packet::{CanonicalConnectionId, ConnectionIdExt, Direction, IpProtocol};
+use mitmproxy::windows::clock;
+use mitmproxy::windows::elevation;
+use mitmproxy::windows::network::{link_local_addresses, network_table};
+use mitmproxy::windows::pipe::PipeSecurity;
+use mitmproxy::processes::{ParentProcessCache, ProcessNameCache};
+use smallvec::{smallvec, SmallVec};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::windows::named_pipe::{ClientOptions, NamedPipeClient, PipeMode};
+use tokio::net::windows::named_pipe::{
+    ClientOptions, NamedPipeClient, NamedPipeServer, PipeMode, ServerOptions,
+};
+use tokio::net::TcpListener;
+use tokio::sync::broadcast;
 use tokio::sync::mpsc;
-use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::{Receiver, Sender, UnboundedReceiver, UnboundedSender};
+use tokio::task::JoinSet;
+use tokio_util::codec::Framed;
+use tracing::{debug, error, info, instrument, warn};
+use tracing_subscriber::EnvFilter;
 use windivert::address::WinDivertAddress;
+use windivert::error::{WinDivertError, WinDivertOpenError, WinDivertRecvError};
 use windivert::prelude::*;
 
 #[derive(Debug)]
 enum Event {
     NetworkPacket(WinDivertAddress<NetworkLayer>, Vec<u8>),
+    ForwardPacket(WinDivertAddress<ForwardLayer>, Vec<u8>),
     SocketInfo(WinDivertAddress<SocketLayer>),
     Ipc(ipc::from_proxy::Message),
+    /// A packet we captured for mitmproxy, which continues on its way because mitmproxy shut down
+    /// before receiving it.
+    ReleasedPacket(Vec<u8>),
+    /// The process id mitmproxy sent in the handshake, after every (re)connect.
+    ControllerPid(Option<u32>),
+}
+
+impl From<ipc::from_proxy::Message> for Event {
+    fn from(message: ipc::from_proxy::Message) -> Self {
+        Event::Ipc(message)
+    }
 }
 
 #[derive(Debug)]
 enum ConnectionState {
-    Known(ConnectionAction),
-    Unknown(Vec<(WinDivertAddress<NetworkLayer>, InternetPacket)>),
+    /// The action applies to packets in the given direction.
+    /// Packets in the other direction are always passed through.
+    Known(ConnectionAction, Direction, Observed),
+    Unknown(PacketBuffer),
+}
+
+impl ConnectionState {
+    /// Count a packet of a known connection.
+    /// Packets of unknown connections are counted once they are released.
+    fn count(&mut self, direction: Direction, len: usize) {
+        if let ConnectionState::Known(_, action_direction, observed) = self {
+            observed.counters.count(direction == *action_direction, len);
+        }
+    }
+
+    /// Remember where the first packet in the action direction came from.
+    fn observe(&mut self, direction: Direction, address: &WinDivertAddress<NetworkLayer>) {
+        if let ConnectionState::Known(_, action_direction, observed) = self {
+            if direction == *action_direction && observed.origin.is_none() {
+                observed.origin = Some(Origin::of(address));
+            }
+        }
+    }
+
+    /// Extract metadata from the first payload in the action direction.
+    fn peek(&mut self, direction: Direction, packet: &InternetPacket) {
+        if let ConnectionState::Known(_, action_direction, observed) = self {
+            if direction == *action_direction
+                && observed.metadata.is_none()
+                && !packet.payload().is_empty()
+            {
+                let metadata = Metadata::of(packet);
+                debug!(
+                    connection_id = %packet.connection_id(),
+                    server_name = ?metadata.server_name,
+                    len = metadata.first_payload_len,
+                    "Peeked at first payload."
+                );
+                observed.metadata = Some(metadata);
+            }
+        }
+    }
+}
+
+/// What we have learned about a known connection from its packets.
+#[derive(Debug, Default)]
+struct Observed {
+    counters: Counters,
+    origin: Option<Origin>,
+    metadata: Option<Metadata>,
+}
+
+/// Application-layer details of a connection, extracted once from its first payload so that
+/// later lookups do not need to parse packets again.
+#[derive(Debug, Clone)]
+struct Metadata {
+    /// The TLS SNI or the DNS query name.
+    server_name: Option<String>,
+    first_payload_len: usize,
+}
+
+impl Metadata {
+    fn of(packet: &InternetPacket) -> Self {
+        let payload = packet.payload();
+        let server_name = match packet.protocol() {
+            TransportProtocol::Tcp => packet::tls_sni(payload),
+            TransportProtocol::Udp => packet::dns_qname(payload),
+        };
+        Metadata {
+            server_name,
+            first_payload_len: payload.len(),
+        }
+    }
+}
+
+/// The direction and interface of a connection's original packets, so that mitmproxy's
+/// packets for that connection can be injected the other way on the same interface.
+#[derive(Debug, Clone, Copy)]
+struct Origin {
+    outbound: bool,
+    loopback: bool,
+    interface_index: u32,
+    subinterface_index: u32,
+}
+
+impl Origin {
+    fn of(address: &WinDivertAddress<NetworkLayer>) -> Self {
+        Origin {
+            outbound: address.outbound(),
+            loopback: address.loopback(),
+            interface_index: address.interface_index(),
+            subinterface_index: address.subinterface_index(),
+        }
+    }
+
+    /// Set up the address of a packet that travels in the same direction as the original packets.
+    fn apply(&self, address: &mut WinDivertAddress<NetworkLayer>) {
+        address.set_outbound(self.outbound);
+        address.set_loopback(self.loopback);
+        address.set_interface_index(self.interface_index);
+        address.set_subinterface_index(self.subinterface_index);
+    }
+
+    /// Set up the address of a packet sent by mitmproxy in reply to the original packets.
+    fn reply(&self, address: &mut WinDivertAddress<NetworkLayer>) {
+        // Loopback packets are always outbound.
+        address.set_outbound(self.loopback || !self.outbound);
+        address.set_loopback(self.loopback);
+        address.set_interface_index(self.interface_index);
+        address.set_subinterface_index(self.subinterface_index);
+    }
+}
+
+/// Packets and payload bytes of a connection. Sent packets are those in the direction the
+/// connection's action applies to, i.e. from the process that opened it.
+#[derive(Debug, Default, Clone, Copy)]
+struct Counters {
+    packets_sent: u64,
+    bytes_sent: u64,
+    packets_received: u64,
+    bytes_received: u64,
+}
+
+impl Counters {
+    fn count(&mut self, sent: bool, len: usize) {
+        if sent {
+            self.packets_sent += 1;
+            self.bytes_sent += len as u64;
+        } else {
+            self.packets_received += 1;
+            self.bytes_received += len as u64;
+        }
+    }
 }
 
+/// The fragments a datagram was reassembled from, which we forward in place of the reassembled
+/// datagram. Empty for packets that were not fragmented.
+type Fragments = Vec<WinDivertPacket<'static, NetworkLayer>>;
+
+/// Packets buffered until we know what to do with their connection.
+/// Most connections are resolved after one or two packets, so we keep those inline.
+type PacketBuffer = SmallVec<[(WinDivertAddress<NetworkLayer>, InternetPacket, Fragments); 2]>;
+
 #[derive(Debug, Clone)]
 enum ConnectionAction {
     None,
     Intercept(ProcessInfo),
+    /// Reflect packets to a proxy on this machine instead of sending them over IPC,
+    /// see [`redirect_packet`].
+    Redirect {
+        process_info: ProcessInfo,
+        original_dst: SocketAddr,
+        proxy_port: u16,
+    },
+    /// Replies of the proxy to a redirected connection: reflect them back so that they come
+    /// from the original destination, and the client sees the connection it opened.
+    RestoreSource(SocketAddr),
+}
+
+impl ConnectionAction {
+    /// The state and tunnel info as reported over IPC.
+    fn describe(&self) -> (&'static str, Option<ipc::TunnelInfo>) {
+        match self {
+            ConnectionAction::None => ("none", None),
+            ConnectionAction::Intercept(info) => (
+                "intercept",
+                Some(ipc::TunnelInfo {
+                    pid: Some(info.pid),
+                    process_name: info.process_name.clone(),
+                }),
+            ),
+            ConnectionAction::Redirect { process_info, .. } => (
+                "redirect",
+                Some(ipc::TunnelInfo {
+                    pid: Some(process_info.pid),
+                    process_name: process_info.process_name.clone(),
+                }),
+            ),
+            ConnectionAction::RestoreSource(_) => ("restore", None),
+        }
+    }
+}
+
+/// Which traffic we redirect.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    /// Connections of processes on this machine.
+    Local,
+    /// Additionally, connections of other hosts that this machine routes, e.g. as a gateway.
+    Forward,
+}
+
+impl FromStr for Mode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "local" => Ok(Mode::Local),
+            "forward" => Ok(Mode::Forward),
+            _ => Err(anyhow!("Unknown mode: {}", s)),
+        }
+    }
+}
+
+/// What to do with captured packets if the main loop cannot keep up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OverflowPolicy {
+    /// Stop receiving packets until there is room in the queue. WinDivert buffers packets
+    /// in the meantime and drops them once its own queue is full.
+    Block,
+    /// Drop packets right away. TCP connections will retransmit them.
+    Drop,
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "block" => Ok(OverflowPolicy::Block),
+            "drop" => Ok(OverflowPolicy::Drop),
+            _ => Err(anyhow!("Unknown overflow policy: {}", s)),
+        }
+    }
+}
+
+/// Packet counters, reported to mitmproxy on request.
+///
+/// The relay threads count as well, so these are atomics.
+struct Stats {
+    packets_received: AtomicU64,
+    packets_injected: AtomicU64,
+    packets_intercepted: AtomicU64,
+    packets_dropped: AtomicU64,
+    injection_errors: AtomicU64,
+    /// The size of the connection table, as of the last event.
+    connections: AtomicU64,
+    /// The time from capture until we passed packets on, summed up.
+    latency_micros: AtomicU64,
+    latency_samples: AtomicU64,
+    /// The packet counters as of the last reset requested over IPC. We report the difference
+    /// instead of resetting the counters themselves, which need to stay monotonic for metrics.
+    baseline: Mutex<[u64; 4]>,
+}
+
+static STATS: Stats = Stats {
+    packets_received: AtomicU64::new(0),
+    packets_injected: AtomicU64::new(0),
+    packets_intercepted: AtomicU64::new(0),
+    packets_dropped: AtomicU64::new(0),
+    injection_errors: AtomicU64::new(0),
+    connections: AtomicU64::new(0),
+    latency_micros: AtomicU64::new(0),
+    latency_samples: AtomicU64::new(0),
+    baseline: Mutex::new([0; 4]),
+};
+
+impl Stats {
+    fn snapshot(&self, reset: bool, connections: usize) -> ipc::StatsResponse {
+        let current = [
+            &self.packets_received,
+            &self.packets_injected,
+            &self.packets_intercepted,
+            &self.packets_dropped,
+        ]
+        .map(|counter| counter.load(Ordering::Relaxed));
+        let mut baseline = self.baseline.lock().unwrap();
+        let [packets_received, packets_injected, packets_intercepted, packets_dropped] =
+            std::array::from_fn(|i| current[i] - baseline[i]);
+        if reset {
+            *baseline = current;
+        }
+        ipc::StatsResponse {
+            packets_received,
+            packets_injected,
+            packets_intercepted,
+            packets_dropped,
+            connections: connections as u64,
+        }
+    }
+
+    /// Record how long a packet captured at `timestamp` took until we injected it or sent it to
+    /// mitmproxy.
+    fn record_latency(&self, timestamp: i64) {
+        if let Some(latency) = clock::elapsed_since(timestamp) {
+            self.latency_micros
+                .fetch_add(latency.as_micros() as u64, Ordering::Relaxed);
+            self.latency_samples.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Render the counters in the Prometheus text exposition format.
+    /// Rates and ratios are left to Prometheus.
+    fn render_metrics(&self) -> String {
+        let metrics = [
+            (
+                "packets_received_total",
+                "counter",
+                "Packets received from WinDivert.",
+                &self.packets_received,
+            ),
+            (
+                "packets_injected_total",
+                "counter",
+                "Packets injected back into the network stack.",
+                &self.packets_injected,
+            ),
+            (
+                "packets_intercepted_total",
+                "counter",
+                "Packets sent to mitmproxy.",
+                &self.packets_intercepted,
+            ),
+            (
+                "packets_dropped_total",
+                "counter",
+                "Packets that were neither injected nor sent to mitmproxy.",
+                &self.packets_dropped,
+            ),
+            (
+                "injection_errors_total",
+                "counter",
+                "Packets that could not be injected.",
+                &self.injection_errors,
+            ),
+            (
+                "connections",
+                "gauge",
+                "Connections in the connection table.",
+                &self.connections,
+            ),
+            (
+                "packet_latency_microseconds_total",
+                "counter",
+                "Time from capture until packets were injected or sent to mitmproxy.",
+                &self.latency_micros,
+            ),
+            (
+                "packet_latency_samples_total",
+                "counter",
+                "Packets in packet_latency_microseconds_total.",
+                &self.latency_samples,
+            ),
+        ];
+        let mut out = String::new();
+        for (name, kind, help, value) in metrics {
+            let value = value.load(Ordering::Relaxed);
+            // Writing to a String cannot fail.
+            let _ = writeln!(out, "# HELP mitmproxy_redirector_{name} {help}");
+            let _ = writeln!(out, "# TYPE mitmproxy_redirector_{name} {kind}");
+            let _ = writeln!(out, "mitmproxy_redirector_{name} {value}");
+        }
+        out
+    }
+}
+
+/// Parse the argument of `--metrics-addr`. A bare port binds to localhost only.
+fn parse_metrics_addr(s: &str) -> Result<SocketAddr> {
+    if let Ok(port) = s.parse::<u16>() {
+        return Ok(SocketAddr::from((Ipv4Addr::LOCALHOST, port)));
+    }
+    s.parse().context("Invalid metrics address")
+}
+
+/// Serve the packet counters over HTTP, e.g. for a Prometheus scraper.
+///
+/// Every request gets the same response, so we do not bother parsing it.
+async fn serve_metrics(listener: TcpListener) -> Result<()> {
+    loop {
+        let (mut stream, peer) = listener.accept().await?;
+        tokio::spawn(async move {
+            // Read (a part of) the request, so that the client does not see a reset connection.
+            let mut buf = [0u8; 1024];
+            let _ = stream.read(&mut buf).await;
+            let body = STATS.render_metrics();
+            let response = format!(
+                "HTTP/1.1 200 OK\r\n\
+                Content-Type: text/plain; version=0.0.4\r\n\
+                Content-Length: {}\r\n\
+                Connection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            if let Err(e) = stream.write_all(response.as_bytes()).await {
+                debug!("Failed to serve metrics to {}: {}", peer, e);
+            }
+        });
+    }
+}
+
+/// Connection events for mitmproxy, sent as separate IPC messages in between the packets.
+static IPC_EVENTS: OnceLock<UnboundedSender<ipc::from_redirector::Message>> = OnceLock::new();
+
+/// Whether the current mitmproxy instance asked for connection events in its hello.
+static IPC_CONNECTION_EVENTS: AtomicBool = AtomicBool::new(false);
+
+/// Connection events for `--monitor-pipe` clients.
+static MONITOR: OnceLock<broadcast::Sender<ipc::ConnectionEvent>> = OnceLock::new();
+
+/// How many events a monitoring client may fall behind before it misses some.
+const MONITOR_BACKLOG: usize = 1024;
+
+/// Tell mitmproxy and monitoring clients about a connection.
+fn monitor(
+    key: &CanonicalConnectionId,
+    direction: Direction,
+    event: &str,
+    action: &ConnectionAction,
+) {
+    let (state, tunnel_info) = action.describe();
+    let event = ipc::ConnectionEvent {
+        connection_id: key.directed(direction).to_string(),
+        event: event.to_string(),
+        state: state.to_string(),
+        tunnel_info,
+    };
+    if let Some(events) = MONITOR.get() {
+        if events.receiver_count() > 0 {
+            // Sending only fails if all clients have disconnected in the meantime.
+            let _ = events.send(event.clone());
+        }
+    }
+    if !IPC_CONNECTION_EVENTS.load(Ordering::Relaxed) {
+        return;
+    }
+    if let Some(ipc_events) = IPC_EVENTS.get() {
+        let _ = ipc_events.send(ipc::from_redirector::Message::ConnectionEvent(event));
+    }
+}
+
+/// Create an instance of the monitoring pipe. Connection events reveal which processes talk
+/// to whom, so only Administrators and SYSTEM may connect.
+fn create_monitor_pipe(name: &str, first: bool) -> Result<NamedPipeServer> {
+    let mut security = PipeSecurity::admin_only()?;
+    let server = unsafe {
+        ServerOptions::new()
+            .first_pipe_instance(first)
+            .access_inbound(false)
+            .create_with_security_attributes_raw(name, security.as_raw())?
+    };
+    Ok(server)
+}
+
+/// Accept monitoring clients, each of which gets all connection events from then on.
+///
+/// Clients can only read. They never see packets and cannot slow down the main loop:
+/// a client that falls behind by more than `MONITOR_BACKLOG` events misses some.
+async fn serve_monitor(
+    pipe_name: String,
+    mut server: NamedPipeServer,
+    events: broadcast::Sender<ipc::ConnectionEvent>,
+) -> Result<()> {
+    loop {
+        server.connect().await?;
+        let client = server;
+        server = create_monitor_pipe(&pipe_name, false)?;
+        let rx = events.subscribe();
+        tokio::spawn(async move {
+            if let Err(e) = stream_events(client, rx).await {
+                debug!("Monitoring client disconnected: {}", e);
+            }
+        });
+    }
+}
+
+async fn stream_events(
+    mut client: NamedPipeServer,
+    mut rx: broadcast::Receiver<ipc::ConnectionEvent>,
+) -> Result<()> {
+    loop {
+        let event = match rx.recv().await {
+            Ok(event) => event,
+            Err(broadcast::error::RecvError::Lagged(n)) => {
+                warn!("Monitoring client fell behind, skipping {} events.", n);
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => return Ok(()),
+        };
+        let mut line = ipc::json::encode(&ipc::FromRedirector {
+            message: Some(ipc::from_redirector::Message::ConnectionEvent(event)),
+        });
+        line.push(b'\n');
+        client.write_all(&line).await?;
+    }
+}
+
+/// Transparently redirect traffic to a mitmproxy instance.
+#[derive(Parser, Debug)]
+#[command(version)]
+struct Args {
+    /// The named pipe to connect to. mitmproxy passes this as the only positional argument.
+    #[arg(value_name = "PIPE", conflicts_with = "pipe")]
+    pipe_positional: Option<String>,
+    /// The named pipe to connect to, if not the default \\.\pipe\mitmproxy-transparent-proxy.
+    #[arg(long)]
+    pipe: Option<String>,
+    /// The WinDivert filter for packets and socket events we look at.
+    #[arg(long, default_value = DEFAULT_CAPTURE_FILTER)]
+    filter: String,
+    /// Which traffic to redirect (local or forward).
+    #[arg(long, default_value = "local")]
+    mode: Mode,
+    /// Also intercept traffic between local processes.
+    #[arg(long)]
+    intercept_loopback: bool,
+    /// Apply the intercept spec to connections of the System process (PID 4), e.g. SMB or some
+    /// VPN clients. By default, they are always passed through.
+    #[arg(long)]
+    intercept_system: bool,
+    /// Apply the intercept spec to connections of mitmproxy itself and its child processes.
+    /// By default, they are always passed through, as intercepting mitmproxy's upstream
+    /// connections would loop them back to it.
+    #[arg(long)]
+    intercept_mitmproxy: bool,
+    /// Never intercept QUIC connections, so that clients fall back to TCP.
+    #[arg(long)]
+    pass_through_quic: bool,
+    /// UDP ports that are passed through unless the intercept spec includes them explicitly
+    /// (e.g. `:5353`). Defaults to DHCP, DHCPv6, SSDP, mDNS and LLMNR. Pass the option without
+    /// ports to intercept all of them.
+    #[arg(
+        long,
+        value_name = "PORTS",
+        value_delimiter = ',',
+        num_args = 0..,
+        default_values_t = DEFAULT_PASS_THROUGH_UDP_PORTS
+    )]
+    pass_through_udp_ports: Vec<u16>,
+    /// Pass all connections through, only log which ones would have been intercepted.
+    #[arg(long)]
+    dry_run: bool,
+    /// Instead of sending intercepted TCP connections to mitmproxy over the pipe, reflect them
+    /// to a proxy listening on this port on all local addresses.
+    #[arg(long, value_name = "PORT")]
+    redirect_to: Option<u16>,
+    /// Seconds of IPC inactivity before we check whether mitmproxy is still alive.
+    #[arg(long, value_name = "SECONDS", default_value_t = DEFAULT_KEEPALIVE_INTERVAL.as_secs())]
+    keepalive: u64,
+    /// Seconds after its last packet until a connection is forgotten.
+    #[arg(long, value_name = "SECONDS", default_value_t = DEFAULT_CONNECTION_EXPIRY.as_secs())]
+    connection_expiry: u64,
+    /// Seconds to wait for the socket event of an outgoing connection before passing it through.
+    #[arg(long, value_name = "SECONDS", default_value_t = DEFAULT_UNKNOWN_TIMEOUT.as_secs())]
+    unknown_timeout: u64,
+    /// How many packets we hold back per connection while waiting for its socket event.
+    /// Beyond that, the connection is passed through.
+    #[arg(long, value_name = "N", default_value_t = DEFAULT_MAX_UNKNOWN_PACKETS)]
+    max_unknown_packets: usize,
+    /// The maximum number of connections we remember.
+    #[arg(long, value_name = "N", default_value_t = DEFAULT_MAX_CONNECTIONS)]
+    max_connections: usize,
+    /// How many events may wait for the main loop.
+    #[arg(long, value_name = "N", default_value_t = DEFAULT_QUEUE_SIZE)]
+    queue_size: usize,
+    /// What to do when the event queue is full (block or drop).
+    #[arg(long, value_name = "POLICY", default_value = "block")]
+    on_overflow: OverflowPolicy,
+    /// How many packets WinDivert queues for us (32 to 16384, WinDivert's default is 4096).
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u64).range(32..=16384))]
+    wd_queue_length: Option<u64>,
+    /// How long WinDivert queues packets for us in milliseconds (100 to 16000, default 2000).
+    #[arg(long, value_name = "MS", value_parser = clap::value_parser!(u64).range(100..=16000))]
+    wd_queue_time: Option<u64>,
+    /// How many bytes WinDivert queues for us (65536 to 33554432, default 4194304).
+    #[arg(
+        long,
+        value_name = "BYTES",
+        value_parser = clap::value_parser!(u64).range(65536..=33554432)
+    )]
+    wd_queue_size: Option<u64>,
+    /// The WinDivert priority of the redirector's lowest handle, the others use the next five.
+    /// Change this if another WinDivert-based tool uses the same priorities.
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = DEFAULT_PRIORITY,
+        value_parser = clap::value_parser!(i16).range(-30000..=29995)
+    )]
+    priority: i16,
+    /// Write all observed packets to this pcap file.
+    #[arg(long, value_name = "PATH")]
+    pcap: Option<PathBuf>,
+    /// Remember the intercept config in this file, and apply it as soon as we are connected
+    /// after a restart instead of waiting for mitmproxy to send it again.
+    #[arg(long, value_name = "PATH")]
+    conf_cache: Option<PathBuf>,
+    /// Serve metrics on this address. A bare port binds to localhost.
+    #[arg(long, value_name = "ADDR", value_parser = parse_metrics_addr)]
+    metrics_addr: Option<SocketAddr>,
+    /// Stream connection events as JSON lines on this read-only named pipe.
+    /// Only Administrators and SYSTEM can open it.
+    #[arg(long, value_name = "PIPE")]
+    monitor_pipe: Option<String>,
+    /// Log to stderr, also in release builds. Setting RUST_LOG does the same.
+    #[arg(short, long)]
+    verbose: bool,
+    /// Write logs to this file instead of stderr, also in release builds.
+    /// It is rotated to `<PATH>.1` once it reaches 10 MiB.
+    #[arg(long, value_name = "PATH")]
+    log_file: Option<PathBuf>,
+    /// Check the prerequisites for capturing traffic, print a report and exit.
+    /// No traffic is diverted, but the pipe check connects to mitmproxy,
+    /// which then sees a redirector that disconnected right away.
+    #[arg(long)]
+    doctor: bool,
+}
+
+/// Settings for the capture loop.
+struct Options {
+    connection_expiry: Duration,
+    unknown_timeout: Duration,
+    max_unknown_packets: usize,
+    max_connections: usize,
+    capture_filter: String,
+    intercept_loopback: bool,
+    intercept_system: bool,
+    intercept_mitmproxy: bool,
+    pass_through_quic: bool,
+    pass_through_udp_ports: Vec<u16>,
+    dry_run: bool,
+    /// Redirect intercepted outgoing TCP connections to a proxy on this port.
+    redirect_to: Option<u16>,
+    mode: Mode,
+    overflow: OverflowPolicy,
+    /// WinDivert queue parameters for the capturing handles, unset ones keep their default.
+    wd_queue_length: Option<u64>,
+    wd_queue_time: Option<u64>,
+    wd_queue_size: Option<u64>,
+    /// The WinDivert priority of our lowest handle, the others are offset from it.
+    priority: i16,
+    /// Write all observed packets to this file.
+    pcap: Option<PathBuf>,
+}
+
+/// The interfaces of this machine's link-local IPv6 addresses.
+///
+/// Network-layer packets tell us their interface, but socket events and packets from mitmproxy
+/// do not. Each of our link-local addresses belongs to one interface, so we look up the local
+/// endpoint instead.
+#[derive(Default)]
+struct LinkLocalScopes {
+    scopes: HashMap<Ipv6Addr, u32>,
+    /// When we last listed the addresses, so that unknown ones do not trigger a lookup each time.
+    refreshed: Option<Instant>,
+}
+
+impl LinkLocalScopes {
+    /// Set the scope id of the link-local endpoints of a connection.
+    pub fn apply(&mut self, connection_id: ConnectionId) -> ConnectionId {
+        if !packet::is_link_local(connection_id.src.ip())
+            && !packet::is_link_local(connection_id.dst.ip())
+        {
+            return connection_id;
+        }
+        let scope_id = match self.lookup(&connection_id) {
+            Some(scope_id) => scope_id,
+            None => {
+                // Addresses come and go with interfaces.
+                let stale = !matches!(
+                    self.refreshed,
+                    Some(at) if at.elapsed() < LINK_LOCAL_REFRESH_INTERVAL
+                );
+                if stale {
+                    self.refreshed = Some(Instant::now());
+                    match link_local_addresses() {
+                        Ok(addresses) => self.scopes = addresses.into_iter().collect(),
+                        Err(e) => warn!("Cannot list link-local addresses: {:?}", e),
+                    }
+                }
+                self.lookup(&connection_id).unwrap_or(0)
+            }
+        };
+        connection_id.scoped(scope_id)
+    }
+
+    fn lookup(&self, connection_id: &ConnectionId) -> Option<u32> {
+        [connection_id.src, connection_id.dst]
+            .into_iter()
+            .find_map(|addr| match addr.ip() {
+                IpAddr::V6(ip) => self.scopes.get(&ip).copied(),
+                IpAddr::V4(_) => None,
+            })
+    }
 }
 
 struct ActiveListeners(HashMap<(SocketAddr, TransportProtocol), ProcessInfo>);
@@ -56,6 +764,7 @@ impl ActiveListeners {
         protocol: TransportProtocol,
         process_info: ProcessInfo,
     ) -> Option<ProcessInfo> {
+        socket = packet::without_scope_id(socket);
         if socket.ip() == IpAddr::V6(Ipv6Addr::UNSPECIFIED) {
             // Dual-stack binds: binding to [::] actually binds to 0.0.0.0 as well.
             socket.set_ip(IpAddr::V4(Ipv4Addr::UNSPECIFIED))
@@ -68,13 +777,15 @@ impl ActiveListeners {
         mut socket: SocketAddr,
         protocol: TransportProtocol,
     ) -> Option<ProcessInfo> {
+        socket = packet::without_scope_id(socket);
         if socket.ip() == IpAddr::V6(Ipv6Addr::UNSPECIFIED) {
             socket.set_ip(IpAddr::V4(Ipv4Addr::UNSPECIFIED))
         }
         self.0.remove(&(socket, protocol))
     }
 
-    pub fn get(&self, mut socket: SocketAddr, protocol: TransportProtocol) -> Option<&ProcessInfo> {
+    pub fn get(&self, socket: SocketAddr, protocol: TransportProtocol) -> Option<&ProcessInfo> {
+        let mut socket = packet::without_scope_id(socket);
         if !self.0.contains_key(&(socket, protocol)) {
             socket.set_ip(Ipv4Addr::UNSPECIFIED.into());
         }
@@ -86,112 +797,673 @@ impl ActiveListeners {
     }
 }
 
+/// A log file that is moved to `<path>.1` once it grows beyond `max_size`,
+/// replacing the previous one.
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf, max_size: u64) -> io::Result<Self> {
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path,
+            file,
+            size,
+            max_size,
+        })
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let mut backup = self.path.clone().into_os_string();
+        backup.push(".1");
+        fs::rename(&self.path, backup)?;
+        self.file = File::create(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl io::Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.size > 0 && self.size + buf.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Per-packet spans and events are only recorded with RUST_LOG=debug or more verbose.
+fn env_filter() -> EnvFilter {
+    EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"))
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
-    if cfg!(debug_assertions) {
-        env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    let args = Args::parse();
+    if let Some(path) = &args.log_file {
+        let file = RotatingFile::open(path.clone(), LOG_FILE_MAX_SIZE)
+            .with_context(|| format!("Cannot open log file {}", path.display()))?;
+        tracing_subscriber::fmt()
+            .with_env_filter(env_filter())
+            .with_ansi(false)
+            .with_writer(Mutex::new(file))
+            .init();
+    } else if cfg!(debug_assertions) || args.verbose || env::var_os("RUST_LOG").is_some() {
+        // Release builds are silent unless asked otherwise.
+        tracing_subscriber::fmt()
+            .with_env_filter(env_filter())
+            .init();
     }
-    let args: Vec<String> = env::args().collect();
     let pipe_name = args
-        .get(1)
-        .map(|x| x.as_str())
-        .unwrap_or(r"\\.\pipe\mitmproxy-transparent-proxy");
+        .pipe
+        .or(args.pipe_positional)
+        .unwrap_or_else(|| DEFAULT_PIPE_NAME.to_string());
+    if args.doctor {
+        let passed = doctor(&pipe_name, &args.filter, args.mode, args.priority);
+        std::process::exit(if passed { 0 } else { 1 });
+    }
+    let keepalive_interval = Duration::from_secs(args.keepalive);
+    let queue_size = args.queue_size;
+    let metrics_addr = args.metrics_addr;
+    let monitor_pipe = args.monitor_pipe;
+    let conf_cache = args.conf_cache;
+    let options = Options {
+        connection_expiry: Duration::from_secs(args.connection_expiry),
+        unknown_timeout: Duration::from_secs(args.unknown_timeout),
+        max_unknown_packets: args.max_unknown_packets,
+        max_connections: args.max_connections,
+        capture_filter: args.filter,
+        intercept_loopback: args.intercept_loopback,
+        intercept_system: args.intercept_system,
+        intercept_mitmproxy: args.intercept_mitmproxy,
+        pass_through_quic: args.pass_through_quic,
+        pass_through_udp_ports: args.pass_through_udp_ports,
+        dry_run: args.dry_run,
+        redirect_to: args.redirect_to,
+        mode: args.mode,
+        overflow: args.on_overflow,
+        wd_queue_length: args.wd_queue_length,
+        wd_queue_time: args.wd_queue_time,
+        wd_queue_size: args.wd_queue_size,
+        priority: args.priority,
+        pcap: args.pcap,
+    };
 
-    let ipc_client = ClientOptions::new()
-        .pipe_mode(PipeMode::Message)
-        .open(pipe_name)
-        .context("Cannot open pipe")?;
+    // mitmproxy may still be creating the pipe when it spawns us.
+    let ipc_client = open_pipe(
+        &pipe_name,
+        ipc::Backoff::new(Duration::from_millis(50), Duration::from_secs(1), 8),
+    )
+    .await
+    .context("Cannot open pipe")?;
 
-    let (event_tx, mut event_rx) = mpsc::unbounded_channel::<Event>();
-    let (mut ipc_tx, ipc_rx) = mpsc::unbounded_channel::<ipc::PacketWithMeta>();
+    if let Some(addr) = metrics_addr {
+        let listener = TcpListener::bind(addr)
+            .await
+            .with_context(|| format!("Cannot bind metrics endpoint to {}", addr))?;
+        info!("Serving metrics on http://{}/metrics", addr);
+        tokio::spawn(async move {
+            if let Err(e) = serve_metrics(listener).await {
+                error!("Error serving metrics: {:?}", e);
+            }
+        });
+    }
 
-    // We currently rely on handles being automatically closed when the program exits.
-    // only needed for forward mode
-    // let _icmp_handle = WinDivert::new("icmp", WinDivertLayer::Network, 1042, WinDivertFlags::new().set_drop()).context("Error opening WinDivert handle")?;
+    if let Some(name) = monitor_pipe {
+        let server = create_monitor_pipe(&name, true)
+            .with_context(|| format!("Cannot create monitoring pipe {}", name))?;
+        let (events, _) = broadcast::channel(MONITOR_BACKLOG);
+        MONITOR.set(events.clone()).ok();
+        info!("Serving connection events on {}", name);
+        tokio::spawn(async move {
+            if let Err(e) = serve_monitor(name, server, events).await {
+                error!("Error serving connection events: {:?}", e);
+            }
+        });
+    }
 
-    let socket_handle = WinDivert::socket(
-        "tcp || udp",
-        1041,
-        WinDivertFlags::new().set_recv_only().set_sniff(),
-    )?;
-    // WinDivert's syntax supports IP ranges (https://github.com/basil00/Divert/issues/250#issuecomment-723515347)
-    let wd_net_filter = "!loopback && ((ip && remoteAddr < 224.0.0.0) || (ipv6 && remoteAddr < ff00::)) && (tcp || udp)";
-    let network_handle = WinDivert::network(wd_net_filter, 1040, WinDivertFlags::new())?;
-    let inject_handle = WinDivert::network("false", 1039, WinDivertFlags::new().set_send_only())?;
+    let (event_tx, event_rx) = mpsc::channel::<Event>(queue_size);
 
+    // When running standalone, Ctrl-C shuts down gracefully just like mitmproxy's shutdown
+    // message does. Pressing it a second time exits right away.
     let tx_clone = event_tx.clone();
-    thread::spawn(move || relay_socket_events(socket_handle, tx_clone));
-    let tx_clone = event_tx.clone();
-    thread::spawn(move || relay_network_events(network_handle, tx_clone));
-
-    let mut state = InterceptConf::disabled();
-    event_tx.send(Event::Ipc(ipc::from_proxy::Message::InterceptConf(state.clone().into())))?;
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_err() {
+            return;
+        }
+        info!("Received Ctrl-C.");
+        let shutdown = Event::Ipc(ipc::from_proxy::Message::Shutdown(ipc::Shutdown {}));
+        if tx_clone.send(shutdown).await.is_err() {
+            return;
+        }
+        if tokio::signal::ctrl_c().await.is_ok() {
+            std::process::exit(130);
+        }
+    });
+    let (ipc_tx, ipc_rx) = mpsc::unbounded_channel::<ipc::PacketWithMeta>();
+    let (error_tx, error_rx) = mpsc::unbounded_channel::<ipc::Error>();
+    let (reply_tx, reply_rx) = mpsc::unbounded_channel::<ipc::from_redirector::Message>();
+    IPC_EVENTS.set(reply_tx.clone()).ok();
 
+    let tx_clone = event_tx.clone();
     tokio::spawn(async move {
-        if let Err(e) = handle_ipc(ipc_client, ipc_rx, event_tx).await {
+        if let Err(e) = run_ipc(
+            pipe_name,
+            ipc_client,
+            keepalive_interval,
+            conf_cache,
+            ipc_rx,
+            tx_clone,
+            error_rx,
+            reply_rx,
+        )
+        .await
+        {
             error!("Error handling IPC: {}", e);
             std::process::exit(1);
         }
     });
 
-    let mut connections = LruCache::<ConnectionId, ConnectionState>::with_expiry_duration(
-        Duration::from_secs(60 * 10),
+    // Without elevation, WinDivert fails with an unhelpful "access denied".
+    match elevation::is_elevated() {
+        Ok(true) => {}
+        Ok(false) => {
+            let message = "The redirector is not running with administrator privileges. \
+                Capturing traffic requires administrator rights, so it will most likely fail."
+                .to_string();
+            warn!("{}", message);
+            reply_tx
+                .send(ipc::from_redirector::Message::Warning(ipc::Warning {
+                    message,
+                }))
+                .ok();
+        }
+        Err(e) => debug!("Cannot determine whether we are elevated: {:?}", e),
+    }
+
+    let result = run(
+        event_tx,
+        event_rx,
+        ipc_tx,
+        reply_tx,
+        error_tx.clone(),
+        options,
+    )
+    .await;
+    if let Err(e) = result {
+        exit_with_error(&error_tx, 1, format!("{:?}", e));
+    }
+    Ok(())
+}
+
+/// Report a fatal error to mitmproxy and exit.
+///
+/// The IPC task exits the process once it has sent the error.
+/// If that does not happen in time (e.g. because we are not connected), we exit anyway.
+fn exit_with_error(error_tx: &UnboundedSender<ipc::Error>, code: i32, message: String) -> ! {
+    error!("{}", message);
+    if error_tx.send(ipc::Error { code, message }).is_ok() {
+        thread::sleep(ERROR_REPORT_TIMEOUT);
+    }
+    std::process::exit(code);
+}
+
+/// Capture packets and socket events, and decide what to do with them.
+async fn run(
+    event_tx: Sender<Event>,
+    mut event_rx: Receiver<Event>,
+    mut ipc_tx: UnboundedSender<ipc::PacketWithMeta>,
+    reply_tx: UnboundedSender<ipc::from_redirector::Message>,
+    error_tx: UnboundedSender<ipc::Error>,
+    options: Options,
+) -> Result<()> {
+    let capture_filter = options.capture_filter.as_str();
+    let intercept_loopback = options.intercept_loopback;
+    let pass_through_quic = options.pass_through_quic;
+    let pass_through_udp_ports = options.pass_through_udp_ports.as_slice();
+    let unknown_timeout = options.unknown_timeout;
+    let intercept_system = options.intercept_system;
+    let intercept_mitmproxy = options.intercept_mitmproxy;
+    let max_unknown_packets = options.max_unknown_packets;
+    let redirect_to = options.redirect_to;
+    let priority = options.priority;
+
+    // We currently rely on handles being automatically closed when the program exits.
+    let socket_handle = WinDivert::socket(
+        capture_filter,
+        priority + PRIORITY_SOCKET,
+        WinDivertFlags::new().set_recv_only().set_sniff(),
+    )
+    .map_err(|e| open_error(e, capture_filter))?;
+    // WinDivert's syntax supports IP ranges (https://github.com/basil00/Divert/issues/250#issuecomment-723515347)
+    let loopback_filter = if intercept_loopback {
+        "true"
+    } else {
+        "!loopback"
+    };
+    let wd_net_filter = format!(
+        "{} && ((ip && remoteAddr < 224.0.0.0) || (ipv6 && remoteAddr < ff00::)) && (({}) || {} || {})",
+        loopback_filter, capture_filter, ICMP_ERROR_FILTER, IPV4_FRAGMENT_FILTER
     );
+    let network_handle = WinDivert::network(
+        &wd_net_filter,
+        priority + PRIORITY_NETWORK,
+        WinDivertFlags::new(),
+    )
+    .map_err(|e| open_error(e, capture_filter))?;
+    set_queue_params(|p, v| network_handle.set_param(p, v), &options)?;
+    let inject_handle = WinDivert::network(
+        "false",
+        priority + PRIORITY_INJECT,
+        WinDivertFlags::new().set_send_only(),
+    )
+    .map_err(|e| open_error(e, "false"))?;
+    let mut injector = Injector::new(inject_handle);
+    let mut pcap = match &options.pcap {
+        Some(path) => {
+            let file = File::create(path)
+                .with_context(|| format!("Cannot create pcap file: {}", path.display()))?;
+            Some(packet::PcapWriter::new(BufWriter::new(file))?)
+        }
+        None => None,
+    };
+    let mut pcap_flushed = Instant::now();
+
+    // When routing packets, Windows tells clients about a better route with ICMP redirects,
+    // which would make them bypass us. The handle needs to stay open to keep dropping them.
+    let mut _icmp_handle = None;
+    let mut forward_inject_handle = None;
+    let mut relays = Relays::new();
+    let overflow = options.overflow;
+    if options.mode == Mode::Forward {
+        _icmp_handle = Some(
+            WinDivert::network(
+                ICMP_REDIRECT_FILTER,
+                priority + PRIORITY_ICMP_REDIRECT,
+                WinDivertFlags::new().set_drop(),
+            )
+            .map_err(|e| open_error(e, ICMP_REDIRECT_FILTER))?,
+        );
+        let wd_fwd_filter = format!(
+            "((ip && remoteAddr < 224.0.0.0) || (ipv6 && remoteAddr < ff00::)) && ({})",
+            capture_filter
+        );
+        let forward_handle = WinDivert::forward(
+            &wd_fwd_filter,
+            priority + PRIORITY_FORWARD,
+            WinDivertFlags::new(),
+        )
+        .map_err(|e| open_error(e, capture_filter))?;
+        set_queue_params(|p, v| forward_handle.set_param(p, v), &options)?;
+        forward_inject_handle = Some(
+            WinDivert::forward(
+                "false",
+                priority + PRIORITY_FORWARD_INJECT,
+                WinDivertFlags::new().set_send_only(),
+            )
+            .map_err(|e| open_error(e, "false"))?,
+        );
+        let tx_clone = event_tx.clone();
+        let error_tx_clone = error_tx.clone();
+        relays.spawn("forward", move |stop| {
+            relay_forward_events(&forward_handle, &tx_clone, &error_tx_clone, overflow, stop)
+        });
+    }
+
+    let tx_clone = event_tx.clone();
+    let error_tx_clone = error_tx.clone();
+    relays.spawn("socket", move |stop| {
+        relay_socket_events(&socket_handle, &tx_clone, &error_tx_clone, stop)
+    });
+    let error_tx_clone = error_tx.clone();
+    relays.spawn("network", move |stop| {
+        relay_network_events(&network_handle, &event_tx, &error_tx_clone, overflow, stop)
+    });
+    let stop_relays = relays.stop.clone();
+    tokio::spawn(relays.supervise(error_tx));
+
+    let mut state = InterceptConf::disabled();
+    let mut dry_run = options.dry_run;
+    // The mitmproxy process, unless its connections may be intercepted.
+    let mut controller_pid = None;
+
+    // lru_time_cache is backed by a BTreeMap, so lookups compare keys instead of hashing them.
+    // A hash map with a faster hasher would need its own expiry and eviction order on top,
+    // so we keep this until a benchmark shows that lookups matter.
+    // Every lookup refreshes an entry, so only idle connections expire.
+    let mut connections =
+        LruCache::<CanonicalConnectionId, ConnectionState>::with_expiry_duration_and_capacity(
+            options.connection_expiry,
+            options.max_connections,
+        );
     let mut active_listeners = ActiveListeners::new();
+    let mut process_names = ProcessNameCache::default();
+    let mut link_local_scopes = LinkLocalScopes::default();
+    let mut parent_processes = ParentProcessCache::default();
+    let mut reassembler = packet::FragmentReassembler::new(Duration::from_secs(30));
+    // Closed connections and when to remove them, ordered by deadline.
+    let mut closed_connections = VecDeque::<(Instant, CanonicalConnectionId)>::new();
+    // Connections waiting for their socket event and when to give up, ordered by deadline.
+    let mut unknown_connections = VecDeque::<(Instant, CanonicalConnectionId)>::new();
+    // Set once mitmproxy has asked us to shut down.
+    let mut shutdown_deadline: Option<Instant> = None;
 
     loop {
-        let result = event_rx.recv().await.unwrap();
+        STATS
+            .connections
+            .store(connections.len() as u64, Ordering::Relaxed);
+
+        while let Some(&(deadline, key)) = unknown_connections.front() {
+            if deadline > Instant::now() {
+                break;
+            }
+            unknown_connections.pop_front();
+            // The socket event may have arrived in the meantime.
+            if !matches!(connections.peek(&key), Some(ConnectionState::Unknown(_))) {
+                continue;
+            }
+            let Some(ConnectionState::Unknown(packets)) = connections.remove(&key) else {
+                unreachable!();
+            };
+            warn!(
+                connection = %key,
+                packets = packets.len(),
+                "No socket event after {:?}, passing connection through.",
+                unknown_timeout
+            );
+            pass_through_unknown(key, packets, &mut connections, &mut injector, &mut ipc_tx)
+                .await?;
+        }
+
+        // Don't hold back packets while we are waiting for new events.
+        if event_rx.is_empty() {
+            injector.flush().await?;
+            if pcap_flushed.elapsed() >= PCAP_FLUSH_INTERVAL {
+                flush_pcap(&mut pcap);
+                pcap_flushed = Instant::now();
+            }
+        }
+
+        let result = match shutdown_deadline {
+            Some(deadline) => {
+                // Keep passing packets through until the queues are empty, but not forever.
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                let timeout = DRAIN_IDLE_TIMEOUT.min(remaining);
+                match tokio::time::timeout(timeout, event_rx.recv()).await {
+                    Ok(event) if Instant::now() < deadline => event.unwrap(),
+                    _ => {
+                        injector.flush().await?;
+                        stop_relays.store(true, Ordering::Relaxed);
+                        flush_pcap(&mut pcap);
+                        info!("Shutdown complete.");
+                        std::process::exit(0);
+                    }
+                }
+            }
+            // Wake up in time to pass through connections that never get a socket event.
+            None => match unknown_connections.front() {
+                Some(&(deadline, _)) => {
+                    match tokio::time::timeout_at(deadline.into(), event_rx.recv()).await {
+                        Ok(event) => event.unwrap(),
+                        Err(_) => continue,
+                    }
+                }
+                None => event_rx.recv().await.unwrap(),
+            },
+        };
+
+        while let Some(&(deadline, key)) = closed_connections.front() {
+            if deadline > Instant::now() {
+                break;
+            }
+            closed_connections.pop_front();
+            match connections.remove(&key) {
+                Some(ConnectionState::Known(action, direction, _)) => {
+                    monitor(&key, direction, "close", &action);
+                }
+                Some(ConnectionState::Unknown(packets)) => {
+                    for (address, packet, fragments) in packets {
+                        process_packet(
+                            address,
+                            packet,
+                            fragments,
+                            &ConnectionAction::None,
+                            &mut injector,
+                            &mut ipc_tx,
+                        )
+                        .await?;
+                    }
+                }
+                None => {}
+            }
+        }
+
         match result {
+            Event::NetworkPacket(address, data) if shutdown_deadline.is_some() => {
+                // We are shutting down, so we do not accept any new packets.
+                injector
+                    .send(WinDivertPacket {
+                        address,
+                        data: data.into(),
+                    })
+                    .await?;
+            }
             Event::NetworkPacket(address, data) => {
                 // We received a network packet and now need to figure out what to do with it.
 
+                write_pcap(&mut pcap, &data);
+
+                // Non-first fragments carry no transport header, so we need to reassemble
+                // the datagram before we can associate it with a connection.
+                // Unless we intercept it, we forward the original fragments instead.
+                let (address, data, fragments) = match reassembler.process(data, address) {
+                    Ok(Some(packet::Reassembled::Packet(address, data))) => {
+                        (address, data, Fragments::new())
+                    }
+                    Ok(Some(packet::Reassembled::Datagram { data, fragments })) => {
+                        // The datagram takes the address of its first fragment.
+                        let address = fragments[0].0.clone();
+                        let fragments = fragments
+                            .into_iter()
+                            .map(|(address, data)| WinDivertPacket {
+                                address,
+                                data: data.into(),
+                            })
+                            .collect();
+                        (address, data, fragments)
+                    }
+                    Ok(None) => continue,
+                    Err(e) => {
+                        debug!("Dropping fragment: {:?}", e);
+                        STATS.packets_dropped.fetch_add(1, Ordering::Relaxed);
+                        continue;
+                    }
+                };
+
+                // ICMP errors for intercepted connections (e.g. "fragmentation needed") go to
+                // mitmproxy, which terminates these connections. Other ICMP errors are passed
+                // through.
+                if let Ok(icmp) = packet::IcmpPacket::parse(&data) {
+                    let quoted = icmp.quoted_connection_id(&data);
+                    if let Some(ConnectionState::Known(ConnectionAction::Intercept(info), ..)) =
+                        quoted.and_then(|id| {
+                            connections.get(&id.scoped(address.interface_index()).canonical().0)
+                        })
+                    {
+                        debug!("Relaying ICMP error for {}", quoted.unwrap());
+                        STATS.packets_intercepted.fetch_add(1, Ordering::Relaxed);
+                        ipc_tx.send(ipc::PacketWithMeta {
+                            data: data.into(),
+                            tunnel_info: Some(ipc::TunnelInfo {
+                                pid: Some(info.pid),
+                                process_name: info.process_name.clone(),
+                            }),
+                        })?;
+                        continue;
+                    }
+                }
+
+                // Packets we cannot make sense of (e.g. unknown IPv6 extension headers)
+                // are passed through untouched instead of being dropped.
+                let proto = match packet::locate_transport(&data) {
+                    Ok((proto, _)) => IpProtocol::try_from(proto)
+                        .ok()
+                        .and_then(IpProtocol::transport),
+                    Err(_) => None,
+                };
+                let Some(proto) = proto else {
+                    debug!("Passing through unsupported packet.");
+                    pass_through(address, data, fragments, &mut injector).await?;
+                    continue;
+                };
+
+                // Outbound packets usually have their checksums offloaded to the NIC,
+                // so we can only validate checksums that WinDivert reports as complete.
+                let checksums_complete = (address.ip_checksum() || data[0] >> 4 == 6)
+                    && match proto {
+                        TransportProtocol::Tcp => address.tcp_checksum(),
+                        TransportProtocol::Udp => address.udp_checksum(),
+                    };
+                if checksums_complete && !packet::validate_checksums(&data) {
+                    warn!("Dropping packet with invalid checksum.");
+                    STATS.packets_dropped.fetch_add(1, Ordering::Relaxed);
+                    continue;
+                }
+
                 let packet = match InternetPacket::try_from(data) {
                     Ok(p) => p,
                     Err(e) => {
                         debug!("Error parsing packet: {:?}", e);
+                        STATS.packets_dropped.fetch_add(1, Ordering::Relaxed);
                         continue;
                     }
                 };
 
+                // IP headers have no scope id, but link-local addresses are only unique with one.
+                let connection_id = packet.connection_id().scoped(address.interface_index());
                 debug!(
-                    "Received packet: {} {} {}",
-                    packet.connection_id(),
-                    packet.tcp_flag_str(),
-                    packet.payload().len()
+                    connection_id = %packet.connection_id(),
+                    flags = %packet.tcp_flag_str(),
+                    len = packet.payload().len(),
+                    "Received packet."
                 );
 
                 let is_multicast = packet.src_ip().is_multicast() || packet.dst_ip().is_multicast();
-                let is_loopback_only =
-                    packet.src_ip().is_loopback() && packet.dst_ip().is_loopback();
-                if is_multicast || is_loopback_only {
+                let is_loopback_only = packet::is_loopback_only(packet.src_ip(), packet.dst_ip());
+                let is_local_service = is_local_service(&packet, pass_through_udp_ports, &state);
+                if is_multicast || is_local_service || (is_loopback_only && !intercept_loopback) {
                     debug!(
-                        "skipping multicast={} loopback={}",
-                        is_multicast, is_loopback_only
+                        connection_id = %packet.connection_id(),
+                        multicast = is_multicast,
+                        local_service = is_local_service,
+                        loopback = is_loopback_only,
+                        "Skipping packet."
                     );
-                    inject_handle.send(&WinDivertPacket {
-                        address,
-                        data: packet.inner().into(),
-                    })?;
+                    pass_through(address, packet.inner(), fragments, &mut injector).await?;
                     continue;
                 }
 
-                match connections.get_mut(&packet.connection_id()) {
-                    Some(state) => match state {
-                        ConnectionState::Known(s) => {
-                            process_packet(address, packet, s, &inject_handle, &mut ipc_tx).await?;
-                        }
-                        ConnectionState::Unknown(packets) => {
-                            packets.push((address, packet));
+                let (key, direction) = connection_id.canonical();
+                // QUIC connections are pinned to pass through as soon as we see a long header
+                // packet, even if a socket event would have us intercept them.
+                if pass_through_quic
+                    && packet.protocol() == TransportProtocol::Udp
+                    && packet::is_quic(packet.payload())
+                    && !matches!(
+                        connections.get(&key),
+                        Some(ConnectionState::Known(ConnectionAction::None, ..))
+                    )
+                {
+                    debug!(
+                        connection_id = %packet.connection_id(),
+                        "Passing through QUIC connection."
+                    );
+                    insert_into_connections(
+                        connection_id,
+                        &ConnectionAction::None,
+                        &address.event(),
+                        &mut connections,
+                        &mut injector,
+                        &mut ipc_tx,
+                    )
+                    .await?;
+                }
+                // Lookups refresh the expiry, so connections are only forgotten once idle.
+                let len = packet.payload().len();
+                match connections.get_mut(&key) {
+                    Some(state) => {
+                        state.count(direction, len);
+                        state.observe(direction, &address);
+                        state.peek(direction, &packet);
+                        match state {
+                            ConnectionState::Known(action, action_direction, ..) => {
+                                let action = if direction == *action_direction {
+                                    &*action
+                                } else {
+                                    &ConnectionAction::None
+                                };
+                                process_packet(
+                                    address,
+                                    packet,
+                                    fragments,
+                                    action,
+                                    &mut injector,
+                                    &mut ipc_tx,
+                                )
+                                .await?;
+                            }
+                            ConnectionState::Unknown(packets) => {
+                                packets.push((address, packet, fragments));
+                                if packets.len() >= max_unknown_packets {
+                                    warn!(
+                                        connection = %key,
+                                        packets = packets.len(),
+                                        "Buffer full without a socket event, passing through."
+                                    );
+                                    let packets = std::mem::take(packets);
+                                    connections.remove(&key);
+                                    pass_through_unknown(
+                                        key,
+                                        packets,
+                                        &mut connections,
+                                        &mut injector,
+                                        &mut ipc_tx,
+                                    )
+                                    .await?;
+                                }
+                            }
                         }
-                    },
+                    }
                     None => {
                         if address.outbound() {
                             // We expect a corresponding socket event soon.
-                            debug!("Adding unknown packet: {}", packet.connection_id());
-                            connections.insert(
-                                packet.connection_id(),
-                                ConnectionState::Unknown(vec![(address, packet)]),
+                            debug!(
+                                connection_id = %packet.connection_id(),
+                                "Adding unknown packet."
                             );
+                            insert_connection(
+                                &mut connections,
+                                key,
+                                ConnectionState::Unknown(smallvec![(address, packet, fragments)]),
+                                &mut injector,
+                                &mut ipc_tx,
+                            )
+                            .await?;
+                            unknown_connections.push_back((Instant::now() + unknown_timeout, key));
                         } else {
                             // For incoming packets, there won't be a socket event if we capture
                             // before it reaches the socket, so we need to make a decision now.
@@ -200,50 +1472,200 @@ async fn main() -> Result<()> {
                                     active_listeners.get(packet.dst(), packet.protocol())
                                 {
                                     debug!(
-                                        "Inbound packet for known application: {:?} ({})",
-                                        &proc_info.process_name, &proc_info.pid
+                                        connection_id = %packet.connection_id(),
+                                        pid = proc_info.pid,
+                                        process_name = ?proc_info.process_name,
+                                        "Inbound packet for known application."
                                     );
-                                    if state.should_intercept(proc_info) {
+                                    let ancestors = (state.needs_ancestors()
+                                        || controller_pid.is_some())
+                                    .then(|| parent_processes.ancestors(proc_info.pid))
+                                    .unwrap_or_default();
+                                    let ctx = ConnectionContext {
+                                        process_info: proc_info,
+                                        ancestors: &ancestors,
+                                        local_addr: packet.dst(),
+                                        remote_addr: packet.src(),
+                                    };
+                                    if !is_controller(&ctx, controller_pid)
+                                        && state.should_intercept(&ctx)
+                                    {
                                         ConnectionAction::Intercept(proc_info.clone())
                                     } else {
                                         ConnectionAction::None
                                     }
                                 } else {
-                                    debug!("Unknown inbound packet. Passing through.");
+                                    debug!(
+                                        connection_id = %packet.connection_id(),
+                                        "Unknown inbound packet. Passing through."
+                                    );
                                     ConnectionAction::None
                                 }
                             };
+                            let action = dry_run_action(action, &connection_id, dry_run);
                             insert_into_connections(
-                                packet.connection_id(),
+                                connection_id,
                                 &action,
                                 &address.event(),
                                 &mut connections,
-                                &inject_handle,
+                                &mut injector,
                                 &mut ipc_tx,
                             )
                             .await?;
-                            process_packet(address, packet, &action, &inject_handle, &mut ipc_tx)
-                                .await?;
+                            if let Some(state) = connections.get_mut(&key) {
+                                state.count(direction, len);
+                                state.observe(direction, &address);
+                                state.peek(direction, &packet);
+                            }
+                            process_packet(
+                                address,
+                                packet,
+                                fragments,
+                                &action,
+                                &mut injector,
+                                &mut ipc_tx,
+                            )
+                            .await?;
+                        }
+                    }
+                }
+            }
+            Event::ForwardPacket(address, data) if shutdown_deadline.is_some() => {
+                forward_packet(forward_inject_handle.as_ref().unwrap(), address, data);
+            }
+            Event::ForwardPacket(address, data) => {
+                write_pcap(&mut pcap, &data);
+                let forward_inject_handle = forward_inject_handle.as_ref().unwrap();
+                let proto = match packet::locate_transport(&data) {
+                    Ok((proto, _)) => IpProtocol::try_from(proto)
+                        .ok()
+                        .and_then(IpProtocol::transport),
+                    Err(_) => None,
+                };
+                if proto.is_none() {
+                    forward_packet(forward_inject_handle, address, data);
+                    continue;
+                }
+                let packet = match InternetPacket::try_from(data) {
+                    Ok(p) => p,
+                    Err(e) => {
+                        debug!("Error parsing forwarded packet: {:?}", e);
+                        STATS.packets_dropped.fetch_add(1, Ordering::Relaxed);
+                        continue;
+                    }
+                };
+
+                // Forwarded connections do not belong to a local process, so there are no
+                // socket events for them. We decide when we see the first packet.
+                let (key, direction) = packet.connection_id().canonical();
+                let len = packet.payload().len();
+                let action = match connections.get_mut(&key) {
+                    Some(state @ ConnectionState::Known(..)) => {
+                        state.count(direction, len);
+                        state.peek(direction, &packet);
+                        let ConnectionState::Known(action, action_direction, _) = state else {
+                            unreachable!();
+                        };
+                        if direction == *action_direction {
+                            action.clone()
+                        } else {
+                            ConnectionAction::None
+                        }
+                    }
+                    _ => {
+                        let process_info = ProcessInfo {
+                            pid: 0,
+                            process_name: None,
+                        };
+                        let ctx = ConnectionContext {
+                            process_info: &process_info,
+                            ancestors: &[],
+                            local_addr: packet.src(),
+                            remote_addr: packet.dst(),
+                        };
+                        let action = if state.should_intercept(&ctx) {
+                            ConnectionAction::Intercept(process_info)
+                        } else {
+                            ConnectionAction::None
+                        };
+                        let action = dry_run_action(action, &packet.connection_id(), dry_run);
+                        debug!(
+                            connection_id = %packet.connection_id(),
+                            ?action,
+                            "Adding forwarded connection."
+                        );
+                        insert_connection(
+                            &mut connections,
+                            key,
+                            ConnectionState::Known(action.clone(), direction, Observed::default()),
+                            &mut injector,
+                            &mut ipc_tx,
+                        )
+                        .await?;
+                        if let Some(state) = connections.get_mut(&key) {
+                            state.count(direction, len);
+                            state.peek(direction, &packet);
                         }
+                        action
+                    }
+                };
+
+                match action {
+                    // Only local connections are redirected.
+                    ConnectionAction::None
+                    | ConnectionAction::Redirect { .. }
+                    | ConnectionAction::RestoreSource(_) => {
+                        forward_packet(forward_inject_handle, address, packet.inner());
+                    }
+                    ConnectionAction::Intercept(_) => {
+                        info!(
+                            connection_id = %packet.connection_id(),
+                            flags = %packet.tcp_flag_str(),
+                            "Intercepting forwarded packet."
+                        );
+                        STATS.packets_intercepted.fetch_add(1, Ordering::Relaxed);
+                        ipc_tx.send(ipc::PacketWithMeta {
+                            data: packet.inner().into(),
+                            tunnel_info: Some(ipc::TunnelInfo {
+                                pid: None,
+                                process_name: None,
+                            }),
+                        })?;
                     }
                 }
             }
             Event::SocketInfo(address) => {
-                if address.process_id() == 4 {
-                    // We get some weird operating system events here, which are not useful.
-                    debug!("Skipping PID 4");
+                let system = address.process_id() == SYSTEM_PID;
+                // Listeners only matter for interception, see `--intercept-system`.
+                let listen = matches!(address.event(), WinDivertEvent::SocketListen);
+                if system && (is_system_noise(&address) || listen && !intercept_system) {
+                    debug!(event = ?address.event(), "Skipping System process socket event.");
                     continue;
                 }
 
-                let Ok(proto) = TransportProtocol::try_from(address.protocol()) else {
-                    warn!("Unknown transport protocol: {}", address.protocol());
+                let proto = match IpProtocol::try_from(address.protocol()) {
+                    Ok(proto) => proto,
+                    Err(e) => {
+                        warn!("{}", e);
+                        continue;
+                    }
+                };
+                // Socket events for ICMP are expected, but there is nothing to track for them.
+                let Some(proto) = proto.transport() else {
                     continue;
                 };
-                let connection_id = ConnectionId {
+                // Dual-stack sockets report IPv4 addresses as IPv4-mapped IPv6 addresses.
+                let connection_id = link_local_scopes.apply(ConnectionId {
                     proto,
-                    src: SocketAddr::from((address.local_address(), address.local_port())),
-                    dst: SocketAddr::from((address.remote_address(), address.remote_port())),
-                };
+                    src: packet::unmap_ipv4(SocketAddr::from((
+                        address.local_address(),
+                        address.local_port(),
+                    ))),
+                    dst: packet::unmap_ipv4(SocketAddr::from((
+                        address.remote_address(),
+                        address.remote_port(),
+                    ))),
+                });
 
                 if connection_id.src.ip().is_multicast() || connection_id.dst.ip().is_multicast() {
                     continue;
@@ -251,20 +1673,23 @@ async fn main() -> Result<()> {
 
                 match address.event() {
                     WinDivertEvent::SocketConnect | WinDivertEvent::SocketAccept => {
-                        let make_entry = match connections.get(&connection_id) {
+                        let make_entry = match connections.get(&connection_id.canonical().0) {
                             None => true,
                             Some(e) => matches!(e, ConnectionState::Unknown(_)),
                         };
 
                         debug!(
-                            "{:<15?} make_entry={} pid={} {}",
-                            address.event(),
+                            %connection_id,
+                            event = ?address.event(),
                             make_entry,
-                            address.process_id(),
-                            connection_id
+                            pid = address.process_id(),
+                            "Socket event."
                         );
 
                         if !make_entry {
+                            // The connection has been reopened while we were waiting to remove it.
+                            let key = connection_id.canonical().0;
+                            closed_connections.retain(|(_, k)| *k != key);
                             continue;
                         }
 
@@ -272,34 +1697,63 @@ async fn main() -> Result<()> {
                             let pid = address.process_id();
                             ProcessInfo {
                                 pid,
-                                process_name: get_process_name(pid)
-                                    .map(|x| x.to_string_lossy().into_owned())
-                                    .ok(),
+                                process_name: process_names.get(pid),
                             }
                         };
 
-                        let action = if state.should_intercept(&proc_info) {
-                            ConnectionAction::Intercept(proc_info)
-                        } else {
+                        let ancestors = (state.needs_ancestors() || controller_pid.is_some())
+                            .then(|| parent_processes.ancestors(proc_info.pid))
+                            .unwrap_or_default();
+                        let ctx = ConnectionContext {
+                            process_info: &proc_info,
+                            ancestors: &ancestors,
+                            local_addr: connection_id.src,
+                            remote_addr: connection_id.dst,
+                        };
+                        let action = if system && !intercept_system {
+                            debug!(%connection_id, "Passing through System process connection.");
+                            ConnectionAction::None
+                        } else if is_controller(&ctx, controller_pid) {
+                            debug!(%connection_id, "Passing through mitmproxy connection.");
                             ConnectionAction::None
+                        } else {
+                            let decision = state.decide(&ctx);
+                            debug!(%connection_id, %decision, "Classified connection.");
+                            if !decision.intercept() {
+                                ConnectionAction::None
+                            } else if let Some(proxy_port) = redirect_to
+                                .filter(|port| can_redirect(&connection_id, address.event(), *port))
+                            {
+                                ConnectionAction::Redirect {
+                                    process_info: proc_info,
+                                    original_dst: connection_id.dst,
+                                    proxy_port,
+                                }
+                            } else {
+                                ConnectionAction::Intercept(proc_info)
+                            }
                         };
+                        let action = dry_run_action(action, &connection_id, dry_run);
 
                         insert_into_connections(
                             connection_id,
                             &action,
                             &address.event(),
                             &mut connections,
-                            &inject_handle,
+                            &mut injector,
                             &mut ipc_tx,
                         )
                         .await?;
                     }
                     WinDivertEvent::SocketListen => {
                         let pid = address.process_id();
-                        let process_name = get_process_name(pid)
-                            .map(|x| x.to_string_lossy().into_owned())
-                            .ok();
-                        debug!("Registering {:?} on {}.", process_name, connection_id.src);
+                        let process_name = process_names.get(pid);
+                        debug!(
+                            local_addr = %connection_id.src,
+                            pid,
+                            ?process_name,
+                            "Registering listener."
+                        );
                         active_listeners.insert(
                             connection_id.src,
                             proto,
@@ -308,12 +1762,13 @@ async fn main() -> Result<()> {
                     }
                     WinDivertEvent::SocketClose => {
                         // We cannot clean up here because there are still final packets on connections after this event,
-                        // But at least we can release memory for unknown connections.
-                        if let Some(ConnectionState::Unknown(packets)) =
-                            connections.get_mut(&connection_id)
-                        {
-                            packets.clear();
+                        // so we remove the connection after a grace period.
+                        // But at least we can release memory for unknown connections right away.
+                        let key = connection_id.canonical().0;
+                        if let Some(ConnectionState::Unknown(packets)) = connections.get_mut(&key) {
+                            *packets = SmallVec::new();
                         }
+                        closed_connections.push_back((Instant::now() + CLOSE_GRACE_PERIOD, key));
 
                         // There might be listen sockets we can clean up.
                         active_listeners.remove(connection_id.src, proto);
@@ -323,14 +1778,23 @@ async fn main() -> Result<()> {
             }
             Event::Ipc(ipc::from_proxy::Message::Packet(ipc::Packet { data: buf })) => {
                 let mut address = unsafe { WinDivertAddress::<NetworkLayer>::new() };
-                // if outbound is false, incoming connections are not re-injected into the right iface.
+                // Without an interface, inbound packets are not re-injected into the right iface,
+                // so we only inject inbound if we know where the connection's packets came from.
                 address.set_outbound(true);
-                address.set_ip_checksum(false);
-                address.set_tcp_checksum(false);
-                address.set_udp_checksum(false);
 
-                // TODO: Use Bytes everywhere to avoid allocation.
-                let packet = match InternetPacket::try_from(buf.to_vec()) {
+                // The decoded message owns its buffer exclusively, so this does not copy.
+                let mut data = Vec::from(buf);
+                // We compute all checksums ourselves instead of relying on checksum offload,
+                // which is not reliably available on all NICs.
+                if let Err(e) = packet::recompute_checksums(&mut data) {
+                    info!("Error computing checksums: {:?}", e);
+                    continue;
+                }
+                address.set_ip_checksum(true);
+                address.set_tcp_checksum(true);
+                address.set_udp_checksum(true);
+
+                let packet = match InternetPacket::try_from(data) {
                     Ok(p) => p,
                     Err(e) => {
                         info!("Error parsing packet: {:?}", e);
@@ -338,12 +1802,25 @@ async fn main() -> Result<()> {
                     }
                 };
 
+                // mitmproxy's packets travel opposite to the intercepted ones.
+                let (key, direction) = link_local_scopes.apply(packet.connection_id()).canonical();
+                if let Some(ConnectionState::Known(_, action_direction, observed)) =
+                    connections.get_mut(&key)
+                {
+                    match observed.origin {
+                        Some(origin) if direction != *action_direction => {
+                            origin.reply(&mut address)
+                        }
+                        _ => {}
+                    }
+                }
+
                 info!(
-                    "Injecting: {} {} with outbound={} loopback={}",
-                    packet.connection_id(),
-                    packet.tcp_flag_str(),
-                    address.outbound(),
-                    address.loopback()
+                    connection_id = %packet.connection_id(),
+                    flags = %packet.tcp_flag_str(),
+                    outbound = address.outbound(),
+                    loopback = address.loopback(),
+                    "Injecting."
                 );
 
                 let packet = WinDivertPacket::<NetworkLayer> {
@@ -351,42 +1828,248 @@ async fn main() -> Result<()> {
                     data: packet.inner().into(),
                 };
 
-                inject_handle.send(&packet)?;
+                injector.send(packet).await?;
+            }
+            Event::ReleasedPacket(data) => {
+                let packet = match InternetPacket::try_from(data) {
+                    Ok(p) => p,
+                    Err(e) => {
+                        debug!("Dropping released packet: {:?}", e);
+                        STATS.packets_dropped.fetch_add(1, Ordering::Relaxed);
+                        continue;
+                    }
+                };
+
+                // Unlike mitmproxy's own packets, these travel in their original direction.
+                let mut address = unsafe { WinDivertAddress::<NetworkLayer>::new() };
+                address.set_outbound(true);
+                let (key, direction) = link_local_scopes.apply(packet.connection_id()).canonical();
+                if let Some(ConnectionState::Known(_, action_direction, observed)) =
+                    connections.peek(&key)
+                {
+                    match observed.origin {
+                        Some(origin) if direction == *action_direction => {
+                            origin.apply(&mut address)
+                        }
+                        Some(origin) => origin.reply(&mut address),
+                        None => {}
+                    }
+                }
+                // Checksums were completed before the packet was sent to mitmproxy.
+                address.set_ip_checksum(true);
+                address.set_tcp_checksum(true);
+                address.set_udp_checksum(true);
+
+                debug!(
+                    connection_id = %packet.connection_id(),
+                    outbound = address.outbound(),
+                    "Re-injecting packet mitmproxy did not receive."
+                );
+                injector
+                    .send(WinDivertPacket {
+                        address,
+                        data: packet.inner().into(),
+                    })
+                    .await?;
+            }
+            Event::Ipc(ipc::from_proxy::Message::StatsRequest(request)) => {
+                let stats = STATS.snapshot(request.reset, connections.len());
+                reply_tx.send(ipc::from_redirector::Message::StatsResponse(stats))?;
+            }
+            Event::Ipc(ipc::from_proxy::Message::ConnectionTableRequest(request)) => {
+                let table = connection_table(&connections, request);
+                reply_tx.send(ipc::from_redirector::Message::ConnectionTableResponse(
+                    table,
+                ))?;
+            }
+            Event::Ipc(ipc::from_proxy::Message::ResetConnection(request)) => {
+                if let Err(e) = reset_connection(
+                    request,
+                    &mut connections,
+                    &mut link_local_scopes,
+                    &mut injector,
+                )
+                .await
+                {
+                    warn!("Error resetting connection: {:?}", e);
+                }
+            }
+            Event::Ipc(ipc::from_proxy::Message::OriginalDstRequest(request)) => {
+                // Always answer, so that the proxy is not left waiting.
+                let original_dst =
+                    lookup_original_dst(&request, &connections).unwrap_or_else(|e| {
+                        warn!("Invalid original destination request: {:?}", e);
+                        None
+                    });
+                reply_tx.send(ipc::from_redirector::Message::OriginalDstResponse(
+                    ipc::OriginalDstResponse {
+                        src: request.src,
+                        dst: request.dst,
+                        original_dst: original_dst.map(ipc::Address::from),
+                    },
+                ))?;
+            }
+            Event::Ipc(ipc::from_proxy::Message::SetDryRun(ipc::SetDryRun { enabled })) => {
+                // Connections we already know keep their action.
+                info!(enabled, "Dry run mode changed.");
+                dry_run = enabled;
+            }
+            // Unlike a new intercept spec, these keep the actions of connections we already know.
+            Event::Ipc(ipc::from_proxy::Message::AddInterceptPid(ipc::AddInterceptPid { pid })) => {
+                debug!(pid, "Adding PID to intercept spec.");
+                state.add_intercept_pid(pid);
+            }
+            Event::Ipc(ipc::from_proxy::Message::RemoveInterceptPid(ipc::RemoveInterceptPid {
+                pid,
+            })) => {
+                debug!(pid, "Removing PID from intercept spec.");
+                state.remove_intercept_pid(pid);
+            }
+            Event::Ipc(ipc::from_proxy::Message::Hello(_)) => {
+                warn!("Ignoring repeated IPC handshake.");
+            }
+            Event::ControllerPid(pid) => {
+                debug!(?pid, intercept_mitmproxy, "Connected to mitmproxy.");
+                controller_pid = pid.filter(|_| !intercept_mitmproxy);
+            }
+            // Keepalive responses are handled in handle_ipc.
+            Event::Ipc(ipc::from_proxy::Message::Pong(_)) => {}
+            Event::Ipc(ipc::from_proxy::Message::Shutdown(_)) if shutdown_deadline.is_some() => {
+                // Both mitmproxy and Ctrl-C may ask us to shut down, don't extend the deadline.
+            }
+            Event::Ipc(ipc::from_proxy::Message::Shutdown(_)) => {
+                info!("Shutting down...");
+                state = InterceptConf::disabled();
+                shutdown_deadline = Some(Instant::now() + DRAIN_TIMEOUT);
+
+                // Release packets that are still waiting for a socket event.
+                let unknown = connections
+                    .iter()
+                    .filter(|(_, state)| matches!(state, ConnectionState::Unknown(_)))
+                    .map(|(key, _)| *key)
+                    .collect::<Vec<_>>();
+                for key in unknown {
+                    if let Some(ConnectionState::Unknown(packets)) = connections.remove(&key) {
+                        for (address, packet, fragments) in packets {
+                            process_packet(
+                                address,
+                                packet,
+                                fragments,
+                                &ConnectionAction::None,
+                                &mut injector,
+                                &mut ipc_tx,
+                            )
+                            .await?;
+                        }
+                    }
+                }
+
+                // Connections we intercepted are passed through from now on,
+                // so that they do not hang while we drain.
+                let intercepted = connections
+                    .iter()
+                    .filter(|(_, state)| {
+                        matches!(
+                            state,
+                            ConnectionState::Known(ConnectionAction::Intercept(_), ..)
+                        )
+                    })
+                    .map(|(key, _)| *key)
+                    .collect::<Vec<_>>();
+                for key in intercepted {
+                    if let Some(ConnectionState::Known(action, ..)) = connections.get_mut(&key) {
+                        *action = ConnectionAction::None;
+                    }
+                }
             }
             Event::Ipc(ipc::from_proxy::Message::InterceptConf(conf)) => {
                 state = conf.try_into()?;
                 info!("{}", state.description());
 
-                // Handle preexisting connections.
+                // Handle preexisting connections. Connections we redirected to a proxy are kept,
+                // their packets need to be rewritten until they are closed. Connections still
+                // waiting for their socket event are kept as well, so that their buffered packets
+                // are not lost. The new config applies to them once we know their process.
+                let kept = connections
+                    .iter()
+                    .filter(|(_, state)| {
+                        matches!(
+                            state,
+                            ConnectionState::Known(
+                                ConnectionAction::Redirect { .. }
+                                    | ConnectionAction::RestoreSource(_),
+                                ..
+                            ) | ConnectionState::Unknown(_)
+                        )
+                    })
+                    .map(|(key, _)| *key)
+                    .collect::<Vec<_>>();
+                let kept = kept
+                    .into_iter()
+                    .filter_map(|key| Some((key, connections.remove(&key)?)))
+                    .collect::<Vec<_>>();
+                for (key, state) in connections.iter() {
+                    if let ConnectionState::Known(action, direction, _) = state {
+                        monitor(key, *direction, "close", action);
+                    }
+                }
                 connections.clear();
+                for (key, state) in kept {
+                    connections.insert(key, state);
+                }
                 active_listeners.clear();
                 for e in network_table()? {
                     let proc_info = ProcessInfo {
                         pid: e.pid,
-                        process_name: get_process_name(e.pid)
-                            .map(|x| x.to_string_lossy().into_owned())
-                            .ok(),
+                        process_name: process_names.get(e.pid),
                     };
                     let proto = TransportProtocol::try_from(e.protocol)?;
                     if e.remote_addr.ip().is_unspecified() {
                         active_listeners.insert(e.local_addr, proto, proc_info);
                     } else {
-                        let connection_id = ConnectionId {
+                        let connection_id = link_local_scopes.apply(ConnectionId {
                             proto,
                             src: e.local_addr,
                             dst: e.remote_addr,
+                        });
+                        // Unknown connections are classified here, which releases their packets.
+                        if matches!(
+                            connections.peek(&connection_id.canonical().0),
+                            Some(ConnectionState::Known(..))
+                        ) {
+                            continue;
+                        }
+                        let ancestors = (state.needs_ancestors() || controller_pid.is_some())
+                            .then(|| parent_processes.ancestors(proc_info.pid))
+                            .unwrap_or_default();
+                        let ctx = ConnectionContext {
+                            process_info: &proc_info,
+                            ancestors: &ancestors,
+                            local_addr: e.local_addr,
+                            remote_addr: e.remote_addr,
                         };
-                        let action = if state.should_intercept(&proc_info) {
-                            ConnectionAction::Intercept(proc_info)
-                        } else {
+                        let action = if e.pid == SYSTEM_PID && !intercept_system {
+                            debug!(%connection_id, "Passing through System process connection.");
                             ConnectionAction::None
+                        } else if is_controller(&ctx, controller_pid) {
+                            debug!(%connection_id, "Passing through mitmproxy connection.");
+                            ConnectionAction::None
+                        } else {
+                            let decision = state.decide(&ctx);
+                            debug!(%connection_id, %decision, "Classified connection.");
+                            if decision.intercept() {
+                                ConnectionAction::Intercept(proc_info)
+                            } else {
+                                ConnectionAction::None
+                            }
                         };
+                        let action = dry_run_action(action, &connection_id, dry_run);
                         insert_into_connections(
                             connection_id,
                             &action,
                             &WinDivertEvent::ReflectOpen,
                             &mut connections,
-                            &inject_handle,
+                            &mut injector,
                             &mut ipc_tx,
                         )
                         .await?;
@@ -397,147 +2080,1125 @@ async fn main() -> Result<()> {
     }
 }
 
-async fn handle_ipc(
-    mut ipc: NamedPipeClient,
+/// Keep the IPC connection to mitmproxy alive, reconnecting with backoff if the pipe breaks.
+#[allow(clippy::too_many_arguments)]
+async fn run_ipc(
+    pipe_name: String,
+    ipc_client: NamedPipeClient,
+    keepalive_interval: Duration,
+    conf_cache: Option<PathBuf>,
     mut ipc_rx: UnboundedReceiver<ipc::PacketWithMeta>,
-    tx: UnboundedSender<Event>,
+    tx: Sender<Event>,
+    mut error_rx: UnboundedReceiver<ipc::Error>,
+    mut reply_rx: UnboundedReceiver<ipc::from_redirector::Message>,
 ) -> Result<()> {
-    let mut buf = [0u8; IPC_BUF_SIZE];
+    // The most recent intercept config received from mitmproxy, re-applied after reconnecting.
+    let mut conf: ipc::InterceptConf = conf_cache
+        .as_deref()
+        .and_then(load_conf_cache)
+        .unwrap_or_else(InterceptConf::disabled)
+        .into();
+    let mut ipc_client = Some(ipc_client);
+
     loop {
-        tokio::select! {
-            r = ipc.read(&mut buf) => {
-                match r {
-                    Ok(len) if len > 0 => {
-
-                        let mut cursor = Cursor::new(&buf[..len]);
-                        let Ok(FromProxy { message: Some(message)}) = FromProxy::decode(&mut cursor) else {
-                            return Err(anyhow!("Received invalid IPC message: {:?}", &buf[..len]));
-                        };
-                        assert_eq!(cursor.position(), len as u64);
+        let client = match ipc_client.take() {
+            Some(client) => client,
+            None => match reconnect(&pipe_name).await {
+                Ok(client) => client,
+                Err(e) => {
+                    info!("{:?}. Exiting.", e);
+                    std::process::exit(0);
+                }
+            },
+        };
+        let mut ipc = Framed::new(client, ipc::codec());
+        let Some(session) = handshake(&mut ipc).await? else {
+            info!("IPC read failed. Exiting.");
+            std::process::exit(0);
+        };
+        IPC_CONNECTION_EVENTS.store(session.connection_events, Ordering::Relaxed);
+        // Before the intercept spec, so that it already applies to existing connections.
+        tx.send(Event::ControllerPid(session.pid)).await?;
+        tx.send(Event::Ipc(ipc::from_proxy::Message::InterceptConf(
+            conf.clone(),
+        )))
+        .await?;
 
-                        tx.send(Event::Ipc(message))?;
-                    }
-                    _ => {
-                        info!("IPC read failed. Exiting.");
-                        std::process::exit(0);
+        let disconnect = handle_ipc(
+            ipc,
+            session.encoding,
+            keepalive_interval,
+            &mut ipc_rx,
+            &tx,
+            &mut conf,
+            conf_cache.as_deref(),
+            &mut error_rx,
+            &mut reply_rx,
+        )
+        .await;
+        match disconnect {
+            Ok(Disconnect::Shutdown) => {
+                // Packets that mitmproxy will not see anymore are re-injected unmodified.
+                while let Ok(packet) = ipc_rx.try_recv() {
+                    tx.send(Event::ReleasedPacket(Vec::from(packet.data)))
+                        .await?;
+                }
+                return Ok(());
+            }
+            Ok(Disconnect::Fatal(code)) => std::process::exit(code),
+            Ok(Disconnect::Closed) => info!("IPC connection closed. Reconnecting..."),
+            Err(e) => warn!("IPC connection broke: {:?}. Reconnecting...", e),
+        }
+
+        // Pass all traffic through while we are disconnected, and discard packets, replies
+        // and connection events that were meant for the previous connection.
+        tx.send(Event::Ipc(ipc::from_proxy::Message::InterceptConf(
+            InterceptConf::disabled().into(),
+        )))
+        .await?;
+        while ipc_rx.try_recv().is_ok() {}
+        while reply_rx.try_recv().is_ok() {}
+    }
+}
+
+async fn reconnect(pipe_name: &str) -> Result<NamedPipeClient> {
+    let backoff = ipc::Backoff::new(Duration::from_millis(100), Duration::from_secs(5), 10);
+    open_pipe(pipe_name, backoff)
+        .await
+        .context("Cannot reopen pipe")
+}
+
+const ERROR_FILE_NOT_FOUND: i32 = 2;
+const ERROR_PIPE_BUSY: i32 = 231;
+
+/// Open the pipe, retrying while it does not exist yet or all its instances are busy.
+async fn open_pipe(pipe_name: &str, mut backoff: ipc::Backoff) -> Result<NamedPipeClient> {
+    loop {
+        let result = ClientOptions::new()
+            .pipe_mode(PipeMode::Byte)
+            .open(pipe_name);
+        match result {
+            Ok(client) => return Ok(client),
+            Err(e) => {
+                let transient = matches!(
+                    e.raw_os_error(),
+                    Some(ERROR_FILE_NOT_FOUND | ERROR_PIPE_BUSY)
+                );
+                match backoff.next() {
+                    Some(delay) if transient => {
+                        debug!("Cannot open pipe ({}), retrying in {:?}.", e, delay);
+                        tokio::time::sleep(delay).await;
                     }
+                    _ => return Err(e.into()),
                 }
-            },
-            Some(packet) = ipc_rx.recv() => {
-                packet.encode(&mut buf.as_mut_slice())?;
-                let len = packet.encoded_len();
+            }
+        }
+    }
+}
+
+/// When shutting down, we exit once no events have arrived for this long...
+const DRAIN_IDLE_TIMEOUT: Duration = Duration::from_millis(100);
+/// ...or after this long at the latest.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How long we wait for a fatal error to be sent to mitmproxy before exiting anyway.
+const ERROR_REPORT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long the IPC channel may be idle before we check whether mitmproxy is still alive.
+/// Can be overridden with `--keepalive`.
+const DEFAULT_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How long we remember a connection after its last packet, unless overridden with
+/// `--connection-expiry`. Expired connections are treated like new ones.
+const DEFAULT_CONNECTION_EXPIRY: Duration = Duration::from_secs(60 * 10);
 
-                ipc.write_all(&buf[..len]).await?;
+/// How long we hold back the packets of an outgoing connection while waiting for its socket
+/// event, unless overridden with `--unknown-timeout`. Some connections, e.g. those of drivers,
+/// never get one.
+const DEFAULT_UNKNOWN_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How many packets we hold back per connection while waiting for its socket event, unless
+/// overridden with `--max-unknown-packets`.
+const DEFAULT_MAX_UNKNOWN_PACKETS: usize = 64;
+
+/// The System process. Besides a lot of noise (see [`is_system_noise`]), it owns connections of
+/// kernel-mode clients and servers such as SMB and some VPNs.
+const SYSTEM_PID: u32 = 4;
+
+/// How often we list link-local addresses at most when looking up an unknown one.
+const LINK_LOCAL_REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long we keep handling packets for a connection after its socket has been closed.
+const CLOSE_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+/// The maximum number of connections we remember, unless overridden with `--max-connections`.
+/// Beyond that, the least recently used connection is dropped.
+const DEFAULT_MAX_CONNECTIONS: usize = 100_000;
+
+/// How often and how long we wait before retrying to inject a packet while the system is
+/// out of resources.
+const INJECT_RETRIES: usize = 5;
+const INJECT_RETRY_DELAY: Duration = Duration::from_millis(1);
+const INJECT_RETRY_MAX_DELAY: Duration = Duration::from_millis(20);
+
+/// The default of `--priority`.
+const DEFAULT_PRIORITY: i16 = 1037;
+
+// The WinDivert priorities of our handles, relative to `--priority`. Packets a handle injects
+// skip all handles of the same or higher priority, so each inject handle ranks below the
+// handles it reinjects for. Otherwise, reinjected packets would be captured again.
+// Dropping ICMP redirects ranks above everything else.
+const PRIORITY_FORWARD_INJECT: i16 = 0;
+const PRIORITY_FORWARD: i16 = 1;
+const PRIORITY_INJECT: i16 = 2;
+const PRIORITY_NETWORK: i16 = 3;
+const PRIORITY_SOCKET: i16 = 4;
+const PRIORITY_ICMP_REDIRECT: i16 = 5;
+
+/// ICMP and ICMPv6 redirects, which we drop in forward mode.
+const ICMP_REDIRECT_FILTER: &str = "(icmp && icmp.Type == 5) || (icmpv6 && icmpv6.Type == 137)";
+
+/// ICMP errors we capture in addition to the capture filter, so that we can relay them for
+/// intercepted connections.
+const ICMP_ERROR_FILTER: &str = "(icmp && (icmp.Type == 3 || icmp.Type == 11 || icmp.Type == 12)) || (icmpv6 && icmpv6.Type >= 1 && icmpv6.Type <= 4)";
+
+/// IPv4 fragments we capture in addition to the capture filter. Non-first fragments carry no
+/// transport header, so the capture filter cannot match them before we have reassembled them.
+const IPV4_FRAGMENT_FILTER: &str = "(ip && (ip.MF || ip.FragOff > 0))";
+
+/// The pipe we connect to if none is given on the command line.
+const DEFAULT_PIPE_NAME: &str = r"\\.\pipe\mitmproxy-transparent-proxy";
+
+/// UDP ports of local service discovery and configuration, which break or flood mitmproxy
+/// if intercepted: DHCP, DHCPv6, SSDP, mDNS and LLMNR. Can be overridden with
+/// `--pass-through-udp-ports`.
+const DEFAULT_PASS_THROUGH_UDP_PORTS: [u16; 7] = [67, 68, 546, 547, 1900, 5353, 5355];
+
+/// How many events may be waiting for the main loop, unless overridden with `--queue-size`.
+/// What happens when the queue is full is decided by `--on-overflow` (`block` or `drop`).
+const DEFAULT_QUEUE_SIZE: usize = 4096;
+
+/// The WinDivert filter for packets and socket events we look at, unless overridden with
+/// `--filter`. It must be valid for both the network and the socket layer.
+/// Other protocols (e.g. SCTP) captured by a custom filter are passed through untouched.
+const DEFAULT_CAPTURE_FILTER: &str = "tcp || udp";
+
+/// How many packets we inject with a single system call at most...
+const MAX_INJECT_BATCH: usize = 64;
+/// ...and how long a packet may wait for its batch to fill up.
+const MAX_INJECT_DELAY: Duration = Duration::from_millis(1);
+
+/// The maximum number of entries in a connection table response,
+/// so that the response fits into a single IPC frame.
+const MAX_CONNECTION_TABLE_PAGE: usize = 256;
+
+/// How often a relay thread is restarted after a panic before we exit.
+const MAX_RELAY_RESTARTS: usize = 3;
+
+/// How often we warn about a full event queue, in occurrences.
+const QUEUE_FULL_WARN_INTERVAL: u64 = 1000;
+
+/// The size at which `--log-file` is rotated.
+const LOG_FILE_MAX_SIZE: u64 = 10 * 1024 * 1024;
+
+/// How often the pcap file is flushed to disk.
+const PCAP_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+type Pcap = Option<packet::PcapWriter<BufWriter<File>>>;
+
+/// Record a packet in the pcap file, if enabled.
+/// A failing pcap file should not take down the redirector, so we stop writing to it instead.
+fn write_pcap(pcap: &mut Pcap, data: &[u8]) {
+    if let Some(writer) = pcap {
+        if let Err(e) = writer.write(data, SystemTime::now()) {
+            warn!("Error writing pcap file, disabling capture: {:?}", e);
+            *pcap = None;
+        }
+    }
+}
+
+fn flush_pcap(pcap: &mut Pcap) {
+    if let Some(writer) = pcap {
+        if let Err(e) = writer.flush() {
+            warn!("Error flushing pcap file, disabling capture: {:?}", e);
+            *pcap = None;
+        }
+    }
+}
+
+/// The threads relaying WinDivert events into the main loop.
+///
+/// Relays run on tokio's blocking pool. They check the stop flag between receives,
+/// so a relay that is blocked in WinDivertRecvEx only stops once it receives another event.
+///
+/// A relay that panics is restarted with the same handle a few times before we give up.
+struct Relays {
+    stop: Arc<AtomicBool>,
+    /// Each task returns the reason why its relay gave up, if it did.
+    tasks: JoinSet<Option<String>>,
+}
+
+impl Relays {
+    fn new() -> Self {
+        Self {
+            stop: Arc::new(AtomicBool::new(false)),
+            tasks: JoinSet::new(),
+        }
+    }
+
+    fn spawn(&mut self, name: &'static str, relay: impl Fn(&AtomicBool) + Send + 'static) {
+        let stop = self.stop.clone();
+        self.tasks.spawn_blocking(move || {
+            let mut restarts = 0;
+            loop {
+                let Err(payload) = panic::catch_unwind(AssertUnwindSafe(|| relay(&stop))) else {
+                    return None;
+                };
+                let message = panic_message(payload.as_ref());
+                if restarts == MAX_RELAY_RESTARTS {
+                    return Some(format!("{name} relay panicked: {message}"));
+                }
+                restarts += 1;
+                error!("{} relay panicked, restarting: {}", name, message);
             }
+        });
+    }
+
+    /// Wait for all relays to finish, and exit with an error if one of them gives up.
+    /// Without its relay, we would silently stop receiving events for that layer.
+    async fn supervise(mut self, error_tx: UnboundedSender<ipc::Error>) {
+        while let Some(result) = self.tasks.join_next().await {
+            let message = match result {
+                Ok(None) => continue,
+                Ok(Some(message)) => message,
+                Err(e) => format!("relay task failed: {e}"),
+            };
+            exit_with_error(&error_tx, 70, message);
         }
     }
 }
 
+fn panic_message(payload: &(dyn std::any::Any + Send)) -> &str {
+    payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown panic")
+}
+
 /// Repeatedly call WinDivertRecvEx to get socket info and feed them into the channel.
-fn relay_socket_events(handle: WinDivert<SocketLayer>, tx: UnboundedSender<Event>) {
-    loop {
+fn relay_socket_events(
+    handle: &WinDivert<SocketLayer>,
+    tx: &Sender<Event>,
+    error_tx: &UnboundedSender<ipc::Error>,
+    stop: &AtomicBool,
+) {
+    while !stop.load(Ordering::Relaxed) {
         let packets = handle.recv_ex(1); // FIXME: more?
         match packets {
             Ok(packets) => {
                 for packet in packets {
-                    if tx.send(Event::SocketInfo(packet.address)).is_err() {
+                    // Socket events are never dropped, or we would not know about the connection.
+                    if tx.blocking_send(Event::SocketInfo(packet.address)).is_err() {
                         return; // main thread shut down.
                     }
                 }
             }
             Err(err) => {
-                eprintln!("WinDivert Error: {err:?}");
-                std::process::exit(74);
+                exit_with_error(error_tx, 74, format!("WinDivert Error: {err:?}"));
             }
         };
     }
 }
 
 /// Repeatedly call WinDivertRecvEx to get network packets and feed them into the channel.
-fn relay_network_events(handle: WinDivert<NetworkLayer>, tx: UnboundedSender<Event>) {
+fn relay_network_events(
+    handle: &WinDivert<NetworkLayer>,
+    tx: &Sender<Event>,
+    error_tx: &UnboundedSender<ipc::Error>,
+    overflow: OverflowPolicy,
+    stop: &AtomicBool,
+) {
     const MAX_PACKETS: usize = 1;
-    let mut buf = [0u8; MAX_PACKET_SIZE * MAX_PACKETS];
-    loop {
-        let packets = handle.recv_ex(Some(&mut buf), MAX_PACKETS);
+    let mut buf = vec![0u8; RECV_BUF_SIZE * MAX_PACKETS];
+    while !stop.load(Ordering::Relaxed) {
+        let packets = handle.recv_ex(Some(&mut buf[..]), MAX_PACKETS);
         match packets {
             Ok(packets) => {
                 for packet in packets {
-                    if tx
-                        .send(Event::NetworkPacket(packet.address, packet.data.into()))
-                        .is_err()
-                    {
+                    STATS.packets_received.fetch_add(1, Ordering::Relaxed);
+                    let event = Event::NetworkPacket(packet.address, packet.data.into());
+                    if !send_packet_event(tx, event, overflow) {
                         return; // main thread shut down.
                     }
                 }
             }
-            Err(err) => {
-                eprintln!("WinDivert Error: {err:?}");
-                std::process::exit(74);
+            Err(err) => recv_error(error_tx, err),
+        };
+    }
+}
+
+fn relay_forward_events(
+    handle: &WinDivert<ForwardLayer>,
+    tx: &Sender<Event>,
+    error_tx: &UnboundedSender<ipc::Error>,
+    overflow: OverflowPolicy,
+    stop: &AtomicBool,
+) {
+    const MAX_PACKETS: usize = 1;
+    let mut buf = vec![0u8; RECV_BUF_SIZE * MAX_PACKETS];
+    while !stop.load(Ordering::Relaxed) {
+        let packets = handle.recv_ex(Some(&mut buf[..]), MAX_PACKETS);
+        match packets {
+            Ok(packets) => {
+                for packet in packets {
+                    STATS.packets_received.fetch_add(1, Ordering::Relaxed);
+                    let event = Event::ForwardPacket(packet.address, packet.data.into());
+                    if !send_packet_event(tx, event, overflow) {
+                        return; // main thread shut down.
+                    }
+                }
             }
+            Err(err) => recv_error(error_tx, err),
         };
     }
 }
 
+/// The largest packet WinDivert captures: an IPv6 header and a payload of 65535 bytes.
+/// This is slightly more than `mitmproxy::MAX_PACKET_SIZE`.
+const RECV_BUF_SIZE: usize = 40 + 0xffff;
+
+/// Handle an error receiving packets. Only a packet that does not fit into the buffer is
+/// recoverable, everything else is fatal.
+fn recv_error(error_tx: &UnboundedSender<ipc::Error>, err: WinDivertError) {
+    match err {
+        // WinDivert dequeues the packet before reporting this, so receiving again would get the
+        // next packet. The buffer fits any packet WinDivert captures, so this is not expected.
+        WinDivertError::Recv(WinDivertRecvError::InsufficientBuffer) => {
+            STATS.packets_dropped.fetch_add(1, Ordering::Relaxed);
+            warn!(
+                "Dropping packet larger than the receive buffer of {} bytes.",
+                RECV_BUF_SIZE
+            );
+        }
+        err => exit_with_error(error_tx, 74, format!("WinDivert Error: {err:?}")),
+    }
+}
+
+/// Queue a packet for the main loop according to the overflow policy.
+/// Returns false if the main loop has shut down.
+fn send_packet_event(tx: &Sender<Event>, event: Event, overflow: OverflowPolicy) -> bool {
+    let event = match tx.try_send(event) {
+        Ok(()) => return true,
+        Err(TrySendError::Full(event)) => event,
+        Err(TrySendError::Closed(_)) => return false,
+    };
+    static QUEUE_FULL: AtomicU64 = AtomicU64::new(0);
+    let n = QUEUE_FULL.fetch_add(1, Ordering::Relaxed);
+    if n % QUEUE_FULL_WARN_INTERVAL == 0 {
+        warn!(
+            times = n + 1,
+            "Event queue is full. If packets get lost, consider a larger --queue-size or \
+            --wd-queue-length."
+        );
+    }
+    match overflow {
+        OverflowPolicy::Block => tx.blocking_send(event).is_ok(),
+        OverflowPolicy::Drop => {
+            debug!("Event queue is full, dropping packet.");
+            STATS.packets_dropped.fetch_add(1, Ordering::Relaxed);
+            true
+        }
+    }
+}
+
+/// Apply the `--wd-queue-*` arguments to a capturing handle.
+fn set_queue_params(
+    mut set_param: impl FnMut(WinDivertParam, u64) -> Result<(), WinDivertError>,
+    options: &Options,
+) -> Result<()> {
+    let params = [
+        (WinDivertParam::QueueLength, options.wd_queue_length),
+        (WinDivertParam::QueueTime, options.wd_queue_time),
+        (WinDivertParam::QueueSize, options.wd_queue_size),
+    ];
+    for (param, value) in params {
+        if let Some(value) = value {
+            set_param(param, value)
+                .with_context(|| format!("Cannot set {:?} to {}", param, value))?;
+        }
+    }
+    Ok(())
+}
+
+/// Check everything we need for capturing traffic and print a report.
+/// Returns whether all checks passed.
+///
+/// No traffic is diverted: the socket handle only sniffs, and all other handles
+/// are opened with a filter that matches nothing.
+fn doctor(pipe_name: &str, capture_filter: &str, mode: Mode, priority: i16) -> bool {
+    let mut checks = vec![
+        ("Administrator privileges", check_elevated()),
+        ("WinDivert driver present", check_driver_file()),
+        // Opening the first handle loads the driver.
+        (
+            "WinDivert driver loadable",
+            check_open(
+                WinDivert::network("false", priority + PRIORITY_NETWORK, WinDivertFlags::new()),
+                "false",
+            ),
+        ),
+        (
+            "Socket handle",
+            check_open(
+                WinDivert::socket(
+                    capture_filter,
+                    priority + PRIORITY_SOCKET,
+                    WinDivertFlags::new().set_recv_only().set_sniff(),
+                ),
+                capture_filter,
+            ),
+        ),
+        (
+            "Injection handle",
+            check_open(
+                WinDivert::network(
+                    "false",
+                    priority + PRIORITY_INJECT,
+                    WinDivertFlags::new().set_send_only(),
+                ),
+                "false",
+            ),
+        ),
+    ];
+    if mode == Mode::Forward {
+        checks.push((
+            "Forwarding handle",
+            check_open(
+                WinDivert::forward("false", priority + PRIORITY_FORWARD, WinDivertFlags::new()),
+                "false",
+            ),
+        ));
+        checks.push((
+            "Forwarding injection handle",
+            check_open(
+                WinDivert::forward(
+                    "false",
+                    priority + PRIORITY_FORWARD_INJECT,
+                    WinDivertFlags::new().set_send_only(),
+                ),
+                "false",
+            ),
+        ));
+    }
+    checks.push(("Connect to mitmproxy", check_pipe(pipe_name)));
+
+    let mut passed = true;
+    for (name, result) in checks {
+        match result {
+            Ok(()) => println!("[ OK ] {}", name),
+            Err(e) => {
+                passed = false;
+                println!("[FAIL] {}\n       {:#}", name, e);
+            }
+        }
+    }
+    passed
+}
+
+fn check_elevated() -> Result<()> {
+    if elevation::is_elevated().context("Cannot determine whether we are elevated.")? {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "Not running as administrator. Run the redirector from an elevated prompt."
+        ))
+    }
+}
+
+fn check_driver_file() -> Result<()> {
+    let exe = env::current_exe().context("Cannot determine the redirector's location.")?;
+    let sys = exe.with_file_name("WinDivert64.sys");
+    if sys.exists() {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "{} not found. Reinstall mitmproxy, the driver must be next to the redirector.",
+            sys.display()
+        ))
+    }
+}
+
+fn check_open<T>(result: Result<T, WinDivertError>, filter: &str) -> Result<()> {
+    result.map(|_| ()).map_err(|e| open_error(e, filter))
+}
+
+/// Connecting occupies the pipe instance, so the waiting mitmproxy sees the redirector exit.
+fn check_pipe(pipe_name: &str) -> Result<()> {
+    let result = ClientOptions::new()
+        .pipe_mode(PipeMode::Byte)
+        .open(pipe_name);
+    match result {
+        Ok(_) => Ok(()),
+        // The pipe exists, but another redirector is already connected.
+        Err(e) if e.raw_os_error() == Some(ERROR_PIPE_BUSY) => Ok(()),
+        Err(e) => {
+            let hint = if e.raw_os_error() == Some(ERROR_FILE_NOT_FOUND) {
+                format!(
+                    "{} does not exist. Start mitmproxy in local mode, \
+                    or pass the pipe name it uses with --pipe.",
+                    pipe_name
+                )
+            } else {
+                format!("Cannot open {}.", pipe_name)
+            };
+            Err(anyhow::Error::new(e).context(hint))
+        }
+    }
+}
+
+/// Explain why a WinDivert handle could not be opened.
+///
+/// Missing privileges and driver problems are the most common failures on first run,
+/// so we tell users what to do about them instead of only showing the raw error.
+fn open_error(e: WinDivertError, filter: &str) -> anyhow::Error {
+    let hint = match &e {
+        WinDivertError::Open(WinDivertOpenError::AccessDenied) => {
+            "The redirector must be run as administrator.".to_string()
+        }
+        WinDivertError::Open(WinDivertOpenError::MissingSYS) => {
+            "WinDivert driver not installed: WinDivert64.sys must be next to the redirector."
+                .to_string()
+        }
+        WinDivertError::Open(WinDivertOpenError::MissingInstall) => {
+            "The WinDivert driver is being uninstalled, please try again.".to_string()
+        }
+        WinDivertError::Open(WinDivertOpenError::InvalidImageHash) => {
+            "The WinDivert driver has an invalid signature.".to_string()
+        }
+        WinDivertError::Open(WinDivertOpenError::IncompatibleVersion) => {
+            "An incompatible version of the WinDivert driver is already loaded. \
+            Close other programs that use WinDivert or reboot."
+                .to_string()
+        }
+        WinDivertError::Open(WinDivertOpenError::DriverBlocked) => {
+            "The WinDivert driver is blocked by security software \
+            or an incompatible virtualization environment."
+                .to_string()
+        }
+        WinDivertError::Open(WinDivertOpenError::BaseFilteringEngineDisabled) => {
+            "WinDivert requires the Base Filtering Engine service, which is disabled.".to_string()
+        }
+        WinDivertError::Open(WinDivertOpenError::InvalidParameter) => {
+            format!("Invalid capture filter: {}", filter)
+        }
+        _ => "Error opening WinDivert handle".to_string(),
+    };
+    anyhow::Error::new(e).context(hint)
+}
+
+/// Describe a page of the connection table, e.g. to find connections that are stuck.
+fn connection_table(
+    connections: &LruCache<CanonicalConnectionId, ConnectionState>,
+    request: ipc::ConnectionTableRequest,
+) -> ipc::ConnectionTableResponse {
+    let limit = match request.limit as usize {
+        0 => MAX_CONNECTION_TABLE_PAGE,
+        n => n.min(MAX_CONNECTION_TABLE_PAGE),
+    };
+    // Looking at the table must not keep connections from expiring.
+    let entries = connections
+        .peek_iter()
+        .skip(request.offset as usize)
+        .take(limit)
+        .map(|(key, state)| match state {
+            ConnectionState::Known(action, direction, observed) => {
+                let (state, tunnel_info) = action.describe();
+                ipc::ConnectionTableEntry {
+                    connection_id: key.directed(*direction).to_string(),
+                    state: state.to_string(),
+                    tunnel_info,
+                    buffered_packets: 0,
+                    packets_sent: observed.counters.packets_sent,
+                    bytes_sent: observed.counters.bytes_sent,
+                    packets_received: observed.counters.packets_received,
+                    bytes_received: observed.counters.bytes_received,
+                    server_name: observed
+                        .metadata
+                        .as_ref()
+                        .and_then(|m| m.server_name.clone())
+                        .unwrap_or_default(),
+                }
+            }
+            ConnectionState::Unknown(packets) => ipc::ConnectionTableEntry {
+                connection_id: key.directed(Direction::Forward).to_string(),
+                state: "unknown".to_string(),
+                tunnel_info: None,
+                buffered_packets: packets.len() as u32,
+                ..Default::default()
+            },
+        })
+        .collect();
+    ipc::ConnectionTableResponse {
+        entries,
+        total: connections.len() as u32,
+    }
+}
+
+/// Whether a socket event of the System process is operating system noise rather than an actual
+/// connection or listener, e.g. for sockets that are not bound yet.
+fn is_system_noise(address: &WinDivertAddress<SocketLayer>) -> bool {
+    if address.local_port() == 0 {
+        return true;
+    }
+    match address.event() {
+        WinDivertEvent::SocketConnect | WinDivertEvent::SocketAccept => {
+            address.remote_address().is_unspecified() || address.remote_port() == 0
+        }
+        _ => false,
+    }
+}
+
+/// Whether a packet belongs to local service discovery that we pass through,
+/// see `--pass-through-udp-ports`.
+fn is_local_service(packet: &InternetPacket, ports: &[u16], conf: &InterceptConf) -> bool {
+    packet.protocol() == TransportProtocol::Udp
+        && [packet.src().port(), packet.dst().port()]
+            .into_iter()
+            .any(|port| ports.contains(&port) && !conf.includes_port(port))
+}
+
+/// Send a forwarded packet on its way.
+fn forward_packet(
+    forward_inject_handle: &WinDivert<ForwardLayer>,
+    address: WinDivertAddress<ForwardLayer>,
+    mut data: Vec<u8>,
+) {
+    STATS.record_latency(address.timestamp());
+    // We route these packets, so we need to do what a router does.
+    match packet::decrement_ttl(&mut data) {
+        Ok(true) => {}
+        Ok(false) => {
+            debug!("Dropping forwarded packet with exhausted TTL.");
+            STATS.packets_dropped.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        Err(e) => debug!("Cannot decrement TTL of forwarded packet: {}", e),
+    }
+    let packet = WinDivertPacket {
+        address,
+        data: data.into(),
+    };
+    match forward_inject_handle.send(&packet) {
+        Ok(_) => {
+            STATS.packets_injected.fetch_add(1, Ordering::Relaxed);
+        }
+        Err(e) => {
+            warn!("Failed to inject forwarded packet: {}", e);
+            STATS.injection_errors.fetch_add(1, Ordering::Relaxed);
+            STATS.packets_dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+#[instrument(level = "debug", skip_all, fields(%connection_id, ?action))]
 async fn insert_into_connections(
     connection_id: ConnectionId,
     action: &ConnectionAction,
     event: &WinDivertEvent,
-    connections: &mut LruCache<ConnectionId, ConnectionState>,
-    inject_handle: &WinDivert<NetworkLayer>,
+    connections: &mut LruCache<CanonicalConnectionId, ConnectionState>,
+    injector: &mut Injector,
     ipc_tx: &mut UnboundedSender<ipc::PacketWithMeta>,
 ) -> Result<()> {
-    debug!("Adding: {} with {:?} ({:?})", &connection_id, action, event);
+    debug!(?event, "Adding connection.");
+    if let ConnectionAction::Redirect {
+        original_dst,
+        proxy_port,
+        ..
+    } = action
+    {
+        // The proxy's replies are a connection of their own.
+        let (key, direction) = proxy_connection(&connection_id, *proxy_port).canonical();
+        let replies = ConnectionAction::RestoreSource(*original_dst);
+        insert_connection(
+            connections,
+            key,
+            ConnectionState::Known(replies, direction, Observed::default()),
+            injector,
+            ipc_tx,
+        )
+        .await?;
+    }
     // no matter which action we do, the reverse direction is whitelisted.
+    let (key, direction) = connection_id.canonical();
+    let existing = insert_connection(
+        connections,
+        key,
+        ConnectionState::Known(action.clone(), direction, Observed::default()),
+        injector,
+        ipc_tx,
+    )
+    .await?;
 
-    let existing1 = connections.insert(
-        connection_id.reverse(),
-        ConnectionState::Known(ConnectionAction::None),
-    );
-    let existing2 = connections.insert(connection_id, ConnectionState::Known(action.clone()));
+    if let Some(ConnectionState::Unknown(packets)) = existing {
+        for (a, p, f) in packets {
+            let packet_direction = p.connection_id().canonical().1;
+            if let Some(state) = connections.get_mut(&key) {
+                state.count(packet_direction, p.payload().len());
+                state.observe(packet_direction, &a);
+                state.peek(packet_direction, &p);
+            }
+            if packet_direction == direction {
+                process_packet(a, p, f, action, injector, ipc_tx).await?;
+            } else {
+                process_packet(a, p, f, &ConnectionAction::None, injector, ipc_tx).await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Stop waiting for the socket event of an unknown connection: pass it through from now on,
+/// and re-inject the packets buffered so far.
+async fn pass_through_unknown(
+    key: CanonicalConnectionId,
+    packets: PacketBuffer,
+    connections: &mut LruCache<CanonicalConnectionId, ConnectionState>,
+    injector: &mut Injector,
+    ipc_tx: &mut UnboundedSender<ipc::PacketWithMeta>,
+) -> Result<()> {
+    let direction = match packets.first() {
+        Some((_, packet, _)) => packet.connection_id().canonical().1,
+        // SocketClose already released the buffer. Passing through applies to both directions,
+        // so the key's own order does as well.
+        None => Direction::Forward,
+    };
+    let mut known = ConnectionState::Known(ConnectionAction::None, direction, Observed::default());
+    for (address, packet, _) in &packets {
+        let packet_direction = packet.connection_id().canonical().1;
+        known.count(packet_direction, packet.payload().len());
+        known.observe(packet_direction, address);
+        known.peek(packet_direction, packet);
+    }
+    insert_connection(connections, key, known, injector, ipc_tx).await?;
+    for (address, packet, fragments) in packets {
+        process_packet(
+            address,
+            packet,
+            fragments,
+            &ConnectionAction::None,
+            injector,
+            ipc_tx,
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+const ERROR_INVALID_HANDLE: i32 = 6;
+const ERROR_NOT_ENOUGH_MEMORY: i32 = 8;
+const ERROR_NO_SYSTEM_RESOURCES: i32 = 1450;
+
+/// Injects packets in batches, which saves system calls.
+///
+/// Packets are queued until the batch is full, the oldest packet has waited for
+/// `MAX_INJECT_DELAY`, or [`Injector::flush`] is called.
+struct Injector {
+    handle: WinDivert<NetworkLayer>,
+    queue: Vec<WinDivertPacket<'static, NetworkLayer>>,
+    queued_since: Instant,
+}
+
+impl Injector {
+    fn new(handle: WinDivert<NetworkLayer>) -> Self {
+        Self {
+            handle,
+            queue: Vec::with_capacity(MAX_INJECT_BATCH),
+            queued_since: Instant::now(),
+        }
+    }
+
+    async fn send(&mut self, packet: WinDivertPacket<'static, NetworkLayer>) -> Result<()> {
+        if self.queue.is_empty() {
+            self.queued_since = Instant::now();
+        }
+        self.queue.push(packet);
+        if self.queue.len() >= MAX_INJECT_BATCH || self.queued_since.elapsed() >= MAX_INJECT_DELAY {
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<()> {
+        match self.queue.len() {
+            0 => {}
+            1 => inject(&self.handle, &self.queue[0]).await?,
+            _ => match self.handle.send_ex(&self.queue) {
+                Ok(_) => {
+                    STATS
+                        .packets_injected
+                        .fetch_add(self.queue.len() as u64, Ordering::Relaxed);
+                }
+                Err(e) => {
+                    // Send packets one by one, so that the error handling of `inject` applies.
+                    debug!("Failed to inject batch, retrying one by one: {}", e);
+                    for packet in &self.queue {
+                        inject(&self.handle, packet).await?;
+                    }
+                }
+            },
+        }
+        self.queue.clear();
+        Ok(())
+    }
+}
+
+/// Inject a packet.
+///
+/// If the system is temporarily out of resources (e.g. because the queue is full), we retry with
+/// a short backoff. Other errors only affect this packet, so we log them and drop the packet.
+/// Only errors that leave the handle unusable are returned.
+async fn inject(
+    inject_handle: &WinDivert<NetworkLayer>,
+    packet: &WinDivertPacket<'_, NetworkLayer>,
+) -> Result<()> {
+    let mut backoff = ipc::Backoff::new(INJECT_RETRY_DELAY, INJECT_RETRY_MAX_DELAY, INJECT_RETRIES);
+    loop {
+        let Err(e) = inject_handle.send(packet) else {
+            STATS.packets_injected.fetch_add(1, Ordering::Relaxed);
+            return Ok(());
+        };
+        match os_error_code(&e) {
+            Some(ERROR_INVALID_HANDLE) => return Err(e).context("cannot inject packets"),
+            Some(ERROR_NOT_ENOUGH_MEMORY | ERROR_NO_SYSTEM_RESOURCES) => {
+                if let Some(delay) = backoff.next() {
+                    debug!("Failed to inject packet, retrying in {:?}: {}", delay, e);
+                    tokio::time::sleep(delay).await;
+                    continue;
+                }
+            }
+            _ => {}
+        }
+        warn!("Dropping packet that could not be injected: {}", e);
+        STATS.injection_errors.fetch_add(1, Ordering::Relaxed);
+        STATS.packets_dropped.fetch_add(1, Ordering::Relaxed);
+        return Ok(());
+    }
+}
+
+/// Find the operating system error code of an error, if there is one.
+fn os_error_code(e: &(dyn std::error::Error + 'static)) -> Option<i32> {
+    let mut source = Some(e);
+    while let Some(e) = source {
+        if let Some(e) = e.downcast_ref::<std::io::Error>() {
+            return e.raw_os_error();
+        }
+        source = e.source();
+    }
+    None
+}
+
+/// In dry-run mode, log the action we would take for a new connection and pass it through.
+fn dry_run_action(
+    action: ConnectionAction,
+    connection_id: &ConnectionId,
+    dry_run: bool,
+) -> ConnectionAction {
+    if !dry_run {
+        return action;
+    }
+    match action {
+        ConnectionAction::Intercept(info) => info!(
+            %connection_id,
+            pid = info.pid,
+            process_name = ?info.process_name,
+            "Dry run: would intercept."
+        ),
+        ConnectionAction::Redirect {
+            process_info,
+            proxy_port,
+            ..
+        } => info!(
+            %connection_id,
+            pid = process_info.pid,
+            process_name = ?process_info.process_name,
+            proxy_port,
+            "Dry run: would redirect."
+        ),
+        ConnectionAction::None | ConnectionAction::RestoreSource(_) => {
+            info!(%connection_id, "Dry run: would pass through.")
+        }
+    }
+    ConnectionAction::None
+}
+
+/// Tear down a TCP connection by injecting RST packets in both directions, and forget it.
+async fn reset_connection(
+    request: ipc::ResetConnection,
+    connections: &mut LruCache<CanonicalConnectionId, ConnectionState>,
+    link_local_scopes: &mut LinkLocalScopes,
+    injector: &mut Injector,
+) -> Result<()> {
+    let src = request.src.as_ref().context("missing src address")?;
+    let dst = request.dst.as_ref().context("missing dst address")?;
+    let connection_id = link_local_scopes.apply(ConnectionId {
+        proto: TransportProtocol::Tcp,
+        src: SocketAddr::try_from(src)?,
+        dst: SocketAddr::try_from(dst)?,
+    });
+    info!(%connection_id, "Resetting connection.");
+    // Each RST needs to be injected towards its destination, on the interface the connection
+    // uses. Without having seen the connection's packets, we can only inject outbound.
+    let origin = match connections.remove(&connection_id.canonical().0) {
+        Some(ConnectionState::Known(_, action_direction, observed)) => {
+            observed.origin.map(|origin| (action_direction, origin))
+        }
+        _ => None,
+    };
+    let [forward, reverse] = packet::tcp_reset(connection_id, request.seq, request.ack)?;
+    for (id, data) in [(connection_id, forward), (connection_id.reverse(), reverse)] {
+        let mut address = unsafe { WinDivertAddress::<NetworkLayer>::new() };
+        address.set_outbound(true);
+        match origin {
+            Some((action_direction, origin)) if id.canonical().1 == action_direction => {
+                origin.apply(&mut address)
+            }
+            Some((_, origin)) => origin.reply(&mut address),
+            None => {}
+        }
+        address.set_ip_checksum(true);
+        address.set_tcp_checksum(true);
+        injector
+            .send(WinDivertPacket {
+                address,
+                data: data.into(),
+            })
+            .await?;
+    }
+    // Resets are rare, so there is no point in batching them with later packets.
+    injector.flush().await?;
+    Ok(())
+}
+
+/// Whether a connection belongs to mitmproxy or one of its child processes.
+fn is_controller(ctx: &ConnectionContext, controller_pid: Option<u32>) -> bool {
+    controller_pid.is_some_and(|pid| ctx.process_info.pid == pid || ctx.ancestors.contains(&pid))
+}
 
-    if let Some(ConnectionState::Unknown(packets)) = existing1 {
-        for (a, p) in packets {
-            process_packet(a, p, &ConnectionAction::None, inject_handle, ipc_tx).await?;
+/// Look up where a connection we redirected to the proxy was originally going.
+///
+/// The proxy's side of a redirected connection is stored as a connection of its own,
+/// see [`insert_into_connections`].
+fn lookup_original_dst(
+    request: &ipc::OriginalDstRequest,
+    connections: &LruCache<CanonicalConnectionId, ConnectionState>,
+) -> Result<Option<SocketAddr>> {
+    let src = request.src.as_ref().context("missing src address")?;
+    let dst = request.dst.as_ref().context("missing dst address")?;
+    let (key, _) = ConnectionId {
+        proto: TransportProtocol::Tcp,
+        src: SocketAddr::try_from(src)?,
+        dst: SocketAddr::try_from(dst)?,
+    }
+    .canonical();
+    match connections.peek(&key) {
+        Some(ConnectionState::Known(ConnectionAction::RestoreSource(original_dst), ..)) => {
+            Ok(Some(*original_dst))
         }
+        _ => Ok(None),
+    }
+}
+
+/// Insert a connection into the cache.
+///
+/// Connections that are dropped to make room (or because they have expired) may still
+/// have packets waiting for a socket event, which are re-injected instead of being lost.
+async fn insert_connection(
+    connections: &mut LruCache<CanonicalConnectionId, ConnectionState>,
+    key: CanonicalConnectionId,
+    state: ConnectionState,
+    injector: &mut Injector,
+    ipc_tx: &mut UnboundedSender<ipc::PacketWithMeta>,
+) -> Result<Option<ConnectionState>> {
+    if let ConnectionState::Known(action, direction, _) = &state {
+        monitor(&key, *direction, "open", action);
     }
-    if let Some(ConnectionState::Unknown(packets)) = existing2 {
-        for (a, p) in packets {
-            process_packet(a, p, action, inject_handle, ipc_tx).await?;
+    let (existing, evicted) = connections.notify_insert(key, state);
+    for (key, state) in evicted {
+        debug!(connection = %key, "Evicting.");
+        match state {
+            ConnectionState::Known(action, direction, _) => {
+                monitor(&key, direction, "close", &action);
+            }
+            ConnectionState::Unknown(packets) => {
+                for (address, packet, fragments) in packets {
+                    process_packet(
+                        address,
+                        packet,
+                        fragments,
+                        &ConnectionAction::None,
+                        injector,
+                        ipc_tx,
+                    )
+                    .await?;
+                }
+            }
         }
     }
+    Ok(existing)
+}
+
+/// Re-inject a packet unmodified, or the fragments it was reassembled from.
+async fn pass_through(
+    address: WinDivertAddress<NetworkLayer>,
+    data: Vec<u8>,
+    fragments: Fragments,
+    injector: &mut Injector,
+) -> Result<()> {
+    if fragments.is_empty() {
+        return injector
+            .send(WinDivertPacket {
+                address,
+                data: data.into(),
+            })
+            .await;
+    }
+    for fragment in fragments {
+        injector.send(fragment).await?;
+    }
     Ok(())
 }
 
+#[instrument(level = "debug", skip_all, fields(connection_id = %packet.connection_id()))]
 async fn process_packet(
-    address: WinDivertAddress<NetworkLayer>,
+    mut address: WinDivertAddress<NetworkLayer>,
     mut packet: InternetPacket,
+    fragments: Fragments,
     action: &ConnectionAction,
-    inject_handle: &WinDivert<NetworkLayer>,
+    injector: &mut Injector,
     ipc_tx: &mut UnboundedSender<ipc::PacketWithMeta>,
 ) -> Result<()> {
     match action {
         ConnectionAction::None => {
             debug!(
-                "Forwarding: {} {} outbound={} loopback={}",
-                packet.connection_id(),
-                packet.tcp_flag_str(),
-                address.outbound(),
-                address.loopback()
+                flags = %packet.tcp_flag_str(),
+                outbound = address.outbound(),
+                loopback = address.loopback(),
+                "Forwarding."
             );
-            inject_handle
-                .send(&WinDivertPacket::<NetworkLayer> {
-                    address,
-                    data: packet.inner().into(),
-                })
+            STATS.record_latency(address.timestamp());
+            pass_through(address, packet.inner(), fragments, injector)
+                .await
                 .context("failed to re-inject packet")?;
         }
         ConnectionAction::Intercept(ProcessInfo { pid, process_name }) => {
             info!(
-                "Intercepting: {} {} outbound={} loopback={}",
-                packet.connection_id(),
-                packet.tcp_flag_str(),
-                address.outbound(),
-                address.loopback()
+                pid,
+                ?process_name,
+                flags = %packet.tcp_flag_str(),
+                outbound = address.outbound(),
+                loopback = address.loopback(),
+                "Intercepting."
             );
 
             if !address.ip_checksum() {
@@ -550,6 +3211,8 @@ async fn process_packet(
                 packet.recalculate_udp_checksum();
             }
 
+            STATS.packets_intercepted.fetch_add(1, Ordering::Relaxed);
+            STATS.record_latency(address.timestamp());
             ipc_tx.send(ipc::PacketWithMeta {
                 data: packet.inner().into(),
                 tunnel_info: Some(ipc::TunnelInfo {
@@ -558,6 +3221,228 @@ async fn process_packet(
                 }),
             })?;
         }
+        ConnectionAction::Redirect {
+            process_info,
+            original_dst,
+            proxy_port,
+        } => {
+            debug!(
+                pid = process_info.pid,
+                %original_dst,
+                proxy_port,
+                flags = %packet.tcp_flag_str(),
+                "Redirecting."
+            );
+            let mut data = packet.inner();
+            if let Err(e) = redirect_packet(&mut address, &mut data, *proxy_port) {
+                warn!("Cannot redirect packet, dropping it: {:?}", e);
+                STATS.packets_dropped.fetch_add(1, Ordering::Relaxed);
+                return Ok(());
+            }
+            STATS.packets_intercepted.fetch_add(1, Ordering::Relaxed);
+            STATS.record_latency(address.timestamp());
+            injector
+                .send(WinDivertPacket {
+                    address,
+                    data: data.into(),
+                })
+                .await
+                .context("failed to re-inject packet")?;
+        }
+        ConnectionAction::RestoreSource(original_dst) => {
+            debug!(
+                %original_dst,
+                flags = %packet.tcp_flag_str(),
+                "Restoring original destination."
+            );
+            let mut data = packet.inner();
+            if let Err(e) = restore_packet(&mut address, &mut data, *original_dst) {
+                warn!("Cannot restore packet source, dropping it: {:?}", e);
+                STATS.packets_dropped.fetch_add(1, Ordering::Relaxed);
+                return Ok(());
+            }
+            STATS.record_latency(address.timestamp());
+            injector
+                .send(WinDivertPacket {
+                    address,
+                    data: data.into(),
+                })
+                .await
+                .context("failed to re-inject packet")?;
+        }
     }
     Ok(())
 }
+
+/// Whether an intercepted connection can be redirected to the proxy on `proxy_port`.
+///
+/// Only outgoing TCP connections are, and never those to the proxy itself.
+fn can_redirect(connection_id: &ConnectionId, event: WinDivertEvent, proxy_port: u16) -> bool {
+    let to_self = packet::is_loopback(connection_id.dst.ip())
+        || connection_id.dst.ip() == connection_id.src.ip();
+    connection_id.proto == TransportProtocol::Tcp
+        && matches!(event, WinDivertEvent::SocketConnect)
+        && !(to_self && connection_id.dst.port() == proxy_port)
+}
+
+/// Reflect a packet of a redirected connection to the proxy, like WinDivert's streamdump
+/// example does. Rewriting only the destination would send a packet with a loopback
+/// destination out on the wire, where it is dropped. Instead, the packet is turned around and
+/// injected inbound, so that the proxy sees a connection from the original destination's
+/// address to its own port, see [`packet::reflect_to`].
+fn redirect_packet(
+    address: &mut WinDivertAddress<NetworkLayer>,
+    data: &mut [u8],
+    proxy_port: u16,
+) -> Result<()> {
+    packet::reflect_to(data, proxy_port)?;
+    address.set_outbound(false);
+    address.set_ip_checksum(true);
+    address.set_tcp_checksum(true);
+    address.set_udp_checksum(true);
+    Ok(())
+}
+
+/// Reflect a reply of the proxy back to the client, the reverse of [`redirect_packet`].
+fn restore_packet(
+    address: &mut WinDivertAddress<NetworkLayer>,
+    data: &mut [u8],
+    original_dst: SocketAddr,
+) -> Result<()> {
+    packet::reflect_from(data, original_dst.port())?;
+    address.set_outbound(false);
+    address.set_ip_checksum(true);
+    address.set_tcp_checksum(true);
+    address.set_udp_checksum(true);
+    Ok(())
+}
+
+/// The connection between the proxy and the reflected packets of a redirected connection,
+/// as the proxy sends its replies.
+fn proxy_connection(connection_id: &ConnectionId, proxy_port: u16) -> ConnectionId {
+    ConnectionId {
+        proto: connection_id.proto,
+        src: SocketAddr::new(connection_id.src.ip(), proxy_port),
+        dst: SocketAddr::new(connection_id.dst.ip(), connection_id.src.port()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mitmproxy::packet::{InternetPacketBuilder, TCP_ACK, TCP_SYN};
+
+    fn outbound() -> WinDivertAddress<NetworkLayer> {
+        let mut address = unsafe { WinDivertAddress::<NetworkLayer>::new() };
+        address.set_outbound(true);
+        address
+    }
+
+    #[test]
+    fn redirect_round_trip() {
+        let client: SocketAddr = "192.168.1.10:50000".parse().unwrap();
+        let original_dst: SocketAddr = "93.184.216.34:443".parse().unwrap();
+        let connection_id = ConnectionId {
+            proto: TransportProtocol::Tcp,
+            src: client,
+            dst: original_dst,
+        };
+        assert!(can_redirect(
+            &connection_id,
+            WinDivertEvent::SocketConnect,
+            8080
+        ));
+
+        // The client's SYN arrives at the proxy, coming from the original destination's address.
+        let syn = InternetPacketBuilder::new(client, original_dst, TransportProtocol::Tcp)
+            .tcp_flags(TCP_SYN)
+            .seq(1)
+            .build()
+            .unwrap();
+        let mut address = outbound();
+        let mut data = syn.inner();
+        redirect_packet(&mut address, &mut data, 8080).unwrap();
+        assert!(!address.outbound());
+        assert!(packet::validate_checksums(&data));
+        assert_eq!(packet::tcp_seq(&data).unwrap(), 1);
+        let reflected = InternetPacket::try_from(data).unwrap();
+        assert_eq!(reflected.src(), "93.184.216.34:50000".parse().unwrap());
+        assert_eq!(reflected.dst(), "192.168.1.10:8080".parse().unwrap());
+
+        // The proxy's SYN-ACK is what we store the reply entry for.
+        let reply_id = proxy_connection(&connection_id, 8080);
+        assert_eq!(reply_id, reflected.connection_id().reverse());
+        let syn_ack =
+            InternetPacketBuilder::new(reply_id.src, reply_id.dst, TransportProtocol::Tcp)
+                .tcp_flags(TCP_SYN | TCP_ACK)
+                .seq(100)
+                .ack(2)
+                .build()
+                .unwrap();
+        let mut address = outbound();
+        let mut data = syn_ack.inner();
+        restore_packet(&mut address, &mut data, original_dst).unwrap();
+        assert!(!address.outbound());
+        assert!(packet::validate_checksums(&data));
+        let restored = InternetPacket::try_from(data).unwrap();
+        assert_eq!(restored.connection_id(), connection_id.reverse());
+    }
+
+    #[test]
+    fn redirect_not_to_proxy() {
+        let redirectable =
+            |id: &ConnectionId| can_redirect(id, WinDivertEvent::SocketConnect, 8080);
+        let to_proxy = ConnectionId {
+            proto: TransportProtocol::Tcp,
+            src: "127.0.0.1:50000".parse().unwrap(),
+            dst: "127.0.0.1:8080".parse().unwrap(),
+        };
+        assert!(!redirectable(&to_proxy));
+        let to_own_address = ConnectionId {
+            src: "192.168.1.10:50000".parse().unwrap(),
+            dst: "192.168.1.10:8080".parse().unwrap(),
+            ..to_proxy
+        };
+        assert!(!redirectable(&to_own_address));
+        let remote = ConnectionId {
+            dst: "192.168.1.20:8080".parse().unwrap(),
+            ..to_own_address
+        };
+        assert!(redirectable(&remote));
+        assert!(!can_redirect(&remote, WinDivertEvent::SocketAccept, 8080));
+        let udp = ConnectionId {
+            proto: TransportProtocol::Udp,
+            ..remote
+        };
+        assert!(!redirectable(&udp));
+    }
+
+    /// The main loop relies on lookups refreshing the expiry, so that connections with regular
+    /// traffic are never evicted. The margins are wide so that a loaded machine does not fail
+    /// the test.
+    #[test]
+    fn expiry_refreshed_on_access() {
+        let (key, direction) = ConnectionId {
+            proto: TransportProtocol::Tcp,
+            src: "10.0.0.1:50000".parse().unwrap(),
+            dst: "93.184.216.34:80".parse().unwrap(),
+        }
+        .canonical();
+        let mut connections =
+            LruCache::<CanonicalConnectionId, ConnectionState>::with_expiry_duration_and_capacity(
+                Duration::from_millis(100),
+                16,
+            );
+        connections.insert(
+            key,
+            ConnectionState::Known(ConnectionAction::None, direction, Observed::default()),
+        );
+        // Active for three times the expiry.
+        for _ in 0..15 {
+            std::thread::sleep(Duration::from_millis(20));
+            assert!(connections.get_mut(&key).is_some());
+        }
+        std::thread::sleep(Duration::from_millis(200));
+        assert!(connections.get_mut(&key).is_none());
+    }
+}
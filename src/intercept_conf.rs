@@ -1,4 +1,8 @@
-use anyhow::{anyhow, ensure};
+use std::net::{IpAddr, SocketAddr};
+
+use anyhow::{anyhow, ensure, Context};
+use ipnet::IpNet;
+use regex::{Regex, RegexBuilder};
 
 pub type PID = u32;
 
@@ -8,6 +12,17 @@ pub struct ProcessInfo {
     pub process_name: Option<String>,
 }
 
+/// The connection an interception decision is made for.
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectionContext<'a> {
+    pub process_info: &'a ProcessInfo,
+    /// The parent of the process, its parent, and so on.
+    /// Only needs to be filled in if [`InterceptConf::needs_ancestors`] says so.
+    pub ancestors: &'a [PID],
+    pub local_addr: SocketAddr,
+    pub remote_addr: SocketAddr,
+}
+
 #[derive(PartialEq, Eq, Debug, Clone)]
 pub struct InterceptConf {
     default: bool,
@@ -23,19 +38,117 @@ enum Action {
 #[derive(PartialEq, Eq, Debug, Clone)]
 enum Pattern {
     Pid(PID),
+    /// A process and all its descendants, written as `1234+` in intercept specs.
+    ProcessTree(PID),
     Process(String),
+    ProcessRegex(ProcessRegex),
+    /// An IP network the remote address of the connection belongs to, e.g. `10.0.0.0/8`.
+    Destination(IpNet),
+    /// The remote port of the connection, written as `:443` in intercept specs.
+    Port(u16),
+    /// The local port, e.g. the listening port of a server.
+    LocalPort(u16),
+}
+
+/// A compiled regular expression for process names, written as `/regex/` in intercept specs.
+#[derive(Debug, Clone)]
+struct ProcessRegex(Regex);
+
+impl PartialEq for ProcessRegex {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+
+impl Eq for ProcessRegex {}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+enum PatternKind {
+    Process,
+    Destination,
+    Port,
+    LocalPort,
+}
+
+impl std::fmt::Display for PatternKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            PatternKind::Process => "process",
+            PatternKind::Destination => "destination",
+            PatternKind::Port => "port",
+            PatternKind::LocalPort => "local port",
+        })
+    }
+}
+
+/// The outcome of [`InterceptConf::decide`], including the rules that led to it.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum Decision {
+    /// The connection matched this exclude rule.
+    Excluded(String),
+    /// The connection matched these include rules, one for each kind of rule.
+    Included(Vec<String>),
+    /// There are include rules of this kind, but none of them matched.
+    NotIncluded(String),
+    /// There are no include rules, so the connection gets the default.
+    Default(bool),
+}
+
+impl Decision {
+    pub fn intercept(&self) -> bool {
+        match self {
+            Decision::Included(_) => true,
+            Decision::Excluded(_) | Decision::NotIncluded(_) => false,
+            Decision::Default(intercept) => *intercept,
+        }
+    }
+}
+
+impl std::fmt::Display for Decision {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Decision::Excluded(rule) => write!(f, "excluded by {}", rule),
+            Decision::Included(rules) => write!(f, "included by {}", rules.join(", ")),
+            Decision::NotIncluded(kind) => write!(f, "no {} rule matched", kind),
+            Decision::Default(true) => write!(f, "not excluded"),
+            Decision::Default(false) => write!(f, "no rules"),
+        }
+    }
 }
 
 impl Pattern {
+    fn kind(&self) -> PatternKind {
+        match self {
+            Pattern::Pid(_)
+            | Pattern::ProcessTree(_)
+            | Pattern::Process(_)
+            | Pattern::ProcessRegex(_) => PatternKind::Process,
+            Pattern::Destination(_) => PatternKind::Destination,
+            Pattern::Port(_) => PatternKind::Port,
+            Pattern::LocalPort(_) => PatternKind::LocalPort,
+        }
+    }
+
     #[inline(always)]
-    fn matches(&self, process_info: &ProcessInfo) -> bool {
+    fn matches(&self, ctx: &ConnectionContext) -> bool {
+        let process_info = ctx.process_info;
         match self {
             Pattern::Pid(pid) => process_info.pid == *pid,
+            Pattern::ProcessTree(pid) => process_info.pid == *pid || ctx.ancestors.contains(pid),
+            // Executable names are case-insensitive on Windows and macOS.
             Pattern::Process(name) => process_info
                 .process_name
                 .as_ref()
-                .map(|n| n.contains(name))
+                .map(|n| n.to_lowercase().contains(&name.to_lowercase()))
                 .unwrap_or(false),
+            Pattern::ProcessRegex(ProcessRegex(regex)) => process_info
+                .process_name
+                .as_ref()
+                .map(|n| regex.is_match(n))
+                .unwrap_or(false),
+            Pattern::Destination(net) => net.contains(&ctx.remote_addr.ip()),
+            Pattern::Port(port) => ctx.remote_addr.port() == *port,
+            Pattern::LocalPort(port) => ctx.local_addr.port() == *port,
         }
     }
 }
@@ -82,6 +195,35 @@ impl TryFrom<&str> for Pattern {
     fn try_from(value: &str) -> Result<Self, Self::Error> {
         let value = value.trim();
         ensure!(!value.is_empty(), "pattern must not be empty");
+        if let Some(regex) = value.strip_prefix('/').and_then(|v| v.strip_suffix('/')) {
+            let regex = RegexBuilder::new(regex)
+                .case_insensitive(true)
+                .build()
+                .with_context(|| format!("invalid process regex: {}", value))?;
+            return Ok(Pattern::ProcessRegex(ProcessRegex(regex)));
+        }
+        if let Some(port) = value.strip_prefix("local:") {
+            let port = port
+                .parse::<u16>()
+                .with_context(|| format!("invalid local port: {}", value))?;
+            return Ok(Pattern::LocalPort(port));
+        }
+        // Before ports, as IPv6 addresses such as `::1` start with a colon as well.
+        if let Ok(net) = value.parse::<IpNet>() {
+            return Ok(Pattern::Destination(net));
+        }
+        if let Ok(ip) = value.parse::<IpAddr>() {
+            return Ok(Pattern::Destination(IpNet::from(ip)));
+        }
+        if let Some(port) = value.strip_prefix(':') {
+            let port = port
+                .parse::<u16>()
+                .with_context(|| format!("invalid port: {}", value))?;
+            return Ok(Pattern::Port(port));
+        }
+        if let Some(Ok(pid)) = value.strip_suffix('+').map(str::parse::<PID>) {
+            return Ok(Pattern::ProcessTree(pid));
+        }
         Ok(match value.parse::<PID>() {
             Ok(pid) => Pattern::Pid(pid),
             Err(_) => Pattern::Process(value.to_string()),
@@ -102,14 +244,21 @@ impl std::fmt::Display for Pattern {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Pattern::Pid(pid) => write!(f, "{}", pid),
+            Pattern::ProcessTree(pid) => write!(f, "{}+", pid),
             Pattern::Process(name) => write!(f, "{}", name),
+            Pattern::ProcessRegex(ProcessRegex(regex)) => write!(f, "/{}/", regex.as_str()),
+            Pattern::Destination(net) => write!(f, "{}", net),
+            Pattern::Port(port) => write!(f, ":{}", port),
+            Pattern::LocalPort(port) => write!(f, "local:{}", port),
         }
     }
 }
 
 impl InterceptConf {
     fn new(actions: Vec<Action>) -> Self {
-        let default = matches!(actions.first(), Some(Action::Exclude(_)));
+        // Without include rules, everything that is not excluded is intercepted.
+        let default =
+            !actions.is_empty() && actions.iter().all(|a| matches!(a, Action::Exclude(_)));
         Self { default, actions }
     }
 
@@ -125,19 +274,102 @@ impl InterceptConf {
         self.default
     }
 
-    pub fn should_intercept(&self, process_info: &ProcessInfo) -> bool {
-        let mut intercept = self.default;
-        for action in &self.actions {
-            match action {
-                Action::Include(pattern) => {
-                    intercept = intercept || pattern.matches(process_info);
-                }
-                Action::Exclude(pattern) => {
-                    intercept = intercept && !pattern.matches(process_info);
-                }
+    /// Add an include rule for a single process, unless there already is one.
+    pub fn add_intercept_pid(&mut self, pid: PID) {
+        let action = Action::Include(Pattern::Pid(pid));
+        if !self.actions.contains(&action) {
+            let mut actions = std::mem::take(&mut self.actions);
+            actions.push(action);
+            *self = Self::new(actions);
+        }
+    }
+
+    /// Remove the include rule for a single process, if there is one.
+    pub fn remove_intercept_pid(&mut self, pid: PID) {
+        let action = Action::Include(Pattern::Pid(pid));
+        let mut actions = std::mem::take(&mut self.actions);
+        actions.retain(|a| *a != action);
+        *self = Self::new(actions);
+    }
+
+    /// Whether any rule matches on a process' ancestors, see [`ConnectionContext::ancestors`].
+    pub fn needs_ancestors(&self) -> bool {
+        self.actions.iter().any(|a| match a {
+            Action::Include(pattern) | Action::Exclude(pattern) => {
+                matches!(pattern, Pattern::ProcessTree(_))
+            }
+        })
+    }
+
+    /// Fail if there are rules other than PIDs and process names.
+    ///
+    /// The macOS and Linux redirectors only understand these two, and they still evaluate rules
+    /// in order rather than as described in [`InterceptConf::should_intercept`].
+    pub fn ensure_process_rules_only(&self) -> anyhow::Result<()> {
+        let unsupported = self.actions.iter().find(|a| match a {
+            Action::Include(pattern) | Action::Exclude(pattern) => {
+                !matches!(pattern, Pattern::Pid(_) | Pattern::Process(_))
+            }
+        });
+        match unsupported {
+            Some(action) => Err(anyhow!(
+                "intercept rule is only supported on Windows: {}",
+                action
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Whether there is an include rule for this port, e.g. `:5353`.
+    pub fn includes_port(&self, port: u16) -> bool {
+        self.includes().any(|p| *p == Pattern::Port(port))
+    }
+
+    fn includes(&self) -> impl Iterator<Item = &Pattern> {
+        self.actions.iter().filter_map(|a| match a {
+            Action::Include(pattern) => Some(pattern),
+            Action::Exclude(_) => None,
+        })
+    }
+
+    /// Decide whether a connection should be intercepted.
+    ///
+    /// Exclude rules take precedence: a connection matching any of them is never intercepted.
+    /// Otherwise, for each kind of rule (process, destination, port, local port) that has include
+    /// rules, the connection must match at least one of them.
+    pub fn should_intercept(&self, ctx: &ConnectionContext) -> bool {
+        self.decide(ctx).intercept()
+    }
+
+    /// Like [`InterceptConf::should_intercept`], but also report which rules decided.
+    pub fn decide(&self, ctx: &ConnectionContext) -> Decision {
+        let excluded = self.actions.iter().find_map(|a| match a {
+            Action::Exclude(pattern) if pattern.matches(ctx) => Some(a),
+            _ => None,
+        });
+        if let Some(action) = excluded {
+            return Decision::Excluded(action.to_string());
+        }
+        if self.includes().next().is_none() {
+            return Decision::Default(self.default);
+        }
+        let mut matched = Vec::new();
+        for kind in [
+            PatternKind::Process,
+            PatternKind::Destination,
+            PatternKind::Port,
+            PatternKind::LocalPort,
+        ] {
+            let mut includes = self.includes().filter(|p| p.kind() == kind).peekable();
+            if includes.peek().is_none() {
+                continue;
+            }
+            match includes.find(|p| p.matches(ctx)) {
+                Some(pattern) => matched.push(pattern.to_string()),
+                None => return Decision::NotIncluded(kind.to_string()),
             }
         }
-        intercept
+        Decision::Included(matched)
     }
 
     pub fn description(&self) -> String {
@@ -153,9 +385,35 @@ impl InterceptConf {
                     format!("Include processes matching \"{}\".", name)
                 }
                 Action::Exclude(Pattern::Pid(pid)) => format!("Exclude PID {}.", pid),
+                Action::Include(Pattern::ProcessTree(pid)) => {
+                    format!("Include PID {} and its children.", pid)
+                }
+                Action::Exclude(Pattern::ProcessTree(pid)) => {
+                    format!("Exclude PID {} and its children.", pid)
+                }
                 Action::Exclude(Pattern::Process(name)) => {
                     format!("Exclude processes matching \"{}\".", name)
                 }
+                Action::Include(Pattern::ProcessRegex(ProcessRegex(regex))) => {
+                    format!("Include processes matching /{}/.", regex.as_str())
+                }
+                Action::Exclude(Pattern::ProcessRegex(ProcessRegex(regex))) => {
+                    format!("Exclude processes matching /{}/.", regex.as_str())
+                }
+                Action::Include(Pattern::Destination(net)) => {
+                    format!("Include destinations in {}.", net)
+                }
+                Action::Exclude(Pattern::Destination(net)) => {
+                    format!("Exclude destinations in {}.", net)
+                }
+                Action::Include(Pattern::Port(port)) => format!("Include port {}.", port),
+                Action::Exclude(Pattern::Port(port)) => format!("Exclude port {}.", port),
+                Action::Include(Pattern::LocalPort(port)) => {
+                    format!("Include local port {}.", port)
+                }
+                Action::Exclude(Pattern::LocalPort(port)) => {
+                    format!("Exclude local port {}.", port)
+                }
             })
             .collect();
         parts.join(" ")
@@ -166,6 +424,20 @@ impl InterceptConf {
 mod tests {
     use super::*;
 
+    const REMOTE: SocketAddr =
+        SocketAddr::new(IpAddr::V4(std::net::Ipv4Addr::new(93, 184, 216, 34)), 443);
+    const LOCAL: SocketAddr =
+        SocketAddr::new(IpAddr::V4(std::net::Ipv4Addr::new(10, 0, 0, 2)), 50000);
+
+    fn ctx(process_info: &ProcessInfo, remote_addr: SocketAddr) -> ConnectionContext<'_> {
+        ConnectionContext {
+            process_info,
+            ancestors: &[],
+            local_addr: LOCAL,
+            remote_addr,
+        }
+    }
+
     #[test]
     fn test_intercept_conf() {
         let a = ProcessInfo {
@@ -178,22 +450,369 @@ mod tests {
         };
 
         let conf = InterceptConf::try_from("1,2,3").unwrap();
-        assert!(conf.should_intercept(&a));
-        assert!(!conf.should_intercept(&b));
+        assert!(conf.should_intercept(&ctx(&a, REMOTE)));
+        assert!(!conf.should_intercept(&ctx(&b, REMOTE)));
 
         let conf = InterceptConf::try_from("").unwrap();
-        assert!(!conf.should_intercept(&a));
-        assert!(!conf.should_intercept(&b));
+        assert!(!conf.should_intercept(&ctx(&a, REMOTE)));
+        assert!(!conf.should_intercept(&ctx(&b, REMOTE)));
         assert_eq!(conf, InterceptConf::disabled());
 
         let conf = InterceptConf::try_from("!1234").unwrap();
-        assert!(conf.should_intercept(&a));
-        assert!(conf.should_intercept(&b));
+        assert!(conf.should_intercept(&ctx(&a, REMOTE)));
+        assert!(conf.should_intercept(&ctx(&b, REMOTE)));
 
         let conf = InterceptConf::try_from("mitm").unwrap();
-        assert!(!conf.should_intercept(&a));
-        assert!(conf.should_intercept(&b));
+        assert!(!conf.should_intercept(&ctx(&a, REMOTE)));
+        assert!(conf.should_intercept(&ctx(&b, REMOTE)));
 
         assert!(InterceptConf::try_from(",,").is_err());
     }
+
+    #[test]
+    fn test_intercept_process_names() {
+        let firefox = ProcessInfo {
+            pid: 100,
+            process_name: Some(r"C:\Program Files\Mozilla Firefox\firefox.exe".into()),
+        };
+        // Child processes are matched by their own name, not by their parent's.
+        let firefox_child = ProcessInfo {
+            pid: 101,
+            process_name: Some(r"C:\Program Files\Mozilla Firefox\pingsender.exe".into()),
+        };
+        let chrome = ProcessInfo {
+            pid: 200,
+            process_name: Some(r"C:\Program Files\Google\Chrome\Application\CHROME.EXE".into()),
+        };
+        let unknown = ProcessInfo {
+            pid: 300,
+            process_name: None,
+        };
+
+        let conf = InterceptConf::try_from("chrome.exe,Firefox.exe").unwrap();
+        assert!(conf.should_intercept(&ctx(&firefox, REMOTE)));
+        assert!(!conf.should_intercept(&ctx(&firefox_child, REMOTE)));
+        assert!(conf.should_intercept(&ctx(&chrome, REMOTE)));
+        assert!(!conf.should_intercept(&ctx(&unknown, REMOTE)));
+
+        let conf = InterceptConf::try_from("!chrome.exe").unwrap();
+        assert!(conf.should_intercept(&ctx(&firefox, REMOTE)));
+        assert!(conf.should_intercept(&ctx(&firefox_child, REMOTE)));
+        assert!(!conf.should_intercept(&ctx(&chrome, REMOTE)));
+        assert!(conf.should_intercept(&ctx(&unknown, REMOTE)));
+    }
+
+    #[test]
+    fn test_intercept_process_tree() {
+        let browser = ProcessInfo {
+            pid: 100,
+            process_name: Some("browser.exe".into()),
+        };
+        let renderer = ProcessInfo {
+            pid: 102,
+            process_name: Some("renderer.exe".into()),
+        };
+        let other = ProcessInfo {
+            pid: 200,
+            process_name: None,
+        };
+        let tree = |process_info, ancestors| ConnectionContext {
+            process_info,
+            ancestors,
+            local_addr: LOCAL,
+            remote_addr: REMOTE,
+        };
+
+        let conf = InterceptConf::try_from("100+").unwrap();
+        assert!(conf.needs_ancestors());
+        assert!(conf.should_intercept(&tree(&browser, &[4])));
+        assert!(conf.should_intercept(&tree(&renderer, &[101, 100, 4])));
+        assert!(!conf.should_intercept(&tree(&renderer, &[])));
+        assert!(!conf.should_intercept(&tree(&other, &[4])));
+        assert_eq!(conf.actions(), ["100+"]);
+
+        let conf = InterceptConf::try_from("!100+").unwrap();
+        assert!(!conf.should_intercept(&tree(&renderer, &[101, 100])));
+        assert!(conf.should_intercept(&tree(&other, &[4])));
+
+        assert!(!InterceptConf::try_from("100").unwrap().needs_ancestors());
+        // not a PID, so this is a process name
+        assert_eq!(
+            InterceptConf::try_from("c++").unwrap().actions,
+            [Action::Include(Pattern::Process("c++".into()))]
+        );
+    }
+
+    #[test]
+    fn test_intercept_pid_updates() {
+        let a = ProcessInfo {
+            pid: 1,
+            process_name: Some("a".into()),
+        };
+        let b = ProcessInfo {
+            pid: 2,
+            process_name: Some("b".into()),
+        };
+
+        let mut conf = InterceptConf::try_from("1").unwrap();
+        conf.add_intercept_pid(2);
+        conf.add_intercept_pid(2);
+        assert_eq!(conf.actions(), vec!["1", "2"]);
+        assert!(conf.should_intercept(&ctx(&b, REMOTE)));
+
+        conf.remove_intercept_pid(1);
+        assert_eq!(conf, InterceptConf::try_from("2").unwrap());
+        assert!(!conf.should_intercept(&ctx(&a, REMOTE)));
+
+        // Adding an include rule to exclude-only rules changes the default.
+        let mut conf = InterceptConf::try_from("!2").unwrap();
+        assert!(conf.should_intercept(&ctx(&a, REMOTE)));
+        conf.add_intercept_pid(3);
+        assert!(!conf.should_intercept(&ctx(&a, REMOTE)));
+        conf.remove_intercept_pid(3);
+        assert_eq!(conf, InterceptConf::try_from("!2").unwrap());
+        // Exclude rules are left alone.
+        conf.remove_intercept_pid(2);
+        assert_eq!(conf.actions(), vec!["!2"]);
+    }
+
+    #[test]
+    fn test_intercept_process_regex() {
+        let python = |pid, name: &str| ProcessInfo {
+            pid,
+            process_name: Some(name.into()),
+        };
+        let py311 = python(1, r"C:\Python311\python3.11.exe");
+        let py312 = python(2, r"C:\Python312\PYTHON3.12.EXE");
+        let pythonw = python(3, r"C:\Python312\pythonw.exe");
+
+        let conf = InterceptConf::try_from(vec![r"/python3\.\d+\.exe$/"]).unwrap();
+        assert!(conf.should_intercept(&ctx(&py311, REMOTE)));
+        assert!(conf.should_intercept(&ctx(&py312, REMOTE)));
+        assert!(!conf.should_intercept(&ctx(&pythonw, REMOTE)));
+        assert_eq!(conf.actions(), vec![r"/python3\.\d+\.exe$/"]);
+        assert_eq!(
+            InterceptConf::try_from(conf.actions()).unwrap(),
+            conf.clone()
+        );
+
+        let conf = InterceptConf::try_from(vec![r"!/python3\.\d+/"]).unwrap();
+        assert!(!conf.should_intercept(&ctx(&py311, REMOTE)));
+        assert!(conf.should_intercept(&ctx(&pythonw, REMOTE)));
+
+        // A single slash is not a regex.
+        let conf = InterceptConf::try_from("/").unwrap();
+        assert!(conf.should_intercept(&ctx(&python(4, "/usr/bin/python3"), REMOTE)));
+
+        assert!(InterceptConf::try_from(vec!["/(/"]).is_err());
+    }
+
+    #[test]
+    fn test_intercept_destination() {
+        let a = ProcessInfo {
+            pid: 1,
+            process_name: Some("curl".into()),
+        };
+        let b = ProcessInfo {
+            pid: 2,
+            process_name: Some("wget".into()),
+        };
+        let private: SocketAddr = "10.1.2.3:80".parse().unwrap();
+        let private_v6: SocketAddr = "[fd00::1]:80".parse().unwrap();
+
+        let conf = InterceptConf::try_from("10.0.0.0/8,fd00::/8").unwrap();
+        assert!(conf.should_intercept(&ctx(&a, private)));
+        assert!(conf.should_intercept(&ctx(&b, private_v6)));
+        assert!(!conf.should_intercept(&ctx(&a, REMOTE)));
+
+        // Both process and destination must match.
+        let conf = InterceptConf::try_from("curl,10.0.0.0/8").unwrap();
+        assert!(conf.should_intercept(&ctx(&a, private)));
+        assert!(!conf.should_intercept(&ctx(&b, private)));
+        assert!(!conf.should_intercept(&ctx(&a, REMOTE)));
+
+        let conf = InterceptConf::try_from("!10.0.0.0/8").unwrap();
+        assert!(!conf.should_intercept(&ctx(&a, private)));
+        assert!(conf.should_intercept(&ctx(&a, REMOTE)));
+
+        let conf = InterceptConf::try_from("93.184.216.34").unwrap();
+        assert_eq!(conf.actions(), vec!["93.184.216.34/32"]);
+        assert!(conf.should_intercept(&ctx(&a, REMOTE)));
+        assert!(!conf.should_intercept(&ctx(&a, private)));
+
+        // IPv6 addresses starting with a colon are not ports.
+        let loopback_v6: SocketAddr = "[::1]:80".parse().unwrap();
+        let conf = InterceptConf::try_from("::1").unwrap();
+        assert_eq!(conf.actions(), vec!["::1/128"]);
+        assert!(conf.should_intercept(&ctx(&a, loopback_v6)));
+        assert!(!conf.should_intercept(&ctx(&a, private_v6)));
+
+        let conf = InterceptConf::try_from("::/0").unwrap();
+        assert!(conf.should_intercept(&ctx(&a, loopback_v6)));
+        assert!(conf.should_intercept(&ctx(&a, private_v6)));
+        assert!(!conf.should_intercept(&ctx(&a, private)));
+
+        let conf = InterceptConf::try_from("!::1").unwrap();
+        assert!(!conf.should_intercept(&ctx(&a, loopback_v6)));
+        assert!(conf.should_intercept(&ctx(&a, private_v6)));
+    }
+
+    #[test]
+    fn test_intercept_port() {
+        let a = ProcessInfo {
+            pid: 1,
+            process_name: Some("curl".into()),
+        };
+        let http: SocketAddr = "93.184.216.34:80".parse().unwrap();
+        let dns: SocketAddr = "10.0.0.1:53".parse().unwrap();
+        let private_https: SocketAddr = "10.0.0.1:443".parse().unwrap();
+
+        let conf = InterceptConf::try_from(":80,:443").unwrap();
+        assert_eq!(conf.actions(), vec![":80", ":443"]);
+        assert!(conf.should_intercept(&ctx(&a, http)));
+        assert!(conf.should_intercept(&ctx(&a, REMOTE)));
+        assert!(!conf.should_intercept(&ctx(&a, dns)));
+
+        // Ports are combined with process rules...
+        let conf = InterceptConf::try_from("1,:443").unwrap();
+        assert!(conf.should_intercept(&ctx(&a, REMOTE)));
+        assert!(!conf.should_intercept(&ctx(&a, http)));
+        let conf = InterceptConf::try_from("2,:443").unwrap();
+        assert!(!conf.should_intercept(&ctx(&a, REMOTE)));
+
+        // ...and with destinations.
+        let conf = InterceptConf::try_from("10.0.0.0/8,:443").unwrap();
+        assert!(conf.should_intercept(&ctx(&a, private_https)));
+        assert!(!conf.should_intercept(&ctx(&a, dns)));
+        assert!(!conf.should_intercept(&ctx(&a, REMOTE)));
+
+        let conf = InterceptConf::try_from("!:53").unwrap();
+        assert!(!conf.should_intercept(&ctx(&a, dns)));
+        assert!(conf.should_intercept(&ctx(&a, http)));
+
+        let conf = InterceptConf::try_from(":5353,!:53").unwrap();
+        assert!(conf.includes_port(5353));
+        assert!(!conf.includes_port(53));
+        assert!(!conf.includes_port(443));
+
+        assert!(InterceptConf::try_from(":http").is_err());
+        assert!(InterceptConf::try_from(":65536").is_err());
+    }
+
+    #[test]
+    fn test_intercept_local_port() {
+        let server = ProcessInfo {
+            pid: 1,
+            process_name: Some("nginx".into()),
+        };
+        let listener = |local_addr| ConnectionContext {
+            process_info: &server,
+            ancestors: &[],
+            local_addr,
+            remote_addr: REMOTE,
+        };
+        let http: SocketAddr = "10.0.0.2:8080".parse().unwrap();
+
+        let conf = InterceptConf::try_from("local:8080").unwrap();
+        assert_eq!(conf.actions(), vec!["local:8080"]);
+        assert!(conf.should_intercept(&listener(http)));
+        assert!(!conf.should_intercept(&listener(LOCAL)));
+        // not the remote port
+        assert!(!conf.should_intercept(&ctx(&server, http)));
+
+        // Local ports are combined with process rules.
+        let conf = InterceptConf::try_from("nginx,local:8080").unwrap();
+        assert!(conf.should_intercept(&listener(http)));
+        assert!(!conf.should_intercept(&listener(LOCAL)));
+        let conf = InterceptConf::try_from("apache,local:8080").unwrap();
+        assert!(!conf.should_intercept(&listener(http)));
+        assert_eq!(
+            conf.decide(&listener(http)).to_string(),
+            "no process rule matched"
+        );
+
+        let conf = InterceptConf::try_from("nginx,!local:8080").unwrap();
+        assert!(!conf.should_intercept(&listener(http)));
+        assert!(conf.should_intercept(&listener(LOCAL)));
+        assert_eq!(
+            conf.description(),
+            "Include processes matching \"nginx\". Exclude local port 8080."
+        );
+
+        assert!(InterceptConf::try_from("local:http").is_err());
+    }
+
+    #[test]
+    fn test_ensure_process_rules_only() {
+        let conf = InterceptConf::try_from("1234,!curl").unwrap();
+        assert!(conf.ensure_process_rules_only().is_ok());
+
+        for spec in ["1234+", "/curl/", "10.0.0.0/8", "!:443", "local:8080"] {
+            let conf = InterceptConf::try_from(spec).unwrap();
+            assert!(conf.ensure_process_rules_only().is_err(), "{}", spec);
+        }
+    }
+
+    #[test]
+    fn test_decide() {
+        let curl = ProcessInfo {
+            pid: 1,
+            process_name: Some("curl".into()),
+        };
+        let conf = InterceptConf::try_from("curl,wget,:443,!10.0.0.0/8").unwrap();
+        let decision = conf.decide(&ctx(&curl, REMOTE));
+        assert_eq!(
+            decision,
+            Decision::Included(vec!["curl".into(), ":443".into()])
+        );
+        assert_eq!(decision.to_string(), "included by curl, :443");
+
+        let decision = conf.decide(&ctx(&curl, "10.0.0.1:443".parse().unwrap()));
+        assert_eq!(decision, Decision::Excluded("!10.0.0.0/8".into()));
+        assert!(!decision.intercept());
+
+        let decision = conf.decide(&ctx(&curl, "93.184.216.34:80".parse().unwrap()));
+        assert_eq!(decision.to_string(), "no port rule matched");
+        assert!(!decision.intercept());
+
+        let conf = InterceptConf::try_from("!wget").unwrap();
+        assert_eq!(conf.decide(&ctx(&curl, REMOTE)), Decision::Default(true));
+        assert_eq!(
+            InterceptConf::disabled().decide(&ctx(&curl, REMOTE)),
+            Decision::Default(false)
+        );
+    }
+
+    #[test]
+    fn test_intercept_deny_wins() {
+        let curl = ProcessInfo {
+            pid: 1,
+            process_name: Some("curl".into()),
+        };
+        let wget = ProcessInfo {
+            pid: 2,
+            process_name: Some("wget".into()),
+        };
+        let internal: SocketAddr = "10.0.0.1:443".parse().unwrap();
+
+        // The order of rules does not matter, exclusions always win.
+        for spec in ["curl,!1", "!1,curl"] {
+            let conf = InterceptConf::try_from(spec).unwrap();
+            assert!(!conf.should_intercept(&ctx(&curl, REMOTE)));
+            assert!(!conf.should_intercept(&ctx(&wget, REMOTE)));
+        }
+
+        // Include and exclude rules of different kinds compose.
+        let conf = InterceptConf::try_from("curl,wget,!10.0.0.0/8,!:80").unwrap();
+        assert!(!conf.default());
+        assert!(conf.should_intercept(&ctx(&curl, REMOTE)));
+        assert!(conf.should_intercept(&ctx(&wget, REMOTE)));
+        assert!(!conf.should_intercept(&ctx(&curl, internal)));
+        assert!(!conf.should_intercept(&ctx(&wget, "93.184.216.34:80".parse().unwrap())));
+
+        let conf = InterceptConf::try_from("!wget,:443").unwrap();
+        assert!(conf.should_intercept(&ctx(&curl, internal)));
+        assert!(!conf.should_intercept(&ctx(&wget, internal)));
+        assert!(!conf.should_intercept(&ctx(&curl, "10.0.0.1:80".parse().unwrap())));
+    }
 }
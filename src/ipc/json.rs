@@ -0,0 +1,477 @@
+//! The JSON encoding of IPC messages, for tools that cannot speak protobuf.
+//!
+//! Each message is a JSON object on its own line. The `type` field names the variant of the
+//! protobuf `oneof` in snake case (e.g. `"stats_request"`), the other fields are those of the
+//! protobuf message. Packet data is base64-encoded. Missing fields take their protobuf defaults.
+//!
+//! Only the redirector's side is implemented: we encode [`FromRedirector`] and decode
+//! [`FromProxy`].
+
+use anyhow::{Context, Result};
+use data_encoding::BASE64;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+use super::{from_proxy, from_redirector, FromProxy, FromRedirector};
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum FromRedirectorJson<'a> {
+    Packet(PacketWithMetaJson<'a>),
+    Hello {
+        version: u32,
+        lz4: bool,
+        json: bool,
+        pid: Option<u32>,
+        connection_events: bool,
+    },
+    PacketBatch {
+        packets: Vec<PacketWithMetaJson<'a>>,
+    },
+    Ping,
+    Error {
+        code: i32,
+        message: &'a str,
+    },
+    StatsResponse {
+        packets_received: u64,
+        packets_injected: u64,
+        packets_intercepted: u64,
+        packets_dropped: u64,
+        connections: u64,
+    },
+    ConnectionTableResponse {
+        entries: Vec<ConnectionTableEntryJson<'a>>,
+        total: u32,
+    },
+    ConnectionEvent {
+        connection_id: &'a str,
+        event: &'a str,
+        state: &'a str,
+        tunnel_info: Option<TunnelInfoJson<'a>>,
+    },
+    Warning {
+        message: &'a str,
+    },
+    OriginalDstResponse {
+        src: Option<AddressRefJson<'a>>,
+        dst: Option<AddressRefJson<'a>>,
+        original_dst: Option<AddressRefJson<'a>>,
+    },
+}
+
+#[derive(Serialize)]
+struct PacketWithMetaJson<'a> {
+    #[serde(serialize_with = "base64")]
+    data: &'a [u8],
+    tunnel_info: Option<TunnelInfoJson<'a>>,
+}
+
+#[derive(Serialize)]
+struct TunnelInfoJson<'a> {
+    pid: Option<u32>,
+    process_name: Option<&'a str>,
+}
+
+#[derive(Serialize)]
+struct ConnectionTableEntryJson<'a> {
+    connection_id: &'a str,
+    state: &'a str,
+    tunnel_info: Option<TunnelInfoJson<'a>>,
+    buffered_packets: u32,
+    packets_sent: u64,
+    bytes_sent: u64,
+    packets_received: u64,
+    bytes_received: u64,
+    server_name: &'a str,
+}
+
+#[derive(Serialize)]
+struct AddressRefJson<'a> {
+    host: &'a str,
+    port: u32,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum FromProxyJson {
+    Packet {
+        #[serde(deserialize_with = "from_base64")]
+        data: Vec<u8>,
+    },
+    InterceptConf {
+        #[serde(default)]
+        actions: Vec<String>,
+    },
+    Hello {
+        version: u32,
+        #[serde(default)]
+        lz4: bool,
+        #[serde(default)]
+        json: bool,
+        pid: Option<u32>,
+        #[serde(default)]
+        connection_events: bool,
+    },
+    Pong,
+    Shutdown,
+    StatsRequest {
+        #[serde(default)]
+        reset: bool,
+    },
+    ConnectionTableRequest {
+        #[serde(default)]
+        offset: u32,
+        #[serde(default)]
+        limit: u32,
+    },
+    ResetConnection {
+        src: AddressJson,
+        dst: AddressJson,
+        #[serde(default)]
+        seq: u32,
+        #[serde(default)]
+        ack: u32,
+    },
+    SetDryRun {
+        #[serde(default)]
+        enabled: bool,
+    },
+    AddInterceptPid {
+        pid: u32,
+    },
+    RemoveInterceptPid {
+        pid: u32,
+    },
+    OriginalDstRequest {
+        src: AddressJson,
+        dst: AddressJson,
+    },
+}
+
+#[derive(Deserialize)]
+struct AddressJson {
+    host: String,
+    port: u32,
+}
+
+fn base64<S: Serializer>(data: &&[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&BASE64.encode(data))
+}
+
+fn from_base64<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+    let data = String::deserialize(deserializer)?;
+    BASE64.decode(data.as_bytes()).map_err(de::Error::custom)
+}
+
+impl<'a> From<&'a super::PacketWithMeta> for PacketWithMetaJson<'a> {
+    fn from(packet: &'a super::PacketWithMeta) -> Self {
+        PacketWithMetaJson {
+            data: &packet.data,
+            tunnel_info: packet.tunnel_info.as_ref().map(TunnelInfoJson::from),
+        }
+    }
+}
+
+impl<'a> From<&'a super::TunnelInfo> for TunnelInfoJson<'a> {
+    fn from(info: &'a super::TunnelInfo) -> Self {
+        TunnelInfoJson {
+            pid: info.pid,
+            process_name: info.process_name.as_deref(),
+        }
+    }
+}
+
+impl<'a> From<&'a super::ConnectionTableEntry> for ConnectionTableEntryJson<'a> {
+    fn from(entry: &'a super::ConnectionTableEntry) -> Self {
+        ConnectionTableEntryJson {
+            connection_id: &entry.connection_id,
+            state: &entry.state,
+            tunnel_info: entry.tunnel_info.as_ref().map(TunnelInfoJson::from),
+            buffered_packets: entry.buffered_packets,
+            packets_sent: entry.packets_sent,
+            bytes_sent: entry.bytes_sent,
+            packets_received: entry.packets_received,
+            bytes_received: entry.bytes_received,
+            server_name: &entry.server_name,
+        }
+    }
+}
+
+impl<'a> From<&'a super::Address> for AddressRefJson<'a> {
+    fn from(address: &'a super::Address) -> Self {
+        AddressRefJson {
+            host: &address.host,
+            port: address.port,
+        }
+    }
+}
+
+impl<'a> From<&'a from_redirector::Message> for FromRedirectorJson<'a> {
+    fn from(message: &'a from_redirector::Message) -> Self {
+        use from_redirector::Message;
+        match message {
+            Message::Packet(packet) => FromRedirectorJson::Packet(packet.into()),
+            Message::Hello(hello) => FromRedirectorJson::Hello {
+                version: hello.version,
+                lz4: hello.lz4,
+                json: hello.json,
+                pid: hello.pid,
+                connection_events: hello.connection_events,
+            },
+            Message::PacketBatch(batch) => FromRedirectorJson::PacketBatch {
+                packets: batch.packets.iter().map(PacketWithMetaJson::from).collect(),
+            },
+            Message::Ping(_) => FromRedirectorJson::Ping,
+            Message::Error(error) => FromRedirectorJson::Error {
+                code: error.code,
+                message: &error.message,
+            },
+            Message::StatsResponse(stats) => FromRedirectorJson::StatsResponse {
+                packets_received: stats.packets_received,
+                packets_injected: stats.packets_injected,
+                packets_intercepted: stats.packets_intercepted,
+                packets_dropped: stats.packets_dropped,
+                connections: stats.connections,
+            },
+            Message::ConnectionTableResponse(table) => {
+                FromRedirectorJson::ConnectionTableResponse {
+                    entries: table
+                        .entries
+                        .iter()
+                        .map(ConnectionTableEntryJson::from)
+                        .collect(),
+                    total: table.total,
+                }
+            }
+            Message::ConnectionEvent(event) => FromRedirectorJson::ConnectionEvent {
+                connection_id: &event.connection_id,
+                event: &event.event,
+                state: &event.state,
+                tunnel_info: event.tunnel_info.as_ref().map(TunnelInfoJson::from),
+            },
+            Message::Warning(warning) => FromRedirectorJson::Warning {
+                message: &warning.message,
+            },
+            Message::OriginalDstResponse(response) => FromRedirectorJson::OriginalDstResponse {
+                src: response.src.as_ref().map(AddressRefJson::from),
+                dst: response.dst.as_ref().map(AddressRefJson::from),
+                original_dst: response.original_dst.as_ref().map(AddressRefJson::from),
+            },
+        }
+    }
+}
+
+impl From<FromProxyJson> for from_proxy::Message {
+    fn from(message: FromProxyJson) -> Self {
+        use from_proxy::Message;
+        match message {
+            FromProxyJson::Packet { data } => Message::Packet(super::Packet { data: data.into() }),
+            FromProxyJson::InterceptConf { actions } => {
+                Message::InterceptConf(super::InterceptConf { actions })
+            }
+            FromProxyJson::Hello {
+                version,
+                lz4,
+                json,
+                pid,
+                connection_events,
+            } => Message::Hello(super::Hello {
+                version,
+                lz4,
+                json,
+                pid,
+                connection_events,
+            }),
+            FromProxyJson::Pong => Message::Pong(super::Pong {}),
+            FromProxyJson::Shutdown => Message::Shutdown(super::Shutdown {}),
+            FromProxyJson::StatsRequest { reset } => {
+                Message::StatsRequest(super::StatsRequest { reset })
+            }
+            FromProxyJson::ConnectionTableRequest { offset, limit } => {
+                Message::ConnectionTableRequest(super::ConnectionTableRequest { offset, limit })
+            }
+            FromProxyJson::ResetConnection { src, dst, seq, ack } => {
+                Message::ResetConnection(super::ResetConnection {
+                    src: Some(src.into()),
+                    dst: Some(dst.into()),
+                    seq,
+                    ack,
+                })
+            }
+            FromProxyJson::SetDryRun { enabled } => {
+                Message::SetDryRun(super::SetDryRun { enabled })
+            }
+            FromProxyJson::AddInterceptPid { pid } => {
+                Message::AddInterceptPid(super::AddInterceptPid { pid })
+            }
+            FromProxyJson::RemoveInterceptPid { pid } => {
+                Message::RemoveInterceptPid(super::RemoveInterceptPid { pid })
+            }
+            FromProxyJson::OriginalDstRequest { src, dst } => {
+                Message::OriginalDstRequest(super::OriginalDstRequest {
+                    src: Some(src.into()),
+                    dst: Some(dst.into()),
+                })
+            }
+        }
+    }
+}
+
+impl From<AddressJson> for super::Address {
+    fn from(address: AddressJson) -> Self {
+        super::Address {
+            host: address.host,
+            port: address.port,
+        }
+    }
+}
+
+/// Encode a message as a single line of JSON, without the trailing newline.
+pub fn encode(message: &FromRedirector) -> Vec<u8> {
+    match &message.message {
+        Some(message) => serde_json::to_vec(&FromRedirectorJson::from(message))
+            .expect("IPC messages are always serializable"),
+        None => b"{}".to_vec(),
+    }
+}
+
+/// Decode a single line of JSON.
+pub fn decode(line: &[u8]) -> Result<FromProxy> {
+    let message: FromProxyJson = serde_json::from_slice(line).context("invalid JSON message")?;
+    Ok(FromProxy {
+        message: Some(message.into()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::*;
+    use super::*;
+
+    #[test]
+    fn encode_packet() {
+        let message = FromRedirector {
+            message: Some(from_redirector::Message::Packet(PacketWithMeta {
+                data: Bytes::from_static(b"hello"),
+                tunnel_info: Some(TunnelInfo {
+                    pid: Some(1234),
+                    process_name: None,
+                }),
+            })),
+        };
+        assert_eq!(
+            String::from_utf8(encode(&message)).unwrap(),
+            r#"{"type":"packet","data":"aGVsbG8=","tunnel_info":{"pid":1234,"process_name":null}}"#
+        );
+
+        let ping = FromRedirector {
+            message: Some(from_redirector::Message::Ping(Ping {})),
+        };
+        assert_eq!(encode(&ping), br#"{"type":"ping"}"#);
+
+        let warning = FromRedirector {
+            message: Some(from_redirector::Message::Warning(Warning {
+                message: "careful".to_string(),
+            })),
+        };
+        assert_eq!(
+            encode(&warning),
+            br#"{"type":"warning","message":"careful"}"#
+        );
+
+        let event = FromRedirector {
+            message: Some(from_redirector::Message::ConnectionEvent(ConnectionEvent {
+                connection_id: "TCP 10.0.0.1:50000 -> 10.0.0.2:80".to_string(),
+                event: "open".to_string(),
+                state: "none".to_string(),
+                tunnel_info: None,
+            })),
+        };
+        assert_eq!(
+            String::from_utf8(encode(&event)).unwrap(),
+            r#"{"type":"connection_event","connection_id":"TCP 10.0.0.1:50000 -> 10.0.0.2:80","event":"open","state":"none","tunnel_info":null}"#
+        );
+
+        let original_dst = FromRedirector {
+            message: Some(from_redirector::Message::OriginalDstResponse(
+                OriginalDstResponse {
+                    src: Some("10.0.0.1:50000".parse::<SocketAddr>().unwrap().into()),
+                    dst: Some("127.0.0.1:8080".parse::<SocketAddr>().unwrap().into()),
+                    original_dst: None,
+                },
+            )),
+        };
+        assert_eq!(
+            String::from_utf8(encode(&original_dst)).unwrap(),
+            r#"{"type":"original_dst_response","src":{"host":"10.0.0.1","port":50000},"dst":{"host":"127.0.0.1","port":8080},"original_dst":null}"#
+        );
+    }
+
+    #[test]
+    fn decode_messages() {
+        assert_eq!(
+            decode(br#"{"type":"packet","data":"aGVsbG8="}"#).unwrap(),
+            FromProxy {
+                message: Some(from_proxy::Message::Packet(Packet {
+                    data: Bytes::from_static(b"hello"),
+                })),
+            }
+        );
+        assert_eq!(
+            decode(br#"{"type":"hello","version":6,"pid":1234}"#)
+                .unwrap()
+                .message,
+            Some(from_proxy::Message::Hello(Hello {
+                version: 6,
+                lz4: false,
+                json: false,
+                pid: Some(1234),
+                connection_events: false,
+            }))
+        );
+        assert_eq!(
+            decode(br#"{"type":"stats_request"}"#).unwrap().message,
+            Some(from_proxy::Message::StatsRequest(StatsRequest {
+                reset: false
+            }))
+        );
+        assert_eq!(
+            decode(br#"{"type":"intercept_conf","actions":["curl"]}"#)
+                .unwrap()
+                .message,
+            Some(from_proxy::Message::InterceptConf(InterceptConf {
+                actions: vec!["curl".to_string()],
+            }))
+        );
+        let reset = br#"{"type":"reset_connection","src":{"host":"10.0.0.1","port":1},"dst":{"host":"10.0.0.2","port":2},"seq":5}"#;
+        assert_eq!(
+            decode(reset).unwrap().message,
+            Some(from_proxy::Message::ResetConnection(ResetConnection {
+                src: Some(Address {
+                    host: "10.0.0.1".to_string(),
+                    port: 1,
+                }),
+                dst: Some(Address {
+                    host: "10.0.0.2".to_string(),
+                    port: 2,
+                }),
+                seq: 5,
+                ack: 0,
+            }))
+        );
+        let original_dst = br#"{"type":"original_dst_request","src":{"host":"10.0.0.1","port":50000},"dst":{"host":"127.0.0.1","port":8080}}"#;
+        assert_eq!(
+            decode(original_dst).unwrap().message,
+            Some(from_proxy::Message::OriginalDstRequest(
+                OriginalDstRequest {
+                    src: Some("10.0.0.1:50000".parse::<SocketAddr>().unwrap().into()),
+                    dst: Some("127.0.0.1:8080".parse::<SocketAddr>().unwrap().into()),
+                }
+            ))
+        );
+
+        assert!(decode(b"").is_err());
+        assert!(decode(br#"{"type":"unknown"}"#).is_err());
+        assert!(decode(br#"{"type":"packet","data":"not base64"}"#).is_err());
+    }
+}
@@ -20,10 +20,54 @@ pub struct TunnelInfo {
     #[prost(string, optional, tag = "2")]
     pub process_name: ::core::option::Option<::prost::alloc::string::String>,
 }
-/// Packet or intercept spec (Windows pipe to redirector)
+/// Packet(s) or handshake (Windows pipe to mitmproxy)
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct FromRedirector {
+    #[prost(
+        oneof = "from_redirector::Message",
+        tags = "1, 2, 3, 4, 5, 6, 7, 8, 9, 10"
+    )]
+    pub message: ::core::option::Option<from_redirector::Message>,
+}
+/// Nested message and enum types in `FromRedirector`.
+pub mod from_redirector {
+    #[derive(Clone, PartialEq, ::prost::Oneof)]
+    pub enum Message {
+        #[prost(message, tag = "1")]
+        Packet(super::PacketWithMeta),
+        #[prost(message, tag = "2")]
+        Hello(super::Hello),
+        #[prost(message, tag = "3")]
+        PacketBatch(super::PacketBatch),
+        #[prost(message, tag = "4")]
+        Ping(super::Ping),
+        #[prost(message, tag = "5")]
+        Error(super::Error),
+        #[prost(message, tag = "6")]
+        StatsResponse(super::StatsResponse),
+        #[prost(message, tag = "7")]
+        ConnectionTableResponse(super::ConnectionTableResponse),
+        #[prost(message, tag = "8")]
+        ConnectionEvent(super::ConnectionEvent),
+        #[prost(message, tag = "9")]
+        Warning(super::Warning),
+        #[prost(message, tag = "10")]
+        OriginalDstResponse(super::OriginalDstResponse),
+    }
+}
+/// Multiple packets in a single message (Windows pipe to mitmproxy)
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct PacketBatch {
+    #[prost(message, repeated, tag = "1")]
+    pub packets: ::prost::alloc::vec::Vec<PacketWithMeta>,
+}
+/// Packet, intercept spec or handshake (Windows pipe to redirector)
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct FromProxy {
-    #[prost(oneof = "from_proxy::Message", tags = "1, 2")]
+    #[prost(
+        oneof = "from_proxy::Message",
+        tags = "1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12"
+    )]
     pub message: ::core::option::Option<from_proxy::Message>,
 }
 /// Nested message and enum types in `FromProxy`.
@@ -34,8 +78,205 @@ pub mod from_proxy {
         Packet(super::Packet),
         #[prost(message, tag = "2")]
         InterceptConf(super::InterceptConf),
+        #[prost(message, tag = "3")]
+        Hello(super::Hello),
+        #[prost(message, tag = "4")]
+        Pong(super::Pong),
+        #[prost(message, tag = "5")]
+        Shutdown(super::Shutdown),
+        #[prost(message, tag = "6")]
+        StatsRequest(super::StatsRequest),
+        #[prost(message, tag = "7")]
+        ConnectionTableRequest(super::ConnectionTableRequest),
+        #[prost(message, tag = "8")]
+        ResetConnection(super::ResetConnection),
+        #[prost(message, tag = "9")]
+        SetDryRun(super::SetDryRun),
+        #[prost(message, tag = "10")]
+        AddInterceptPid(super::AddInterceptPid),
+        #[prost(message, tag = "11")]
+        RemoveInterceptPid(super::RemoveInterceptPid),
+        #[prost(message, tag = "12")]
+        OriginalDstRequest(super::OriginalDstRequest),
     }
 }
+/// Protocol version and capabilities, sent as the first message in both directions (Windows pipe)
+#[derive(Clone, Copy, PartialEq, ::prost::Message)]
+pub struct Hello {
+    #[prost(uint32, tag = "1")]
+    pub version: u32,
+    /// If both sides support it, all further frames carry a flag byte and may be LZ4-compressed.
+    #[prost(bool, tag = "2")]
+    pub lz4: bool,
+    /// If both sides support it, all further messages are newline-delimited JSON instead of protobuf.
+    #[prost(bool, tag = "3")]
+    pub json: bool,
+    /// The process id of mitmproxy, so that the redirector can exclude it from interception.
+    /// Only sent by mitmproxy.
+    #[prost(uint32, optional, tag = "4")]
+    pub pid: ::core::option::Option<u32>,
+    /// If set by mitmproxy, the redirector sends a ConnectionEvent whenever it opens or closes a connection.
+    #[prost(bool, tag = "5")]
+    pub connection_events: bool,
+}
+/// Fatal error, sent by the redirector before it exits (Windows pipe)
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Error {
+    #[prost(int32, tag = "1")]
+    pub code: i32,
+    #[prost(string, tag = "2")]
+    pub message: ::prost::alloc::string::String,
+}
+/// Non-fatal problem the user should know about (Windows pipe)
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Warning {
+    #[prost(string, tag = "1")]
+    pub message: ::prost::alloc::string::String,
+}
+/// Keepalive request, sent by the redirector when the channel has been idle (Windows pipe)
+#[derive(Clone, Copy, PartialEq, ::prost::Message)]
+pub struct Ping {}
+/// Keepalive response (Windows pipe)
+#[derive(Clone, Copy, PartialEq, ::prost::Message)]
+pub struct Pong {}
+/// Sent by mitmproxy before it closes the pipe, so that the redirector can exit cleanly (Windows pipe)
+#[derive(Clone, Copy, PartialEq, ::prost::Message)]
+pub struct Shutdown {}
+/// Request for the redirector's packet counters (Windows pipe)
+#[derive(Clone, Copy, PartialEq, ::prost::Message)]
+pub struct StatsRequest {
+    /// Reset all counters after reporting them.
+    #[prost(bool, tag = "1")]
+    pub reset: bool,
+}
+/// Packet counters since startup or the last reset, sent in response to a StatsRequest (Windows pipe)
+#[derive(Clone, Copy, PartialEq, ::prost::Message)]
+pub struct StatsResponse {
+    #[prost(uint64, tag = "1")]
+    pub packets_received: u64,
+    #[prost(uint64, tag = "2")]
+    pub packets_injected: u64,
+    #[prost(uint64, tag = "3")]
+    pub packets_intercepted: u64,
+    #[prost(uint64, tag = "4")]
+    pub packets_dropped: u64,
+    /// The current size of the connection table.
+    #[prost(uint64, tag = "5")]
+    pub connections: u64,
+}
+/// Request for a page of the redirector's connection table, for troubleshooting (Windows pipe)
+#[derive(Clone, Copy, PartialEq, ::prost::Message)]
+pub struct ConnectionTableRequest {
+    /// The index of the first entry to return.
+    #[prost(uint32, tag = "1")]
+    pub offset: u32,
+    /// The maximum number of entries to return. The redirector may return fewer.
+    #[prost(uint32, tag = "2")]
+    pub limit: u32,
+}
+/// A page of the connection table, sent in response to a ConnectionTableRequest (Windows pipe)
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ConnectionTableResponse {
+    #[prost(message, repeated, tag = "1")]
+    pub entries: ::prost::alloc::vec::Vec<ConnectionTableEntry>,
+    /// The number of entries in the whole table.
+    #[prost(uint32, tag = "2")]
+    pub total: u32,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ConnectionTableEntry {
+    #[prost(string, tag = "1")]
+    pub connection_id: ::prost::alloc::string::String,
+    /// "intercept", "none", "redirect", "restore" for the proxy's replies to a redirected
+    /// connection, or "unknown" if we are still waiting for the socket event.
+    #[prost(string, tag = "2")]
+    pub state: ::prost::alloc::string::String,
+    #[prost(message, optional, tag = "3")]
+    pub tunnel_info: ::core::option::Option<TunnelInfo>,
+    /// The number of packets held back while the state is unknown.
+    #[prost(uint32, tag = "4")]
+    pub buffered_packets: u32,
+    /// Packets and payload bytes from the source of connection_id, and to it.
+    #[prost(uint64, tag = "5")]
+    pub packets_sent: u64,
+    #[prost(uint64, tag = "6")]
+    pub bytes_sent: u64,
+    #[prost(uint64, tag = "7")]
+    pub packets_received: u64,
+    #[prost(uint64, tag = "8")]
+    pub bytes_received: u64,
+    /// The TLS SNI or DNS query name from the first payload, if any.
+    #[prost(string, tag = "9")]
+    pub server_name: ::prost::alloc::string::String,
+}
+/// A connection was opened or closed (Windows pipe and monitoring pipe)
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ConnectionEvent {
+    #[prost(string, tag = "1")]
+    pub connection_id: ::prost::alloc::string::String,
+    /// "open" or "close".
+    #[prost(string, tag = "2")]
+    pub event: ::prost::alloc::string::String,
+    /// "intercept", "none", "redirect", or "restore" for the proxy's replies to a redirected connection.
+    #[prost(string, tag = "3")]
+    pub state: ::prost::alloc::string::String,
+    #[prost(message, optional, tag = "4")]
+    pub tunnel_info: ::core::option::Option<TunnelInfo>,
+}
+/// Tear down a TCP connection by injecting RST packets in both directions (Windows pipe)
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ResetConnection {
+    /// The connection as seen from the side that opened it.
+    #[prost(message, optional, tag = "1")]
+    pub src: ::core::option::Option<Address>,
+    #[prost(message, optional, tag = "2")]
+    pub dst: ::core::option::Option<Address>,
+    /// The next sequence number from src, and the next sequence number src expects from dst.
+    #[prost(uint32, tag = "3")]
+    pub seq: u32,
+    #[prost(uint32, tag = "4")]
+    pub ack: u32,
+}
+/// Pass all new connections through and only log which ones would be intercepted (Windows pipe)
+#[derive(Clone, Copy, PartialEq, ::prost::Message)]
+pub struct SetDryRun {
+    #[prost(bool, tag = "1")]
+    pub enabled: bool,
+}
+/// Add an include rule for a single process to the current intercept spec, without touching
+/// the connections we already know (Windows pipe)
+#[derive(Clone, Copy, PartialEq, ::prost::Message)]
+pub struct AddInterceptPid {
+    #[prost(uint32, tag = "1")]
+    pub pid: u32,
+}
+/// Remove the include rule for a single process again (Windows pipe)
+#[derive(Clone, Copy, PartialEq, ::prost::Message)]
+pub struct RemoveInterceptPid {
+    #[prost(uint32, tag = "1")]
+    pub pid: u32,
+}
+/// Request for the original destination of a connection redirected with --redirect-to (Windows pipe)
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct OriginalDstRequest {
+    /// The connection as seen by the proxy: src is its peer, dst is the proxy itself.
+    #[prost(message, optional, tag = "1")]
+    pub src: ::core::option::Option<Address>,
+    #[prost(message, optional, tag = "2")]
+    pub dst: ::core::option::Option<Address>,
+}
+/// Sent in response to an OriginalDstRequest (Windows pipe)
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct OriginalDstResponse {
+    /// The connection from the request.
+    #[prost(message, optional, tag = "1")]
+    pub src: ::core::option::Option<Address>,
+    #[prost(message, optional, tag = "2")]
+    pub dst: ::core::option::Option<Address>,
+    /// Where the client originally connected to. Unset if the connection is not known to be redirected.
+    #[prost(message, optional, tag = "3")]
+    pub original_dst: ::core::option::Option<Address>,
+}
 /// Packet (macOS UDP Stream)
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Packet {
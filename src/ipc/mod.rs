@@ -1,9 +1,262 @@
+pub mod json;
 mod mitmproxy_ipc;
+pub mod redirector;
 pub use mitmproxy_ipc::*;
 
 use crate::intercept_conf;
+use crate::packet_sources::IPC_BUF_SIZE;
+use anyhow::{bail, ensure, Context, Result};
+use prost::bytes::{BufMut, Bytes, BytesMut};
+use prost::Message;
+use std::io;
 use std::net::{AddrParseError, IpAddr, SocketAddr};
 use std::str::FromStr;
+use std::time::Duration;
+use tokio_util::codec::{Decoder, Encoder, LengthDelimitedCodec, LengthDelimitedCodecError};
+
+/// The codec for IPC messages between mitmproxy and the Windows redirector.
+///
+/// Named pipes do not preserve message boundaries in byte mode, so each message is
+/// prefixed with its length as a little-endian u32. Frames longer than [`IPC_BUF_SIZE`]
+/// are rejected. Both sides must agree on all of this, see the `wire_format_pinned` test.
+pub fn codec() -> IpcCodec {
+    IpcCodec {
+        length_delimited: LengthDelimitedCodec::builder()
+            .little_endian()
+            .length_field_length(4)
+            .max_frame_length(IPC_BUF_SIZE)
+            .new_codec(),
+        json_lines: false,
+    }
+}
+
+/// JSON lines are longer than the corresponding protobuf frames, mostly because of base64.
+const MAX_JSON_LINE_LEN: usize = 2 * IPC_BUF_SIZE;
+
+/// Length-delimited frames, or newline-delimited ones once JSON has been negotiated.
+#[derive(Debug)]
+pub struct IpcCodec {
+    length_delimited: LengthDelimitedCodec,
+    json_lines: bool,
+}
+
+impl IpcCodec {
+    /// Switch to newline-delimited frames. Data that has already been read is kept.
+    pub fn use_json_lines(&mut self) {
+        self.json_lines = true;
+    }
+}
+
+impl Decoder for IpcCodec {
+    type Item = BytesMut;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<BytesMut>, io::Error> {
+        if !self.json_lines {
+            // The length prefix is checked before any memory is reserved for the frame.
+            return self.length_delimited.decode(src).map_err(frame_error);
+        }
+        match src.iter().position(|&b| b == b'\n') {
+            Some(pos) => {
+                let mut line = src.split_to(pos + 1);
+                line.truncate(pos);
+                Ok(Some(line))
+            }
+            None if src.len() > MAX_JSON_LINE_LEN => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "JSON line too long",
+            )),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Replace the generic "frame size too big" error with a more helpful one.
+fn frame_error(e: io::Error) -> io::Error {
+    match e.get_ref() {
+        Some(inner) if inner.is::<LengthDelimitedCodecError>() => io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "IPC frame exceeds the maximum size of {} bytes",
+                IPC_BUF_SIZE
+            ),
+        ),
+        _ => e,
+    }
+}
+
+impl Encoder<Bytes> for IpcCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, item: Bytes, dst: &mut BytesMut) -> Result<(), io::Error> {
+        if !self.json_lines {
+            return self.length_delimited.encode(item, dst);
+        }
+        dst.reserve(item.len() + 1);
+        dst.extend_from_slice(&item);
+        dst.put_u8(b'\n');
+        Ok(())
+    }
+}
+
+/// How messages are encoded after the handshake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    /// Protobuf, see [`encode_frame`].
+    Protobuf { compression: bool },
+    /// Newline-delimited JSON, see [`json`].
+    Json,
+}
+
+impl Encoding {
+    pub fn encode(&self, message: &FromRedirector) -> Bytes {
+        match self {
+            Encoding::Protobuf { compression } => encode_frame(message, *compression),
+            Encoding::Json => Bytes::from(json::encode(message)),
+        }
+    }
+
+    pub fn decode(&self, frame: BytesMut) -> Result<FromProxy> {
+        match self {
+            Encoding::Protobuf { compression } => {
+                let frame = decode_frame(frame, *compression)?;
+                Ok(FromProxy::decode(frame)?)
+            }
+            Encoding::Json => json::decode(&frame),
+        }
+    }
+}
+
+/// Messages smaller than this are never compressed.
+pub const COMPRESSION_THRESHOLD: usize = 1024;
+
+const FRAME_UNCOMPRESSED: u8 = 0;
+const FRAME_LZ4: u8 = 1;
+
+/// Serialize a message into a frame.
+///
+/// If compression has been negotiated in the handshake, the frame starts with a flag byte
+/// and large messages are LZ4-compressed. Otherwise the encoded message is sent as-is.
+pub fn encode_frame(message: &impl Message, compression: bool) -> Bytes {
+    let data = message.encode_to_vec();
+    if !compression {
+        return Bytes::from(data);
+    }
+    if data.len() >= COMPRESSION_THRESHOLD {
+        let compressed = lz4_flex::block::compress_prepend_size(&data);
+        if compressed.len() < data.len() {
+            let mut frame = Vec::with_capacity(1 + compressed.len());
+            frame.push(FRAME_LZ4);
+            frame.extend(compressed);
+            return Bytes::from(frame);
+        }
+    }
+    let mut frame = Vec::with_capacity(1 + data.len());
+    frame.push(FRAME_UNCOMPRESSED);
+    frame.extend(data);
+    Bytes::from(frame)
+}
+
+/// The inverse of [`encode_frame`].
+pub fn decode_frame(mut frame: BytesMut, compression: bool) -> Result<Bytes> {
+    if !compression {
+        return Ok(frame.freeze());
+    }
+    ensure!(!frame.is_empty(), "empty IPC frame");
+    let flag = frame.split_to(1)[0];
+    match flag {
+        FRAME_UNCOMPRESSED => Ok(frame.freeze()),
+        FRAME_LZ4 => {
+            ensure!(frame.len() >= 4, "truncated compressed IPC frame");
+            let len = u32::from_le_bytes([frame[0], frame[1], frame[2], frame[3]]) as usize;
+            // Don't let a bogus size make us allocate arbitrary amounts of memory.
+            ensure!(
+                len <= IPC_BUF_SIZE,
+                "compressed IPC frame too large: {} bytes",
+                len
+            );
+            let data = lz4_flex::block::decompress(&frame[4..], len)
+                .context("invalid compressed IPC frame")?;
+            Ok(Bytes::from(data))
+        }
+        _ => bail!("unknown IPC frame flag: {}", flag),
+    }
+}
+
+/// Room for the `FromRedirector` envelope around a batch and the compression flag byte.
+const BATCH_ENVELOPE_LEN: usize = 8;
+
+/// Coalesce packets into as few `FromRedirector` messages as possible,
+/// each of which still fits into a single IPC frame.
+#[derive(Debug, Default)]
+pub struct PacketBatcher {
+    packets: Vec<PacketWithMeta>,
+    encoded_len: usize,
+}
+
+impl PacketBatcher {
+    /// Add a packet. If it does not fit into the current batch anymore,
+    /// the current batch is returned and a new one is started.
+    pub fn push(&mut self, packet: PacketWithMeta) -> Option<FromRedirector> {
+        let len = packet.encoded_len();
+        // field key + length delimiter + message
+        let len = 1 + prost::length_delimiter_len(len) + len;
+        let full = self.encoded_len + len + BATCH_ENVELOPE_LEN > IPC_BUF_SIZE;
+        let ret = if full { self.flush() } else { None };
+        self.packets.push(packet);
+        self.encoded_len += len;
+        ret
+    }
+
+    /// Take the current batch. A single packet is sent without a batch wrapper.
+    pub fn flush(&mut self) -> Option<FromRedirector> {
+        self.encoded_len = 0;
+        let message = match self.packets.len() {
+            0 => return None,
+            1 => from_redirector::Message::Packet(self.packets.pop().unwrap()),
+            _ => from_redirector::Message::PacketBatch(PacketBatch {
+                packets: std::mem::take(&mut self.packets),
+            }),
+        };
+        Some(FromRedirector {
+            message: Some(message),
+        })
+    }
+}
+
+/// Exponential backoff for reconnecting to a broken IPC channel.
+///
+/// Yields a delay before each retry and stops after `max_attempts` retries.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    delay: Duration,
+    max_delay: Duration,
+    attempts_left: usize,
+}
+
+impl Backoff {
+    pub fn new(initial_delay: Duration, max_delay: Duration, max_attempts: usize) -> Self {
+        Self {
+            delay: initial_delay,
+            max_delay,
+            attempts_left: max_attempts,
+        }
+    }
+}
+
+impl Iterator for Backoff {
+    type Item = Duration;
+
+    fn next(&mut self) -> Option<Duration> {
+        if self.attempts_left == 0 {
+            return None;
+        }
+        self.attempts_left -= 1;
+        let delay = self.delay;
+        self.delay = (self.delay * 2).min(self.max_delay);
+        Some(delay)
+    }
+}
 
 impl TryFrom<&Address> for SocketAddr {
     type Error = AddrParseError;
@@ -37,3 +290,412 @@ impl TryFrom<InterceptConf> for intercept_conf::InterceptConf {
         intercept_conf::InterceptConf::try_from(conf.actions)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MAX_PACKET_SIZE;
+    use futures_util::{SinkExt, StreamExt};
+    use rand::Rng;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_util::codec::Framed;
+
+    fn packet(data: &'static [u8]) -> FromProxy {
+        FromProxy {
+            message: Some(from_proxy::Message::Packet(Packet {
+                data: Bytes::from_static(data),
+            })),
+        }
+    }
+
+    fn wire_format(messages: &[FromProxy]) -> Vec<u8> {
+        let mut wire = Vec::new();
+        for msg in messages {
+            let data = msg.encode_to_vec();
+            wire.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            wire.extend(data);
+        }
+        wire
+    }
+
+    #[tokio::test]
+    async fn partial_reads() {
+        let messages = [packet(b"hello"), packet(b"world")];
+        let wire = wire_format(&messages);
+        let (mut tx, rx) = tokio::io::duplex(64);
+        let mut rx = Framed::new(rx, codec());
+
+        let writer = tokio::spawn(async move {
+            for b in wire {
+                tx.write_all(&[b]).await.unwrap();
+                tokio::task::yield_now().await;
+            }
+        });
+        for expected in messages {
+            let frame = rx.next().await.unwrap().unwrap();
+            assert_eq!(FromProxy::decode(frame.as_ref()).unwrap(), expected);
+        }
+        writer.await.unwrap();
+        assert!(rx.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn coalesced_reads() {
+        let messages = [packet(b"hello"), packet(b"world")];
+        let (mut tx, rx) = tokio::io::duplex(1024);
+        let mut rx = Framed::new(rx, codec());
+
+        tx.write_all(&wire_format(&messages)).await.unwrap();
+        drop(tx);
+        for expected in messages {
+            let frame = rx.next().await.unwrap().unwrap();
+            assert_eq!(FromProxy::decode(frame.as_ref()).unwrap(), expected);
+        }
+        assert!(rx.next().await.is_none());
+    }
+
+    fn packet_with_meta(len: usize) -> PacketWithMeta {
+        PacketWithMeta {
+            data: Bytes::from(vec![0; len]),
+            tunnel_info: Some(TunnelInfo {
+                pid: Some(1234),
+                process_name: Some("curl.exe".to_string()),
+            }),
+        }
+    }
+
+    #[test]
+    fn batch_packets() {
+        let mut batcher = PacketBatcher::default();
+        assert_eq!(batcher.flush(), None);
+
+        assert_eq!(batcher.push(packet_with_meta(100)), None);
+        assert_eq!(
+            batcher.flush().unwrap().message,
+            Some(from_redirector::Message::Packet(packet_with_meta(100)))
+        );
+
+        let mut messages = Vec::new();
+        for _ in 0..100 {
+            messages.extend(batcher.push(packet_with_meta(1500)));
+        }
+        messages.extend(batcher.flush());
+        assert!(messages.len() > 1);
+        let mut count = 0;
+        for message in messages {
+            let data = message.encode_to_vec();
+            assert!(data.len() <= IPC_BUF_SIZE);
+            match FromRedirector::decode(data.as_slice()).unwrap().message {
+                Some(from_redirector::Message::PacketBatch(batch)) => count += batch.packets.len(),
+                Some(from_redirector::Message::Packet(_)) => count += 1,
+                _ => unreachable!(),
+            }
+        }
+        assert_eq!(count, 100);
+
+        // Maximum-size packets are never batched together.
+        assert_eq!(batcher.push(packet_with_meta(MAX_PACKET_SIZE)), None);
+        let first = batcher.push(packet_with_meta(MAX_PACKET_SIZE)).unwrap();
+        assert!(first.encode_to_vec().len() <= IPC_BUF_SIZE);
+        assert!(matches!(
+            batcher.flush().unwrap().message,
+            Some(from_redirector::Message::Packet(_))
+        ));
+    }
+
+    #[test]
+    fn frame_compression() {
+        let small = packet(b"hello");
+        let large = FromProxy {
+            message: Some(from_proxy::Message::Packet(Packet {
+                data: Bytes::from(vec![0x42; 4096]),
+            })),
+        };
+        for message in [small, large] {
+            let frame = encode_frame(&message, false);
+            assert_eq!(frame, message.encode_to_vec());
+            let data = decode_frame(BytesMut::from(frame.as_ref()), false).unwrap();
+            assert_eq!(FromProxy::decode(data).unwrap(), message);
+
+            let frame = encode_frame(&message, true);
+            let compressed = frame[0] == FRAME_LZ4;
+            assert_eq!(compressed, message.encoded_len() >= COMPRESSION_THRESHOLD);
+            let data = decode_frame(BytesMut::from(frame.as_ref()), true).unwrap();
+            assert_eq!(FromProxy::decode(data).unwrap(), message);
+        }
+
+        assert!(decode_frame(BytesMut::new(), true).is_err());
+        assert!(decode_frame(BytesMut::from(&[2u8][..]), true).is_err());
+        let mut bogus = vec![FRAME_LZ4];
+        bogus.extend_from_slice(&u32::MAX.to_le_bytes());
+        assert!(decode_frame(BytesMut::from(bogus.as_slice()), true).is_err());
+    }
+
+    #[test]
+    fn backoff() {
+        let delays = Backoff::new(Duration::from_millis(100), Duration::from_millis(500), 5)
+            .map(|d| d.as_millis())
+            .collect::<Vec<_>>();
+        assert_eq!(delays, [100, 200, 400, 500, 500]);
+    }
+
+    #[tokio::test]
+    async fn json_lines() {
+        let (mut tx, rx) = tokio::io::duplex(1024);
+        let mut rx = Framed::new(rx, codec());
+
+        let wire = wire_format(&[packet(b"hello")]);
+        tx.write_all(&wire).await.unwrap();
+        tx.write_all(b"{\"type\":\"pong\"}\n{\"type\":")
+            .await
+            .unwrap();
+        let frame = rx.next().await.unwrap().unwrap();
+        assert_eq!(FromProxy::decode(frame.as_ref()).unwrap(), packet(b"hello"));
+
+        rx.codec_mut().use_json_lines();
+        let frame = rx.next().await.unwrap().unwrap();
+        assert_eq!(
+            Encoding::Json.decode(frame).unwrap().message,
+            Some(from_proxy::Message::Pong(Pong {}))
+        );
+        tx.write_all(b"\"shutdown\"}\n").await.unwrap();
+        let frame = rx.next().await.unwrap().unwrap();
+        assert_eq!(frame, &br#"{"type":"shutdown"}"#[..]);
+
+        let ping = FromRedirector {
+            message: Some(from_redirector::Message::Ping(Ping {})),
+        };
+        rx.send(Encoding::Json.encode(&ping)).await.unwrap();
+        let mut line = [0; 16];
+        tx.read_exact(&mut line).await.unwrap();
+        assert_eq!(&line, b"{\"type\":\"ping\"}\n");
+    }
+
+    fn random_bytes(len: usize) -> Bytes {
+        let mut data = vec![0; len];
+        rand::thread_rng().fill(&mut data[..]);
+        Bytes::from(data)
+    }
+
+    /// Write a frame with the codec and read it back.
+    fn roundtrip_frame(frame: Bytes) -> BytesMut {
+        let mut wire = BytesMut::new();
+        codec().encode(frame.clone(), &mut wire).unwrap();
+        assert_eq!(wire[..4], (frame.len() as u32).to_le_bytes());
+        let decoded = codec().decode(&mut wire).unwrap().unwrap();
+        assert!(wire.is_empty());
+        decoded
+    }
+
+    fn all_from_redirector() -> Vec<from_redirector::Message> {
+        use from_redirector::Message;
+        let tunnel_info = TunnelInfo {
+            pid: Some(u32::MAX),
+            process_name: Some("ü.exe".to_string()),
+        };
+        let mut batcher = PacketBatcher::default();
+        let batch = std::iter::repeat_with(|| PacketWithMeta {
+            data: random_bytes(1500),
+            tunnel_info: Some(tunnel_info.clone()),
+        })
+        .find_map(|packet| batcher.push(packet))
+        .unwrap();
+        let messages = vec![
+            Message::Packet(PacketWithMeta {
+                data: random_bytes(MAX_PACKET_SIZE),
+                tunnel_info: Some(tunnel_info.clone()),
+            }),
+            Message::Hello(Hello {
+                version: u32::MAX,
+                lz4: true,
+                json: true,
+                pid: None,
+                connection_events: false,
+            }),
+            batch.message.unwrap(),
+            Message::Ping(Ping {}),
+            Message::Error(Error {
+                code: i32::MIN,
+                message: "error".to_string(),
+            }),
+            Message::StatsResponse(StatsResponse {
+                packets_received: u64::MAX,
+                packets_injected: 1,
+                packets_intercepted: 2,
+                packets_dropped: 3,
+                connections: 4,
+            }),
+            Message::ConnectionTableResponse(ConnectionTableResponse {
+                entries: vec![ConnectionTableEntry {
+                    connection_id: "TCP 10.0.0.1:1 -> 10.0.0.2:2".to_string(),
+                    state: "intercept".to_string(),
+                    tunnel_info: Some(tunnel_info.clone()),
+                    buffered_packets: 5,
+                    packets_sent: 6,
+                    bytes_sent: u64::MAX,
+                    packets_received: 7,
+                    bytes_received: 8,
+                    server_name: "example.com".to_string(),
+                }],
+                total: 1,
+            }),
+            Message::ConnectionEvent(ConnectionEvent {
+                connection_id: "UDP [::1]:1 -> [::1]:2".to_string(),
+                event: "open".to_string(),
+                state: "none".to_string(),
+                tunnel_info: Some(tunnel_info),
+            }),
+            Message::Warning(Warning {
+                message: "warning".to_string(),
+            }),
+            Message::OriginalDstResponse(OriginalDstResponse {
+                src: Some("10.0.0.1:1".parse::<SocketAddr>().unwrap().into()),
+                dst: Some("127.0.0.1:8080".parse::<SocketAddr>().unwrap().into()),
+                original_dst: Some("[2001:db8::1]:443".parse::<SocketAddr>().unwrap().into()),
+            }),
+        ];
+        // Adding a message without covering it here is a compile error.
+        for message in &messages {
+            match message {
+                Message::Packet(_)
+                | Message::Hello(_)
+                | Message::PacketBatch(_)
+                | Message::Ping(_)
+                | Message::Error(_)
+                | Message::StatsResponse(_)
+                | Message::ConnectionTableResponse(_)
+                | Message::ConnectionEvent(_)
+                | Message::Warning(_)
+                | Message::OriginalDstResponse(_) => {}
+            }
+        }
+        messages
+    }
+
+    fn all_from_proxy() -> Vec<from_proxy::Message> {
+        use from_proxy::Message;
+        let messages = vec![
+            Message::Packet(Packet {
+                data: random_bytes(MAX_PACKET_SIZE),
+            }),
+            Message::InterceptConf(InterceptConf {
+                actions: vec!["curl".to_string(), "!:53".to_string()],
+            }),
+            Message::Hello(Hello {
+                version: 1,
+                lz4: false,
+                json: true,
+                pid: Some(u32::MAX),
+                connection_events: true,
+            }),
+            Message::Pong(Pong {}),
+            Message::Shutdown(Shutdown {}),
+            Message::StatsRequest(StatsRequest { reset: true }),
+            Message::ConnectionTableRequest(ConnectionTableRequest {
+                offset: u32::MAX,
+                limit: 10,
+            }),
+            Message::ResetConnection(ResetConnection {
+                src: Some("10.0.0.1:1".parse::<SocketAddr>().unwrap().into()),
+                dst: Some("[::1]:65535".parse::<SocketAddr>().unwrap().into()),
+                seq: u32::MAX,
+                ack: 1,
+            }),
+            Message::SetDryRun(SetDryRun { enabled: true }),
+            Message::AddInterceptPid(AddInterceptPid { pid: u32::MAX }),
+            Message::RemoveInterceptPid(RemoveInterceptPid { pid: 0 }),
+            Message::OriginalDstRequest(OriginalDstRequest {
+                src: Some("10.0.0.1:1".parse::<SocketAddr>().unwrap().into()),
+                dst: Some("127.0.0.1:8080".parse::<SocketAddr>().unwrap().into()),
+            }),
+        ];
+        for message in &messages {
+            match message {
+                Message::Packet(_)
+                | Message::InterceptConf(_)
+                | Message::Hello(_)
+                | Message::Pong(_)
+                | Message::Shutdown(_)
+                | Message::StatsRequest(_)
+                | Message::ConnectionTableRequest(_)
+                | Message::ResetConnection(_)
+                | Message::SetDryRun(_)
+                | Message::AddInterceptPid(_)
+                | Message::RemoveInterceptPid(_)
+                | Message::OriginalDstRequest(_) => {}
+            }
+        }
+        messages
+    }
+
+    /// Every message survives encoding, framing and decoding, also when it is as large as a
+    /// frame can be.
+    #[test]
+    fn roundtrip_all_messages() {
+        for compression in [false, true] {
+            for message in all_from_redirector() {
+                let message = FromRedirector {
+                    message: Some(message),
+                };
+                let frame = encode_frame(&message, compression);
+                assert!(frame.len() <= IPC_BUF_SIZE);
+                let data = decode_frame(roundtrip_frame(frame), compression).unwrap();
+                assert_eq!(FromRedirector::decode(data).unwrap(), message);
+            }
+            for message in all_from_proxy() {
+                let message = FromProxy {
+                    message: Some(message),
+                };
+                let frame = encode_frame(&message, compression);
+                assert!(frame.len() <= IPC_BUF_SIZE);
+                let encoding = Encoding::Protobuf { compression };
+                assert_eq!(encoding.decode(roundtrip_frame(frame)).unwrap(), message);
+            }
+        }
+    }
+
+    /// The wire format must not change between releases.
+    #[test]
+    fn wire_format_pinned() {
+        let mut wire = BytesMut::new();
+        let message = FromRedirector {
+            message: Some(from_redirector::Message::Hello(Hello {
+                version: 6,
+                lz4: true,
+                json: false,
+                pid: None,
+                connection_events: false,
+            })),
+        };
+        codec()
+            .encode(encode_frame(&message, false), &mut wire)
+            .unwrap();
+        assert_eq!(
+            &wire[..],
+            [6u8, 0, 0, 0, 0x12, 0x04, 0x08, 0x06, 0x10, 0x01]
+        );
+
+        let message = FromProxy {
+            message: Some(from_proxy::Message::AddInterceptPid(AddInterceptPid {
+                pid: 300,
+            })),
+        };
+        assert_eq!(
+            &encode_frame(&message, true)[..],
+            [FRAME_UNCOMPRESSED, 0x52, 0x03, 0x08, 0xac, 0x02]
+        );
+    }
+
+    #[tokio::test]
+    async fn oversized_frame() {
+        let (mut tx, rx) = tokio::io::duplex(64);
+        let mut rx = Framed::new(rx, codec());
+
+        tx.write_all(&(IPC_BUF_SIZE as u32 + 1).to_le_bytes())
+            .await
+            .unwrap();
+        let err = rx.next().await.unwrap().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("maximum size"));
+    }
+}
@@ -0,0 +1,498 @@
+//! The redirector's side of the IPC protocol: the handshake with mitmproxy, and relaying messages
+//! in both directions afterwards.
+//!
+//! This works with any byte stream, so that it can be tested without the Windows named pipe
+//! the redirector uses in production.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use futures_util::{SinkExt, StreamExt};
+use log::{info, trace, warn};
+use pretty_hex::pretty_hex;
+use prost::bytes::Bytes;
+use prost::Message;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::mpsc::{Sender, UnboundedReceiver};
+use tokio::time::Instant;
+use tokio_util::codec::Framed;
+
+use super::{
+    from_proxy, from_redirector, AddInterceptPid, Encoding, Error, FromProxy, FromRedirector,
+    Hello, IpcCodec, PacketBatcher, PacketWithMeta, Ping, RemoveInterceptPid,
+};
+use crate::intercept_conf;
+use crate::packet_sources::PROTOCOL_VERSION;
+
+/// The maximum number of packets we take from the queue before yielding to incoming messages.
+const MAX_BATCH_PACKETS: usize = 64;
+
+/// The byte stream to mitmproxy. This is a named pipe, but the IPC logic works with anything
+/// that can be read from and written to, e.g. an in-memory or Unix socket for testing.
+pub trait Transport: AsyncRead + AsyncWrite + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> Transport for T {}
+
+/// What mitmproxy told us in the handshake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Session {
+    /// How further messages are encoded.
+    pub encoding: Encoding,
+    /// mitmproxy's process id, if it sent one.
+    pub pid: Option<u32>,
+    /// Whether mitmproxy wants to be told about connections being opened and closed.
+    pub connection_events: bool,
+}
+
+/// Make sure that we speak the same protocol before doing anything else.
+/// Returns `None` if the connection closed before mitmproxy replied.
+pub async fn handshake<T: Transport>(ipc: &mut Framed<T, IpcCodec>) -> Result<Option<Session>> {
+    let hello = FromRedirector {
+        message: Some(from_redirector::Message::Hello(Hello {
+            version: PROTOCOL_VERSION,
+            lz4: true,
+            json: true,
+            pid: None,
+            connection_events: false,
+        })),
+    };
+    ipc.send(Bytes::from(hello.encode_to_vec())).await?;
+    let Some(Ok(frame)) = ipc.next().await else {
+        return Ok(None);
+    };
+    match FromProxy::decode(frame.as_ref()) {
+        Ok(FromProxy {
+            message:
+                Some(from_proxy::Message::Hello(Hello {
+                    version,
+                    lz4,
+                    json,
+                    pid,
+                    connection_events,
+                })),
+        }) => {
+            if version != PROTOCOL_VERSION {
+                return Err(anyhow!(
+                    "mitmproxy speaks IPC protocol version {}, but the redirector expects version {}. \
+                    Make sure that mitmproxy and the redirector are from the same release.",
+                    version,
+                    PROTOCOL_VERSION
+                ));
+            }
+            let encoding = if json {
+                info!("Using JSON for IPC messages.");
+                ipc.codec_mut().use_json_lines();
+                Encoding::Json
+            } else {
+                Encoding::Protobuf { compression: lz4 }
+            };
+            Ok(Some(Session {
+                encoding,
+                pid,
+                connection_events,
+            }))
+        }
+        _ => Err(anyhow!("Expected IPC handshake, received: {:?}", &frame)),
+    }
+}
+
+/// Why [`handle_ipc`] returned.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Disconnect {
+    /// The pipe was closed.
+    Closed,
+    /// mitmproxy asked us to shut down.
+    Shutdown,
+    /// We reported a fatal error to mitmproxy, and need to exit with this code.
+    Fatal(i32),
+}
+
+/// Relay messages until the connection is closed or breaks.
+///
+/// Messages from mitmproxy are passed on to `tx`, with the exception of keepalive responses.
+/// The intercept config in `conf` is kept up to date, so that it can be replayed after
+/// reconnecting, and saved to `conf_cache` if given.
+///
+/// If we have not heard from mitmproxy for `keepalive_interval`, we send a ping.
+/// If there is no response within another `keepalive_interval`, the connection is considered dead.
+#[allow(clippy::too_many_arguments)]
+pub async fn handle_ipc<T: Transport, E: From<from_proxy::Message>>(
+    mut ipc: Framed<T, IpcCodec>,
+    encoding: Encoding,
+    keepalive_interval: Duration,
+    ipc_rx: &mut UnboundedReceiver<PacketWithMeta>,
+    tx: &Sender<E>,
+    conf: &mut super::InterceptConf,
+    conf_cache: Option<&Path>,
+    error_rx: &mut UnboundedReceiver<Error>,
+    reply_rx: &mut UnboundedReceiver<from_redirector::Message>,
+) -> Result<Disconnect> {
+    let mut last_received = Instant::now();
+    let mut ping_sent: Option<Instant> = None;
+    loop {
+        let deadline = ping_sent.unwrap_or(last_received) + keepalive_interval;
+        tokio::select! {
+            r = ipc.next() => {
+                match r {
+                    Some(Ok(frame)) => {
+                        // Any message shows that the other side is still alive.
+                        last_received = Instant::now();
+                        ping_sent = None;
+
+                        trace!("Received IPC message:\n{}", pretty_hex(&frame));
+                        let FromProxy { message: Some(message) } = encoding
+                            .decode(frame)
+                            .context("Received invalid IPC message")? else {
+                            return Err(anyhow!("Received empty IPC message."));
+                        };
+                        let shutdown = matches!(message, from_proxy::Message::Shutdown(_));
+                        match &message {
+                            from_proxy::Message::Pong(_) => continue,
+                            from_proxy::Message::InterceptConf(c) => {
+                                *conf = c.clone();
+                                if let Some(path) = conf_cache {
+                                    save_conf_cache(path, c);
+                                }
+                            }
+                            // Keep track of incremental updates, so that we can replay them after reconnecting.
+                            from_proxy::Message::AddInterceptPid(AddInterceptPid { pid }) => {
+                                update_conf(conf, conf_cache, |c| c.add_intercept_pid(*pid));
+                            }
+                            from_proxy::Message::RemoveInterceptPid(RemoveInterceptPid { pid }) => {
+                                update_conf(conf, conf_cache, |c| c.remove_intercept_pid(*pid));
+                            }
+                            _ => {}
+                        }
+
+                        tx.send(message.into()).await.map_err(|_| anyhow!("channel closed"))?;
+
+                        if shutdown {
+                            return Ok(Disconnect::Shutdown);
+                        }
+                    }
+                    Some(Err(e)) => return Err(e.into()),
+                    None => return Ok(Disconnect::Closed),
+                }
+            },
+            // Responses to requests from mitmproxy.
+            Some(reply) = reply_rx.recv() => {
+                let message = FromRedirector {
+                    message: Some(reply),
+                };
+                ipc.send(encoding.encode(&message)).await?;
+            },
+            Some(error) = error_rx.recv() => {
+                let code = error.code;
+                let message = FromRedirector {
+                    message: Some(from_redirector::Message::Error(error)),
+                };
+                // Best effort, we are exiting anyway.
+                if ipc.send(encoding.encode(&message)).await.is_err() {
+                    warn!("Failed to report error to mitmproxy.");
+                }
+                return Ok(Disconnect::Fatal(code));
+            },
+            _ = tokio::time::sleep_until(deadline) => {
+                if ping_sent.is_some() {
+                    return Err(anyhow!("No keepalive response within {:?}.", keepalive_interval));
+                }
+                let ping = FromRedirector {
+                    message: Some(from_redirector::Message::Ping(Ping {})),
+                };
+                ipc.send(encoding.encode(&ping)).await?;
+                ping_sent = Some(Instant::now());
+            },
+            Some(packet) = ipc_rx.recv() => {
+                // Coalesce packets that are already queued into as few frames as possible.
+                // We never wait for more packets, so sparse traffic is flushed immediately.
+                let mut batcher = PacketBatcher::default();
+                let mut messages = Vec::new();
+                messages.extend(batcher.push(packet));
+                for _ in 1..MAX_BATCH_PACKETS {
+                    let Ok(packet) = ipc_rx.try_recv() else {
+                        break;
+                    };
+                    messages.extend(batcher.push(packet));
+                }
+                messages.extend(batcher.flush());
+
+                for message in messages {
+                    let data = encoding.encode(&message);
+                    trace!("Sending IPC message:\n{}", pretty_hex(&data));
+                    ipc.send(data).await?;
+                }
+            }
+        }
+    }
+}
+
+/// Read the intercept config saved by [`save_conf_cache`], one action per line.
+pub fn load_conf_cache(path: &Path) -> Option<intercept_conf::InterceptConf> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return None,
+        Err(e) => {
+            warn!("Cannot read cached intercept config: {}", e);
+            return None;
+        }
+    };
+    let actions: Vec<&str> = contents.lines().filter(|l| !l.is_empty()).collect();
+    match intercept_conf::InterceptConf::try_from(actions) {
+        Ok(conf) => {
+            info!("Using cached intercept config. {}", conf.description());
+            Some(conf)
+        }
+        Err(e) => {
+            warn!("Ignoring invalid cached intercept config: {:?}", e);
+            None
+        }
+    }
+}
+
+pub fn save_conf_cache(path: &Path, conf: &super::InterceptConf) {
+    if let Err(e) = fs::write(path, conf.actions.join("\n")) {
+        warn!("Cannot cache intercept config: {}", e);
+    }
+}
+
+/// Apply an incremental update to the intercept spec we replay after reconnecting.
+fn update_conf(
+    conf: &mut super::InterceptConf,
+    conf_cache: Option<&Path>,
+    update: impl FnOnce(&mut intercept_conf::InterceptConf),
+) {
+    // An invalid spec is reported by the main loop.
+    let Ok(mut parsed) = intercept_conf::InterceptConf::try_from(conf.actions.clone()) else {
+        return;
+    };
+    update(&mut parsed);
+    conf.actions = parsed.actions();
+    if let Some(path) = conf_cache {
+        save_conf_cache(path, conf);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ipc::{codec, decode_frame, encode_frame, InterceptConf, StatsResponse};
+    use tokio::sync::mpsc;
+
+    fn decode(frame: prost::bytes::BytesMut) -> FromRedirector {
+        FromRedirector::decode(decode_frame(frame, false).unwrap()).unwrap()
+    }
+
+    fn encode(message: from_proxy::Message) -> Bytes {
+        let message = FromProxy {
+            message: Some(message),
+        };
+        encode_frame(&message, false)
+    }
+
+    #[tokio::test]
+    async fn handshake_and_relay() {
+        let (redirector, proxy) = tokio::io::duplex(64 * 1024);
+        let mut redirector = Framed::new(redirector, codec());
+        let mut proxy = Framed::new(proxy, codec());
+
+        let mitmproxy = async {
+            let frame = proxy.next().await.unwrap().unwrap();
+            let hello = FromRedirector::decode(frame.as_ref()).unwrap();
+            assert!(matches!(
+                hello.message,
+                Some(from_redirector::Message::Hello(Hello {
+                    version: PROTOCOL_VERSION,
+                    ..
+                }))
+            ));
+            let hello = FromProxy {
+                message: Some(from_proxy::Message::Hello(Hello {
+                    version: PROTOCOL_VERSION,
+                    lz4: false,
+                    json: false,
+                    pid: Some(42),
+                    connection_events: true,
+                })),
+            };
+            proxy
+                .send(Bytes::from(hello.encode_to_vec()))
+                .await
+                .unwrap();
+        };
+        let (result, ()) = tokio::join!(handshake(&mut redirector), mitmproxy);
+        let session = result.unwrap().unwrap();
+        assert_eq!(
+            session,
+            Session {
+                encoding: Encoding::Protobuf { compression: false },
+                pid: Some(42),
+                connection_events: true,
+            }
+        );
+
+        let (packet_tx, mut ipc_rx) = mpsc::unbounded_channel();
+        let (tx, mut rx) = mpsc::channel::<from_proxy::Message>(16);
+        let (_error_tx, mut error_rx) = mpsc::unbounded_channel();
+        let (reply_tx, mut reply_rx) = mpsc::unbounded_channel();
+        let mut conf: InterceptConf = intercept_conf::InterceptConf::disabled().into();
+
+        let mitmproxy = async {
+            let packet = PacketWithMeta {
+                data: Bytes::from_static(b"packet"),
+                tunnel_info: None,
+            };
+            packet_tx.send(packet.clone()).unwrap();
+            let frame = proxy.next().await.unwrap().unwrap();
+            assert_eq!(
+                decode(frame).message,
+                Some(from_redirector::Message::Packet(packet))
+            );
+
+            reply_tx
+                .send(from_redirector::Message::StatsResponse(
+                    StatsResponse::default(),
+                ))
+                .unwrap();
+            let frame = proxy.next().await.unwrap().unwrap();
+            assert!(matches!(
+                decode(frame).message,
+                Some(from_redirector::Message::StatsResponse(_))
+            ));
+
+            let messages = [
+                from_proxy::Message::InterceptConf(InterceptConf {
+                    actions: vec!["curl".to_string()],
+                }),
+                from_proxy::Message::Pong(Default::default()),
+                from_proxy::Message::AddInterceptPid(AddInterceptPid { pid: 1234 }),
+                from_proxy::Message::Shutdown(Default::default()),
+            ];
+            for message in messages {
+                proxy.send(encode(message)).await.unwrap();
+            }
+        };
+        let (result, ()) = tokio::join!(
+            handle_ipc(
+                redirector,
+                session.encoding,
+                Duration::from_secs(60),
+                &mut ipc_rx,
+                &tx,
+                &mut conf,
+                None,
+                &mut error_rx,
+                &mut reply_rx,
+            ),
+            mitmproxy
+        );
+        assert_eq!(result.unwrap(), Disconnect::Shutdown);
+
+        // Keepalive responses are not passed on.
+        assert!(matches!(
+            rx.recv().await,
+            Some(from_proxy::Message::InterceptConf(_))
+        ));
+        assert!(matches!(
+            rx.recv().await,
+            Some(from_proxy::Message::AddInterceptPid(_))
+        ));
+        assert!(matches!(
+            rx.recv().await,
+            Some(from_proxy::Message::Shutdown(_))
+        ));
+
+        // The incremental update is part of the config we replay after reconnecting.
+        let mut expected = intercept_conf::InterceptConf::try_from(vec!["curl"]).unwrap();
+        expected.add_intercept_pid(1234);
+        assert_eq!(conf.actions, expected.actions());
+    }
+
+    #[tokio::test]
+    async fn keepalive_timeout() {
+        let (redirector, proxy) = tokio::io::duplex(1024);
+        let mut proxy = Framed::new(proxy, codec());
+
+        let (_packet_tx, mut ipc_rx) = mpsc::unbounded_channel();
+        let (tx, _rx) = mpsc::channel::<from_proxy::Message>(16);
+        let (_error_tx, mut error_rx) = mpsc::unbounded_channel();
+        let (_reply_tx, mut reply_rx) = mpsc::unbounded_channel();
+        let mut conf = InterceptConf::default();
+
+        let (result, frame) = tokio::join!(
+            handle_ipc(
+                Framed::new(redirector, codec()),
+                Encoding::Protobuf { compression: false },
+                Duration::from_millis(20),
+                &mut ipc_rx,
+                &tx,
+                &mut conf,
+                None,
+                &mut error_rx,
+                &mut reply_rx,
+            ),
+            proxy.next()
+        );
+        // We do not respond to the ping.
+        assert_eq!(
+            decode(frame.unwrap().unwrap()).message,
+            Some(from_redirector::Message::Ping(Ping {}))
+        );
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn fatal_error() {
+        let (redirector, proxy) = tokio::io::duplex(1024);
+        let mut proxy = Framed::new(proxy, codec());
+
+        let (_packet_tx, mut ipc_rx) = mpsc::unbounded_channel();
+        let (tx, _rx) = mpsc::channel::<from_proxy::Message>(16);
+        let (error_tx, mut error_rx) = mpsc::unbounded_channel();
+        let (_reply_tx, mut reply_rx) = mpsc::unbounded_channel();
+        let mut conf = InterceptConf::default();
+
+        let error = Error {
+            code: 74,
+            message: "WinDivert Error".to_string(),
+        };
+        error_tx.send(error.clone()).unwrap();
+        let result = handle_ipc(
+            Framed::new(redirector, codec()),
+            Encoding::Protobuf { compression: false },
+            Duration::from_secs(60),
+            &mut ipc_rx,
+            &tx,
+            &mut conf,
+            None,
+            &mut error_rx,
+            &mut reply_rx,
+        )
+        .await;
+        assert_eq!(result.unwrap(), Disconnect::Fatal(74));
+        assert_eq!(
+            decode(proxy.next().await.unwrap().unwrap()).message,
+            Some(from_redirector::Message::Error(error))
+        );
+
+        // Closing the pipe is not an error.
+        drop(proxy);
+        let (redirector, proxy) = tokio::io::duplex(1024);
+        drop(proxy);
+        let result = handle_ipc(
+            Framed::new(redirector, codec()),
+            Encoding::Protobuf { compression: false },
+            Duration::from_secs(60),
+            &mut ipc_rx,
+            &tx,
+            &mut conf,
+            None,
+            &mut error_rx,
+            &mut reply_rx,
+        )
+        .await;
+        assert_eq!(result.unwrap(), Disconnect::Closed);
+    }
+}
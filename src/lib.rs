@@ -6,6 +6,7 @@ pub mod intercept_conf;
 pub mod ipc;
 pub mod messages;
 pub mod network;
+pub mod packet;
 pub mod packet_sources;
 pub mod processes;
 pub mod shutdown;
@@ -0,0 +1,271 @@
+use std::net::SocketAddr;
+
+use anyhow::{bail, ensure, Result};
+use internet_packet::{ConnectionId, InternetPacket, TransportProtocol};
+
+use super::tcp::TCP_MIN_HEADER_LEN;
+use super::{recompute_checksums, IPV4_MIN_HEADER_LEN, IPV6_HEADER_LEN};
+use crate::MAX_PACKET_SIZE;
+
+pub const TCP_FIN: u8 = 0x01;
+pub const TCP_SYN: u8 = 0x02;
+pub const TCP_RST: u8 = 0x04;
+pub const TCP_PSH: u8 = 0x08;
+pub const TCP_ACK: u8 = 0x10;
+
+const UDP_HEADER_LEN: usize = 8;
+
+/// Construct synthetic TCP or UDP packets with valid headers and checksums.
+#[derive(Debug, Clone)]
+pub struct InternetPacketBuilder {
+    src: SocketAddr,
+    dst: SocketAddr,
+    protocol: TransportProtocol,
+    tcp_flags: u8,
+    seq: u32,
+    ack: u32,
+    window: u16,
+    ttl: u8,
+    payload: Vec<u8>,
+}
+
+impl InternetPacketBuilder {
+    pub fn new(src: SocketAddr, dst: SocketAddr, protocol: TransportProtocol) -> Self {
+        Self {
+            src,
+            dst,
+            protocol,
+            tcp_flags: 0,
+            seq: 0,
+            ack: 0,
+            window: u16::MAX,
+            ttl: 64,
+            payload: Vec::new(),
+        }
+    }
+
+    /// A bitmask of `TCP_*` flags. Ignored for UDP.
+    pub fn tcp_flags(mut self, flags: u8) -> Self {
+        self.tcp_flags = flags;
+        self
+    }
+
+    pub fn seq(mut self, seq: u32) -> Self {
+        self.seq = seq;
+        self
+    }
+
+    pub fn ack(mut self, ack: u32) -> Self {
+        self.ack = ack;
+        self
+    }
+
+    pub fn window(mut self, window: u16) -> Self {
+        self.window = window;
+        self
+    }
+
+    /// The IPv4 TTL or IPv6 hop limit.
+    pub fn ttl(mut self, ttl: u8) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    pub fn payload(mut self, payload: impl Into<Vec<u8>>) -> Self {
+        self.payload = payload.into();
+        self
+    }
+
+    /// Serialize the packet into raw IP packet bytes.
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let (proto, transport_header_len) = match self.protocol {
+            TransportProtocol::Tcp => (6, TCP_MIN_HEADER_LEN),
+            TransportProtocol::Udp => (17, UDP_HEADER_LEN),
+        };
+        let transport_len = transport_header_len + self.payload.len();
+        let ip_header_len = match (self.src, self.dst) {
+            (SocketAddr::V4(_), SocketAddr::V4(_)) => IPV4_MIN_HEADER_LEN,
+            (SocketAddr::V6(_), SocketAddr::V6(_)) => IPV6_HEADER_LEN,
+            _ => bail!("address family mismatch: {} -> {}", self.src, self.dst),
+        };
+        let total_len = ip_header_len + transport_len;
+        ensure!(
+            total_len <= MAX_PACKET_SIZE,
+            "packet too large: {} bytes",
+            total_len
+        );
+
+        let mut data = Vec::with_capacity(total_len);
+        match (self.src, self.dst) {
+            (SocketAddr::V4(src), SocketAddr::V4(dst)) => {
+                data.extend_from_slice(&[0x45, 0]);
+                data.extend_from_slice(&(total_len as u16).to_be_bytes());
+                // identification, don't fragment
+                data.extend_from_slice(&[0, 0, 0x40, 0]);
+                data.extend_from_slice(&[self.ttl, proto, 0, 0]);
+                data.extend_from_slice(&src.ip().octets());
+                data.extend_from_slice(&dst.ip().octets());
+            }
+            (SocketAddr::V6(src), SocketAddr::V6(dst)) => {
+                data.extend_from_slice(&[0x60, 0, 0, 0]);
+                data.extend_from_slice(&(transport_len as u16).to_be_bytes());
+                data.extend_from_slice(&[proto, self.ttl]);
+                data.extend_from_slice(&src.ip().octets());
+                data.extend_from_slice(&dst.ip().octets());
+            }
+            _ => unreachable!(),
+        }
+
+        data.extend_from_slice(&self.src.port().to_be_bytes());
+        data.extend_from_slice(&self.dst.port().to_be_bytes());
+        match self.protocol {
+            TransportProtocol::Tcp => {
+                data.extend_from_slice(&self.seq.to_be_bytes());
+                data.extend_from_slice(&self.ack.to_be_bytes());
+                data.extend_from_slice(&[(TCP_MIN_HEADER_LEN as u8 / 4) << 4, self.tcp_flags]);
+                data.extend_from_slice(&self.window.to_be_bytes());
+                // checksum, urgent pointer
+                data.extend_from_slice(&[0, 0, 0, 0]);
+            }
+            TransportProtocol::Udp => {
+                data.extend_from_slice(&(transport_len as u16).to_be_bytes());
+                data.extend_from_slice(&[0, 0]);
+            }
+        }
+        data.extend_from_slice(&self.payload);
+
+        recompute_checksums(&mut data)?;
+        Ok(data)
+    }
+
+    pub fn build(&self) -> Result<InternetPacket> {
+        InternetPacket::try_from(self.to_bytes()?)
+    }
+}
+
+/// Build RST packets that tear down a TCP connection in both directions.
+///
+/// `seq` is the next sequence number in the direction of `connection_id`, and `ack` the next
+/// sequence number in the opposite direction. The first packet is addressed to
+/// `connection_id.dst`, the second one to `connection_id.src`.
+pub fn tcp_reset(connection_id: ConnectionId, seq: u32, ack: u32) -> Result<[Vec<u8>; 2]> {
+    ensure!(
+        connection_id.proto == TransportProtocol::Tcp,
+        "cannot reset a non-TCP connection"
+    );
+    let forward =
+        InternetPacketBuilder::new(connection_id.src, connection_id.dst, TransportProtocol::Tcp)
+            .tcp_flags(TCP_RST | TCP_ACK)
+            .seq(seq)
+            .ack(ack)
+            .window(0);
+    let reverse =
+        InternetPacketBuilder::new(connection_id.dst, connection_id.src, TransportProtocol::Tcp)
+            .tcp_flags(TCP_RST | TCP_ACK)
+            .seq(ack)
+            .ack(seq)
+            .window(0);
+    Ok([forward.to_bytes()?, reverse.to_bytes()?])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::tests::{hex, TCP_V4_SYN, TCP_V6_SYN, UDP_V6};
+    use crate::packet::{tcp_ack, tcp_seq, validate_checksums, IpHeader};
+
+    #[test]
+    fn build_tcp() {
+        let data = InternetPacketBuilder::new(
+            "10.0.0.1:50000".parse().unwrap(),
+            "93.184.216.34:80".parse().unwrap(),
+            TransportProtocol::Tcp,
+        )
+        .tcp_flags(TCP_SYN)
+        .seq(1)
+        .to_bytes()
+        .unwrap();
+        // The builder does not set an IP identification.
+        let mut expected = hex(TCP_V4_SYN);
+        expected[4..6].copy_from_slice(&[0, 0]);
+        recompute_checksums(&mut expected).unwrap();
+        assert_eq!(data, expected);
+
+        let data = InternetPacketBuilder::new(
+            "[2001:db8::1]:50000".parse().unwrap(),
+            "[2001:db8::2]:443".parse().unwrap(),
+            TransportProtocol::Tcp,
+        )
+        .tcp_flags(TCP_SYN)
+        .seq(1)
+        .to_bytes()
+        .unwrap();
+        assert_eq!(data, hex(TCP_V6_SYN));
+    }
+
+    #[test]
+    fn build_udp() {
+        let builder = InternetPacketBuilder::new(
+            "[2001:db8::1]:1234".parse().unwrap(),
+            "[2001:db8::2]:53".parse().unwrap(),
+            TransportProtocol::Udp,
+        )
+        .payload(b"abcd".as_slice());
+        assert_eq!(builder.to_bytes().unwrap(), hex(UDP_V6));
+
+        let packet = builder.build().unwrap();
+        assert_eq!(packet.src(), "[2001:db8::1]:1234".parse().unwrap());
+        assert_eq!(packet.payload(), b"abcd");
+    }
+
+    #[test]
+    fn build_reset() {
+        let connection_id = ConnectionId {
+            proto: TransportProtocol::Tcp,
+            src: "10.0.0.1:50000".parse().unwrap(),
+            dst: "93.184.216.34:80".parse().unwrap(),
+        };
+        let [forward, reverse] = tcp_reset(connection_id, 1000, 2000).unwrap();
+        let forward_ip = IpHeader::parse(&forward).unwrap();
+        let reverse_ip = IpHeader::parse(&reverse).unwrap();
+        assert_eq!(forward_ip.dst_ip, connection_id.dst.ip());
+        assert_eq!(reverse_ip.dst_ip, connection_id.src.ip());
+        assert_eq!(forward[22..24], 80u16.to_be_bytes());
+        assert_eq!(reverse[22..24], 50000u16.to_be_bytes());
+        assert_eq!(tcp_seq(&forward).unwrap(), 1000);
+        assert_eq!(tcp_ack(&forward).unwrap(), 2000);
+        assert_eq!(tcp_seq(&reverse).unwrap(), 2000);
+        assert_eq!(tcp_ack(&reverse).unwrap(), 1000);
+        for data in [&forward, &reverse] {
+            assert_eq!(data[33], TCP_RST | TCP_ACK);
+            assert!(validate_checksums(data));
+        }
+
+        let udp = ConnectionId {
+            proto: TransportProtocol::Udp,
+            ..connection_id
+        };
+        assert!(tcp_reset(udp, 0, 0).is_err());
+    }
+
+    #[test]
+    fn build_invalid() {
+        let builder = InternetPacketBuilder::new(
+            "10.0.0.1:1234".parse().unwrap(),
+            "[2001:db8::2]:53".parse().unwrap(),
+            TransportProtocol::Udp,
+        );
+        assert!(builder.to_bytes().is_err());
+
+        let builder = InternetPacketBuilder::new(
+            "10.0.0.1:1234".parse().unwrap(),
+            "10.0.0.2:53".parse().unwrap(),
+            TransportProtocol::Udp,
+        )
+        .payload(vec![0; MAX_PACKET_SIZE]);
+        assert!(builder.to_bytes().is_err());
+        assert!(validate_checksums(
+            &builder.payload(b"x".as_slice()).to_bytes().unwrap()
+        ));
+    }
+}
@@ -0,0 +1,191 @@
+use std::net::IpAddr;
+
+use anyhow::{ensure, Result};
+
+use super::{locate_transport_in_fragment, IpHeader, IpVersion};
+
+/// Add up `data` as big-endian 16-bit words (RFC 1071).
+fn sum(data: &[u8], mut acc: u32) -> u32 {
+    let mut chunks = data.chunks_exact(2);
+    for chunk in &mut chunks {
+        acc += u16::from_be_bytes([chunk[0], chunk[1]]) as u32;
+    }
+    if let [last] = chunks.remainder() {
+        acc += (*last as u32) << 8;
+    }
+    acc
+}
+
+fn fold(mut acc: u32) -> u16 {
+    while acc > 0xffff {
+        acc = (acc & 0xffff) + (acc >> 16);
+    }
+    !(acc as u16)
+}
+
+fn pseudo_header_sum(ip: &IpHeader, proto: u8, len: usize) -> u32 {
+    let mut acc = match (ip.src_ip, ip.dst_ip) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => sum(&dst.octets(), sum(&src.octets(), 0)),
+        (IpAddr::V6(src), IpAddr::V6(dst)) => sum(&dst.octets(), sum(&src.octets(), 0)),
+        _ => unreachable!(),
+    };
+    acc += proto as u32;
+    acc += (len as u32) >> 16;
+    acc += len as u32 & 0xffff;
+    acc
+}
+
+/// Offset of the checksum field within the transport header,
+/// and whether the checksum covers a pseudo-header.
+fn checksum_field(version: IpVersion, proto: u8) -> Option<(usize, bool)> {
+    match (version, proto) {
+        (_, 6) => Some((16, true)),
+        (_, 17) => Some((6, true)),
+        (IpVersion::V4, 1) => Some((2, false)),
+        (IpVersion::V6, 58) => Some((2, true)),
+        _ => None,
+    }
+}
+
+/// Compute the transport layer checksum of a packet, treating the checksum field as zero.
+/// Returns the transport protocol, the checksum field offset, and the computed checksum.
+///
+/// The checksum of a fragmented datagram covers data in other packets, so first fragments
+/// have none we could compute.
+fn transport_checksum(data: &[u8]) -> Result<Option<(u8, usize, u16)>> {
+    let ip = IpHeader::parse(data)?;
+    let (proto, offset, fragment) = locate_transport_in_fragment(data)?;
+    if fragment {
+        return Ok(None);
+    }
+    let Some((field, pseudo_header)) = checksum_field(ip.version, proto) else {
+        return Ok(None);
+    };
+    let segment = &data[offset..ip.total_len];
+    ensure!(segment.len() >= field + 2, "truncated transport header");
+
+    let acc = if pseudo_header {
+        pseudo_header_sum(&ip, proto, segment.len())
+    } else {
+        0
+    };
+    let acc = sum(&segment[..field], acc);
+    let acc = sum(&segment[field + 2..], acc);
+    let mut checksum = fold(acc);
+    if proto == 17 && checksum == 0 {
+        // A zero UDP checksum means "no checksum", so it is transmitted as all ones.
+        checksum = 0xffff;
+    }
+    Ok(Some((proto, offset + field, checksum)))
+}
+
+pub(super) fn ipv4_header_checksum(data: &[u8], header_len: usize) -> u16 {
+    fold(sum(&data[12..header_len], sum(&data[..10], 0)))
+}
+
+/// Recalculate the IPv4 header checksum and the TCP/UDP/ICMP checksum of a packet.
+pub fn recompute_checksums(data: &mut [u8]) -> Result<()> {
+    let ip = IpHeader::parse(data)?;
+    if ip.version == IpVersion::V4 {
+        let checksum = ipv4_header_checksum(data, ip.header_len);
+        data[10..12].copy_from_slice(&checksum.to_be_bytes());
+    }
+    if let Some((_, field, checksum)) = transport_checksum(data)? {
+        data[field..field + 2].copy_from_slice(&checksum.to_be_bytes());
+    }
+    Ok(())
+}
+
+/// Verify the IPv4 header checksum and the TCP/UDP/ICMP checksum of a packet.
+/// For first fragments, only the IPv4 header checksum is verified.
+pub fn validate_checksums(data: &[u8]) -> bool {
+    let Ok(ip) = IpHeader::parse(data) else {
+        return false;
+    };
+    if ip.version == IpVersion::V4
+        && ipv4_header_checksum(data, ip.header_len) != u16::from_be_bytes([data[10], data[11]])
+    {
+        return false;
+    }
+    match transport_checksum(data) {
+        Ok(Some((proto, field, checksum))) => {
+            let actual = u16::from_be_bytes([data[field], data[field + 1]]);
+            // UDP checksums are optional for IPv4 (but not for IPv6).
+            (proto == 17 && ip.version == IpVersion::V4 && actual == 0) || actual == checksum
+        }
+        Ok(None) => true,
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::tests::{hex, TCP_V4_SYN, TCP_V6_SYN, UDP_V4, UDP_V6};
+
+    #[test]
+    fn recompute() {
+        for (fixture, field) in [
+            (TCP_V4_SYN, 36),
+            (TCP_V6_SYN, 56),
+            (UDP_V4, 26),
+            (UDP_V6, 46),
+            // ICMP and ICMPv6 echo
+            (
+                b"4500002012344000400114a70a0000010a000002080006fa1234000170696e67".as_slice(),
+                22,
+            ),
+            (b"60000000000c3a4020010db800000000000000000000000220010db80000000000000000000000018100323e1234000170696e67".as_slice(), 42),
+        ] {
+            let expected = hex(fixture);
+            let mut data = expected.clone();
+            data[field] ^= 0xff;
+            if data[0] >> 4 == 4 {
+                data[10] ^= 0xff;
+            }
+            recompute_checksums(&mut data).unwrap();
+            assert_eq!(data, expected);
+        }
+    }
+
+    #[test]
+    fn validate() {
+        for fixture in [TCP_V4_SYN, TCP_V6_SYN, UDP_V4, UDP_V6] {
+            let mut data = hex(fixture);
+            assert!(validate_checksums(&data));
+            let last = data.len() - 1;
+            data[last] ^= 0xff;
+            assert!(!validate_checksums(&data));
+        }
+
+        let mut data = hex(TCP_V4_SYN);
+        data[10] ^= 0xff;
+        assert!(!validate_checksums(&data));
+
+        // UDP without checksum
+        let mut data = hex(UDP_V4);
+        data[26..28].copy_from_slice(&[0, 0]);
+        assert!(validate_checksums(&data));
+        let mut data = hex(UDP_V6);
+        data[46..48].copy_from_slice(&[0, 0]);
+        assert!(!validate_checksums(&data));
+
+        // First fragments only carry part of the segment the checksum covers.
+        let mut data = hex(UDP_V4);
+        data[6] |= 0x20;
+        let checksum = ipv4_header_checksum(&data, 20);
+        data[10..12].copy_from_slice(&checksum.to_be_bytes());
+        let last = data.len() - 1;
+        data[last] ^= 0xff;
+        assert!(validate_checksums(&data));
+        data[10] ^= 0xff;
+        assert!(!validate_checksums(&data));
+        let data = hex(b"60000000001c2c4020010db800000000000000000000000120010db80000000000000000000000020600000100000001c35001bb00000001000000005002ffff00000000");
+        assert!(validate_checksums(&data));
+    }
+
+    #[test]
+    fn recompute_invalid() {
+        assert!(recompute_checksums(&mut hex(TCP_V4_SYN)[..30]).is_err());
+    }
+}
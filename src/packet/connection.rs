@@ -0,0 +1,252 @@
+use std::fmt;
+use std::net::SocketAddr;
+
+use internet_packet::{ConnectionId, TransportProtocol};
+use serde::{Deserialize, Serialize};
+
+use super::{unmap_ipv4, with_scope_id, IpProtocol};
+
+/// A direction-independent connection key.
+///
+/// Both directions of a connection map to the same key, with the endpoints in ascending order.
+/// Only link-local IPv6 addresses keep their scope id, see [`with_scope_id`], and IPv4-mapped
+/// IPv6 addresses are turned into IPv4 addresses, see [`unmap_ipv4`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct CanonicalConnectionId {
+    pub proto: TransportProtocol,
+    pub a: SocketAddr,
+    pub b: SocketAddr,
+}
+
+/// The direction of a [`ConnectionId`] relative to its [`CanonicalConnectionId`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// `src` is `a` and `dst` is `b`.
+    Forward,
+    /// `src` is `b` and `dst` is `a`.
+    Reverse,
+}
+
+pub trait ConnectionIdExt {
+    fn canonical(&self) -> (CanonicalConnectionId, Direction);
+    /// Set the scope id of link-local IPv6 endpoints, e.g. to the interface a packet was seen on.
+    fn scoped(&self, scope_id: u32) -> ConnectionId;
+}
+
+impl ConnectionIdExt for ConnectionId {
+    fn canonical(&self) -> (CanonicalConnectionId, Direction) {
+        let src = unmap_ipv4(normalize_scope_id(self.src));
+        let dst = unmap_ipv4(normalize_scope_id(self.dst));
+        if src <= dst {
+            (
+                CanonicalConnectionId {
+                    proto: self.proto,
+                    a: src,
+                    b: dst,
+                },
+                Direction::Forward,
+            )
+        } else {
+            (
+                CanonicalConnectionId {
+                    proto: self.proto,
+                    a: dst,
+                    b: src,
+                },
+                Direction::Reverse,
+            )
+        }
+    }
+
+    fn scoped(&self, scope_id: u32) -> ConnectionId {
+        ConnectionId {
+            proto: self.proto,
+            src: with_scope_id(self.src, scope_id),
+            dst: with_scope_id(self.dst, scope_id),
+        }
+    }
+}
+
+fn normalize_scope_id(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V4(_) => addr,
+        SocketAddr::V6(v6) => with_scope_id(addr, v6.scope_id()),
+    }
+}
+
+impl CanonicalConnectionId {
+    /// Restore the directed connection id.
+    pub fn directed(&self, direction: Direction) -> ConnectionId {
+        match direction {
+            Direction::Forward => ConnectionId {
+                proto: self.proto,
+                src: self.a,
+                dst: self.b,
+            },
+            Direction::Reverse => ConnectionId {
+                proto: self.proto,
+                src: self.b,
+                dst: self.a,
+            },
+        }
+    }
+}
+
+impl fmt::Display for CanonicalConnectionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} <-> {}",
+            IpProtocol::from(self.proto),
+            self.a,
+            self.b
+        )
+    }
+}
+
+/// Serde support for [`TransportProtocol`], for use with `#[serde(with = "TransportProtocolDef")]`.
+#[derive(Serialize, Deserialize)]
+#[serde(remote = "TransportProtocol", rename_all = "lowercase")]
+pub enum TransportProtocolDef {
+    Tcp,
+    Udp,
+}
+
+/// Serde support for [`ConnectionId`], for use with `#[serde(with = "ConnectionIdDef")]`.
+#[derive(Serialize, Deserialize)]
+#[serde(remote = "ConnectionId")]
+pub struct ConnectionIdDef {
+    #[serde(with = "TransportProtocolDef")]
+    pub proto: TransportProtocol,
+    #[serde(with = "socket_addr")]
+    pub src: SocketAddr,
+    #[serde(with = "socket_addr")]
+    pub dst: SocketAddr,
+}
+
+/// Socket addresses are always (de)serialized as strings.
+/// Serde's default drops IPv6 scope ids for non-human-readable formats.
+mod socket_addr {
+    use std::net::SocketAddr;
+
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(addr: &SocketAddr, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(addr)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<SocketAddr, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn canonical() {
+        let id = ConnectionId {
+            proto: TransportProtocol::Tcp,
+            src: "10.0.0.1:50000".parse().unwrap(),
+            dst: "93.184.216.34:80".parse().unwrap(),
+        };
+        let (key, direction) = id.canonical();
+        let (reverse_key, reverse_direction) = id.reverse().canonical();
+        assert_eq!(key, reverse_key);
+        assert_ne!(direction, reverse_direction);
+        assert_eq!(key.directed(direction), id);
+        assert_eq!(key.directed(reverse_direction), id.reverse());
+
+        let udp = ConnectionId {
+            proto: TransportProtocol::Udp,
+            ..id
+        };
+        assert_ne!(udp.canonical().0, key);
+        assert_eq!(key.to_string(), "TCP 10.0.0.1:50000 <-> 93.184.216.34:80");
+    }
+
+    #[test]
+    fn canonical_scope_id() {
+        let id = ConnectionId {
+            proto: TransportProtocol::Udp,
+            src: "[fe80::1]:5353".parse().unwrap(),
+            dst: "[fe80::2]:5353".parse().unwrap(),
+        };
+        let (key, direction) = id.scoped(7).canonical();
+        assert_eq!(key.a, "[fe80::1%7]:5353".parse().unwrap());
+        assert_eq!(key.b, "[fe80::2%7]:5353".parse().unwrap());
+        assert_eq!(direction, id.canonical().1);
+        assert_eq!(id.reverse().scoped(7).canonical().0, key);
+
+        // The same addresses on another interface are a different connection.
+        assert_ne!(id.scoped(8).canonical().0, key);
+        assert_ne!(id.canonical().0, key);
+
+        // Other addresses are unique without a scope id.
+        let global = ConnectionId {
+            proto: TransportProtocol::Udp,
+            src: "[2001:db8::1]:5353".parse().unwrap(),
+            dst: "[2001:db8::2]:5353".parse().unwrap(),
+        };
+        assert_eq!(global.scoped(7), global);
+        let stray_scope = ConnectionId {
+            src: "[2001:db8::1%7]:5353".parse().unwrap(),
+            ..global
+        };
+        assert_eq!(stray_scope.canonical(), global.canonical());
+    }
+
+    #[test]
+    fn canonical_ipv4_mapped() {
+        // A dual-stack socket listening on [::]:8080 accepts a connection from an IPv4 client.
+        let accept = ConnectionId {
+            proto: TransportProtocol::Tcp,
+            src: "[::ffff:10.0.0.2]:8080".parse().unwrap(),
+            dst: "[::ffff:10.0.0.1]:50000".parse().unwrap(),
+        };
+        // The client's SYN is an IPv4 packet.
+        let syn = ConnectionId {
+            proto: TransportProtocol::Tcp,
+            src: "10.0.0.1:50000".parse().unwrap(),
+            dst: "10.0.0.2:8080".parse().unwrap(),
+        };
+        let (key, direction) = accept.canonical();
+        assert_eq!(key, syn.canonical().0);
+        assert_eq!(key.directed(direction), syn.reverse());
+
+        // Other IPv6 addresses are left alone.
+        let v6 = ConnectionId {
+            src: "[::1]:8080".parse().unwrap(),
+            dst: "[64:ff9b::a00:1]:50000".parse().unwrap(),
+            ..accept
+        };
+        assert_eq!(v6.canonical().0.a, v6.src);
+        assert_eq!(v6.canonical().0.b, v6.dst);
+    }
+
+    #[test]
+    fn serde_roundtrip() {
+        #[derive(Serialize, Deserialize)]
+        struct Wrapper(#[serde(with = "ConnectionIdDef")] ConnectionId);
+
+        let id = ConnectionId {
+            proto: TransportProtocol::Udp,
+            src: "[fe80::1%7]:5353".parse().unwrap(),
+            dst: "10.0.0.2:53".parse().unwrap(),
+        };
+        let json = serde_json::to_string(&Wrapper(id)).unwrap();
+        assert_eq!(
+            json,
+            r#"{"proto":"udp","src":"[fe80::1%7]:5353","dst":"10.0.0.2:53"}"#
+        );
+        let Wrapper(parsed) = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed, id);
+        match parsed.src {
+            SocketAddr::V6(addr) => assert_eq!(addr.scope_id(), 7),
+            SocketAddr::V4(_) => unreachable!(),
+        }
+    }
+}
@@ -0,0 +1,103 @@
+const DNS_HEADER_LEN: usize = 12;
+/// Compression pointers are not expected in the question section, but we follow a few of
+/// them to be lenient. The limit guards against pointer loops in malformed messages.
+const MAX_POINTERS: usize = 8;
+const MAX_NAME_LEN: usize = 255;
+
+/// Extract the name of the first question from a DNS message (e.g. a UDP payload).
+///
+/// Returns `None` for responses, messages without questions, and malformed names.
+pub fn dns_qname(payload: &[u8]) -> Option<String> {
+    if payload.len() < DNS_HEADER_LEN {
+        return None;
+    }
+    let is_response = payload[2] & 0x80 != 0;
+    let question_count = u16::from_be_bytes([payload[4], payload[5]]);
+    if is_response || question_count == 0 {
+        return None;
+    }
+
+    let mut labels: Vec<&str> = Vec::new();
+    let mut name_len = 0;
+    let mut offset = DNS_HEADER_LEN;
+    let mut pointers = 0;
+    loop {
+        let len = *payload.get(offset)? as usize;
+        match len & 0xc0 {
+            0x00 if len == 0 => break,
+            0x00 => {
+                let label = payload.get(offset + 1..offset + 1 + len)?;
+                name_len += len + 1;
+                if name_len > MAX_NAME_LEN {
+                    return None;
+                }
+                labels.push(std::str::from_utf8(label).ok()?);
+                offset += 1 + len;
+            }
+            0xc0 => {
+                pointers += 1;
+                let target = u16::from_be_bytes([len as u8, *payload.get(offset + 1)?]) & 0x3fff;
+                // Pointers must point backwards, and their number is limited to break loops.
+                if pointers > MAX_POINTERS || target as usize >= offset {
+                    return None;
+                }
+                offset = target as usize;
+            }
+            // 0x40 and 0x80 are reserved label types.
+            _ => return None,
+        }
+    }
+    if labels.is_empty() {
+        // the root domain
+        return Some(".".to_string());
+    }
+    Some(labels.join("."))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::tests::hex;
+
+    /// A query for example.com A
+    const QUERY: &[u8] = b"123401000001000000000000076578616d706c6503636f6d0000010001";
+
+    #[test]
+    fn parse_qname() {
+        let query = hex(QUERY);
+        assert_eq!(dns_qname(&query), Some("example.com".to_string()));
+
+        // root
+        let mut root = query[..12].to_vec();
+        root.extend_from_slice(&[0, 0, 1, 0, 1]);
+        assert_eq!(dns_qname(&root), Some(".".to_string()));
+
+        // response
+        let mut response = query.clone();
+        response[2] |= 0x80;
+        assert_eq!(dns_qname(&response), None);
+    }
+
+    #[test]
+    fn parse_invalid() {
+        let query = hex(QUERY);
+        for i in 0..query.len() - 4 {
+            assert_eq!(dns_qname(&query[..i]), None);
+        }
+
+        // pointer to itself
+        let mut data = query[..12].to_vec();
+        data.extend_from_slice(&[0xc0, 0x0c]);
+        assert_eq!(dns_qname(&data), None);
+
+        // pointer loop
+        let mut data = query[..12].to_vec();
+        data.extend_from_slice(&[0x01, b'a', 0xc0, 0x0c]);
+        assert_eq!(dns_qname(&data), None);
+
+        // reserved label type
+        let mut data = query[..12].to_vec();
+        data.extend_from_slice(&[0x40, 0x00]);
+        assert_eq!(dns_qname(&data), None);
+    }
+}
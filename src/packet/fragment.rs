@@ -0,0 +1,479 @@
+use std::net::Ipv4Addr;
+use std::ops::Range;
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use lru_time_cache::LruCache;
+
+use super::checksum::ipv4_header_checksum;
+use super::{ecn, Ecn, IpHeader, IpVersion};
+use crate::MAX_PACKET_SIZE;
+
+const IPV4_MORE_FRAGMENTS: u16 = 0x2000;
+const IPV4_FRAGMENT_OFFSET: u16 = 0x1fff;
+
+/// The maximum number of incomplete datagrams we keep fragments for.
+const MAX_FRAGMENT_SETS: usize = 256;
+/// The maximum number of fragments per datagram,
+/// enough for a datagram of maximum size at the minimum MTU of 576 bytes.
+const MAX_FRAGMENTS: usize = 128;
+/// The maximum number of payload bytes we buffer per datagram.
+/// Overlapping fragments could otherwise add up to much more than the datagram itself.
+const MAX_SET_BYTES: usize = 64 * 1024;
+/// The maximum number of payload bytes we buffer across all incomplete datagrams.
+const MAX_BUFFERED_BYTES: usize = 4 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+struct FragmentKey {
+    src: Ipv4Addr,
+    dst: Ipv4Addr,
+    id: u16,
+    proto: u8,
+}
+
+#[derive(Debug)]
+struct Fragment<T> {
+    /// The offset of the payload in the reassembled datagram.
+    offset: usize,
+    /// The payload's position in `data`.
+    payload: Range<usize>,
+    meta: T,
+    data: Vec<u8>,
+}
+
+impl<T> Fragment<T> {
+    fn end(&self) -> usize {
+        self.offset + self.payload.len()
+    }
+}
+
+#[derive(Debug)]
+struct FragmentSet<T> {
+    /// The length of the reassembled payload, known once we have seen the last fragment.
+    payload_len: Option<usize>,
+    /// All fragments received so far.
+    fragments: Vec<Fragment<T>>,
+    /// Whether any fragment was marked as congestion experienced.
+    congestion: bool,
+    /// The number of payload bytes in `fragments`.
+    buffered: usize,
+}
+
+impl<T> FragmentSet<T> {
+    fn new() -> Self {
+        Self {
+            payload_len: None,
+            fragments: Vec::new(),
+            congestion: false,
+            buffered: 0,
+        }
+    }
+
+    fn is_complete(&mut self) -> bool {
+        let Some(payload_len) = self.payload_len else {
+            return false;
+        };
+        self.fragments.sort_by_key(|f| f.offset);
+        let mut covered = 0;
+        for fragment in &self.fragments {
+            if fragment.offset > covered {
+                return false;
+            }
+            covered = covered.max(fragment.end());
+        }
+        covered >= payload_len
+    }
+
+    fn reassemble(self) -> Reassembled<T> {
+        // Fragments are sorted by offset, so the first one carries the header we reuse.
+        let first = &self.fragments[0];
+        let header_len = first.payload.start;
+        let mut data = first.data[..header_len].to_vec();
+        data.resize(header_len + self.payload_len.unwrap(), 0);
+        for f in &self.fragments {
+            data[header_len + f.offset..header_len + f.end()]
+                .copy_from_slice(&f.data[f.payload.clone()]);
+        }
+
+        let total_len = data.len() as u16;
+        data[2..4].copy_from_slice(&total_len.to_be_bytes());
+        // Clear MF and the fragment offset, but keep DF.
+        data[6] &= 0x40;
+        data[7] = 0;
+        // A congestion mark on any fragment applies to the whole datagram (RFC 3168, 5.3).
+        if self.congestion && matches!(Ecn::from_bits(data[1]), Ecn::Ect0 | Ecn::Ect1) {
+            data[1] |= Ecn::Ce.bits();
+        }
+        let checksum = ipv4_header_checksum(&data, header_len);
+        data[10..12].copy_from_slice(&checksum.to_be_bytes());
+
+        Reassembled::Datagram {
+            data,
+            fragments: self
+                .fragments
+                .into_iter()
+                .map(|f| (f.meta, f.data))
+                .collect(),
+        }
+    }
+}
+
+/// A packet returned by [`FragmentReassembler::process`].
+#[derive(Debug, PartialEq, Eq)]
+pub enum Reassembled<T> {
+    /// A packet that is not a fragment, returned as-is.
+    Packet(T, Vec<u8>),
+    /// A reassembled datagram, and the fragments it was reassembled from ordered by offset.
+    Datagram {
+        data: Vec<u8>,
+        fragments: Vec<(T, Vec<u8>)>,
+    },
+}
+
+/// Reassemble fragmented IPv4 datagrams.
+///
+/// Each packet comes with metadata of type `T` (e.g. its capture address), which is handed back
+/// together with the original fragments so that they can be forwarded unmodified.
+/// Incomplete fragment sets expire after the configured timeout.
+pub struct FragmentReassembler<T> {
+    fragments: LruCache<FragmentKey, FragmentSet<T>>,
+}
+
+impl<T> FragmentReassembler<T> {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            fragments: LruCache::with_expiry_duration_and_capacity(timeout, MAX_FRAGMENT_SETS),
+        }
+    }
+
+    /// Process a packet.
+    ///
+    /// Packets that are not IPv4 fragments are returned as-is. Fragments are buffered until
+    /// the datagram is complete, at which point the reassembled datagram is returned.
+    /// Duplicate fragments are dropped. Fragments that are inconsistent with the rest of their
+    /// set, e.g. because they extend past the end of the datagram, are an error and discard the
+    /// whole set. So are fragments that would exceed the per-datagram buffer limit. Fragments
+    /// that would exceed the overall buffer limit are an error as well, but only they are dropped.
+    pub fn process(&mut self, data: Vec<u8>, meta: T) -> Result<Option<Reassembled<T>>> {
+        let ip = match IpHeader::parse(&data) {
+            Ok(ip) if ip.version == IpVersion::V4 => ip,
+            _ => return Ok(Some(Reassembled::Packet(meta, data))),
+        };
+        let flags = u16::from_be_bytes([data[6], data[7]]);
+        let more_fragments = flags & IPV4_MORE_FRAGMENTS != 0;
+        let offset = (flags & IPV4_FRAGMENT_OFFSET) as usize * 8;
+        if !more_fragments && offset == 0 {
+            return Ok(Some(Reassembled::Packet(meta, data)));
+        }
+
+        let key = FragmentKey {
+            src: Ipv4Addr::new(data[12], data[13], data[14], data[15]),
+            dst: Ipv4Addr::new(data[16], data[17], data[18], data[19]),
+            id: u16::from_be_bytes([data[4], data[5]]),
+            proto: ip.next_header,
+        };
+        let fragment = Fragment {
+            offset,
+            payload: ip.header_len..ip.total_len,
+            meta,
+            data,
+        };
+        let end = fragment.end();
+        if ip.header_len + end > MAX_PACKET_SIZE {
+            self.fragments.remove(&key);
+            bail!("reassembled datagram exceeds maximum packet size");
+        }
+
+        // `get` drops the entry if it has expired.
+        if self.fragments.get(&key).is_none() {
+            self.fragments.insert(key, FragmentSet::new());
+        }
+        let buffered: usize = self.fragments.peek_iter().map(|(_, s)| s.buffered).sum();
+        let set = self.fragments.get_mut(&key).unwrap();
+        if set
+            .fragments
+            .iter()
+            .any(|f| f.offset == offset && f.end() == end)
+        {
+            return Ok(None);
+        }
+        let inconsistent = match set.payload_len {
+            Some(payload_len) => end > payload_len || (!more_fragments && end != payload_len),
+            None => !more_fragments && set.fragments.iter().any(|f| f.end() > end),
+        };
+        if inconsistent {
+            self.fragments.remove(&key);
+            bail!("fragment overlaps the end of the datagram");
+        }
+        if set.fragments.len() >= MAX_FRAGMENTS {
+            self.fragments.remove(&key);
+            bail!("too many fragments");
+        }
+        let len = fragment.payload.len();
+        if set.buffered + len > MAX_SET_BYTES {
+            self.fragments.remove(&key);
+            bail!("too many overlapping fragments");
+        }
+        if buffered + len > MAX_BUFFERED_BYTES {
+            bail!("too many fragments buffered");
+        }
+        if !more_fragments {
+            set.payload_len = Some(end);
+        }
+        set.congestion |= ecn(&fragment.data)? == Ecn::Ce;
+        set.buffered += len;
+        set.fragments.push(fragment);
+
+        if set.is_complete() {
+            Ok(self.fragments.remove(&key).map(FragmentSet::reassemble))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::tests::{hex, UDP_V4, UDP_V6};
+    use crate::packet::validate_checksums;
+
+    /// Cut an IPv4 fragment with the given payload offset and length out of a packet.
+    fn fragment_at(data: &[u8], offset: usize, len: usize, more: bool) -> Vec<u8> {
+        let mut frag = data[..20].to_vec();
+        frag.extend_from_slice(&data[20 + offset..20 + offset + len]);
+        let total_len = frag.len() as u16;
+        frag[2..4].copy_from_slice(&total_len.to_be_bytes());
+        let mut flags = (offset / 8) as u16;
+        if more {
+            flags |= IPV4_MORE_FRAGMENTS;
+        }
+        frag[6..8].copy_from_slice(&flags.to_be_bytes());
+        frag
+    }
+
+    /// Split a packet into IPv4 fragments with the given payload offsets.
+    fn fragment(data: &[u8], offsets: &[usize]) -> Vec<Vec<u8>> {
+        let payload_len = data.len() - 20;
+        let mut bounds = offsets.to_vec();
+        bounds.push(payload_len);
+        bounds
+            .windows(2)
+            .map(|w| fragment_at(data, w[0], w[1] - w[0], w[1] != payload_len))
+            .collect()
+    }
+
+    fn udp_datagram(len: usize) -> Vec<u8> {
+        let mut data = hex(UDP_V4);
+        crate::packet::set_payload(&mut data, &vec![0x42; len]).unwrap();
+        data
+    }
+
+    fn datagram(result: Reassembled<usize>) -> Vec<u8> {
+        match result {
+            Reassembled::Datagram { data, .. } => data,
+            Reassembled::Packet(..) => panic!("not reassembled"),
+        }
+    }
+
+    #[test]
+    fn passthrough() {
+        let mut reassembler = FragmentReassembler::new(Duration::from_secs(30));
+        for fixture in [UDP_V4, UDP_V6] {
+            let data = hex(fixture);
+            assert_eq!(
+                reassembler.process(data.clone(), 0).unwrap(),
+                Some(Reassembled::Packet(0, data))
+            );
+        }
+    }
+
+    #[test]
+    fn reassemble_in_order() {
+        let data = udp_datagram(3000);
+        let mut reassembler = FragmentReassembler::new(Duration::from_secs(30));
+        let fragments = fragment(&data, &[0, 1480, 2960]);
+        assert_eq!(reassembler.process(fragments[0].clone(), 0).unwrap(), None);
+        assert_eq!(reassembler.process(fragments[1].clone(), 1).unwrap(), None);
+        let Some(Reassembled::Datagram {
+            data: reassembled,
+            fragments: originals,
+        }) = reassembler.process(fragments[2].clone(), 2).unwrap()
+        else {
+            panic!("not reassembled");
+        };
+        // DF is cleared on fragments, so the header differs from the original in that bit.
+        assert_eq!(reassembled[20..], data[20..]);
+        assert_eq!(IpHeader::parse(&reassembled).unwrap().total_len, data.len());
+        assert!(validate_checksums(&reassembled));
+        assert_eq!(
+            originals,
+            fragments.into_iter().enumerate().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn reassemble_out_of_order() {
+        let data = udp_datagram(100);
+        let mut reassembler = FragmentReassembler::new(Duration::from_secs(30));
+        let mut fragments = fragment(&data, &[0, 24, 64]);
+        fragments.reverse();
+        // a duplicate fragment is dropped
+        fragments.insert(1, fragments[0].clone());
+        let mut results = fragments
+            .into_iter()
+            .enumerate()
+            .map(|(i, f)| reassembler.process(f, i).unwrap())
+            .collect::<Vec<_>>();
+        let Some(Reassembled::Datagram {
+            data: reassembled,
+            fragments: originals,
+        }) = results.pop().unwrap()
+        else {
+            panic!("not reassembled");
+        };
+        assert!(results.iter().all(Option::is_none));
+        assert_eq!(reassembled[20..], data[20..]);
+        assert!(validate_checksums(&reassembled));
+        let order = originals.iter().map(|(i, _)| *i).collect::<Vec<_>>();
+        assert_eq!(order, [3, 2, 0]);
+    }
+
+    #[test]
+    fn reassemble_ecn() {
+        let mut data = udp_datagram(100);
+        crate::packet::set_ecn(&mut data, Ecn::Ect0).unwrap();
+        let mut reassembler = FragmentReassembler::new(Duration::from_secs(30));
+        let fragments = fragment(&data, &[0, 64]);
+        assert_eq!(reassembler.process(fragments[0].clone(), 0).unwrap(), None);
+        let reassembled = datagram(
+            reassembler
+                .process(fragments[1].clone(), 1)
+                .unwrap()
+                .unwrap(),
+        );
+        assert_eq!(ecn(&reassembled).unwrap(), Ecn::Ect0);
+
+        // A router marked the second fragment only.
+        let mut fragments = fragment(&data, &[0, 64]);
+        crate::packet::set_ecn(&mut fragments[1], Ecn::Ce).unwrap();
+        assert_eq!(reassembler.process(fragments[0].clone(), 0).unwrap(), None);
+        let reassembled = datagram(
+            reassembler
+                .process(fragments[1].clone(), 1)
+                .unwrap()
+                .unwrap(),
+        );
+        assert_eq!(ecn(&reassembled).unwrap(), Ecn::Ce);
+        assert!(validate_checksums(&reassembled));
+    }
+
+    #[test]
+    fn reject_overlapping_end() {
+        let data = udp_datagram(100);
+        let mut reassembler = FragmentReassembler::new(Duration::from_secs(30));
+
+        // A last fragment that ends before data we already have.
+        assert_eq!(
+            reassembler
+                .process(fragment_at(&data, 0, 32, true), 0)
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            reassembler
+                .process(fragment_at(&data, 32, 8, true), 1)
+                .unwrap(),
+            None
+        );
+        assert!(reassembler
+            .process(fragment_at(&data, 8, 8, false), 2)
+            .is_err());
+        // The whole set was discarded.
+        assert_eq!(
+            reassembler
+                .process(fragment_at(&data, 8, 24, false), 3)
+                .unwrap(),
+            None
+        );
+
+        // A fragment that extends past the last fragment.
+        let mut reassembler = FragmentReassembler::new(Duration::from_secs(30));
+        assert_eq!(
+            reassembler
+                .process(fragment_at(&data, 16, 8, false), 0)
+                .unwrap(),
+            None
+        );
+        assert!(reassembler
+            .process(fragment_at(&data, 0, 32, true), 1)
+            .is_err());
+        assert_eq!(
+            reassembler
+                .process(fragment_at(&data, 0, 16, true), 2)
+                .unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn reject_too_many_fragments() {
+        let data = udp_datagram(8 * (MAX_FRAGMENTS + 1));
+        let mut reassembler = FragmentReassembler::new(Duration::from_secs(30));
+        for i in 0..MAX_FRAGMENTS {
+            let fragment = fragment_at(&data, 8 * i, 8, true);
+            assert_eq!(reassembler.process(fragment, i).unwrap(), None);
+        }
+        let fragment = fragment_at(&data, 8 * MAX_FRAGMENTS, 8, false);
+        assert!(reassembler.process(fragment, MAX_FRAGMENTS).is_err());
+    }
+
+    #[test]
+    fn reject_overlapping_full_size() {
+        let data = udp_datagram(60000);
+        let mut reassembler = FragmentReassembler::new(Duration::from_secs(30));
+        assert_eq!(
+            reassembler
+                .process(fragment_at(&data, 0, 40000, true), 0)
+                .unwrap(),
+            None
+        );
+        assert!(reassembler
+            .process(fragment_at(&data, 8, 40000, true), 1)
+            .is_err());
+        // The whole set was discarded.
+        assert_eq!(
+            reassembler
+                .process(fragment_at(&data, 40000, 20008, false), 2)
+                .unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn reject_too_much_buffered() {
+        let data = udp_datagram(60000);
+        let mut reassembler = FragmentReassembler::new(Duration::from_secs(30));
+        let sets = MAX_BUFFERED_BYTES / 40000;
+        for id in 0..=sets {
+            let mut fragment = fragment_at(&data, 0, 40000, true);
+            fragment[4..6].copy_from_slice(&(id as u16).to_be_bytes());
+            let result = reassembler.process(fragment, id);
+            if id < sets {
+                assert_eq!(result.unwrap(), None);
+            } else {
+                assert!(result.is_err());
+            }
+        }
+    }
+
+    #[test]
+    fn expire_incomplete() {
+        let data = udp_datagram(100);
+        let mut reassembler = FragmentReassembler::new(Duration::from_millis(10));
+        let fragments = fragment(&data, &[0, 64]);
+        assert_eq!(reassembler.process(fragments[0].clone(), 0).unwrap(), None);
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(reassembler.process(fragments[1].clone(), 1).unwrap(), None);
+    }
+}
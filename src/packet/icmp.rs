@@ -0,0 +1,209 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use anyhow::{ensure, Result};
+use internet_packet::{ConnectionId, TransportProtocol};
+
+use super::{locate_transport, IpHeader, IpVersion};
+
+const ICMP_ECHO_REPLY: u8 = 0;
+const ICMP_ECHO_REQUEST: u8 = 8;
+const ICMPV6_ECHO_REQUEST: u8 = 128;
+const ICMPV6_ECHO_REPLY: u8 = 129;
+
+const ICMP_DEST_UNREACHABLE: u8 = 3;
+const ICMP_TIME_EXCEEDED: u8 = 11;
+const ICMP_PARAMETER_PROBLEM: u8 = 12;
+const ICMPV6_DEST_UNREACHABLE: u8 = 1;
+const ICMPV6_PACKET_TOO_BIG: u8 = 2;
+const ICMPV6_TIME_EXCEEDED: u8 = 3;
+const ICMPV6_PARAMETER_PROBLEM: u8 = 4;
+
+/// An ICMP or ICMPv6 packet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IcmpPacket {
+    pub ip: IpHeader,
+    pub icmp_type: u8,
+    pub code: u8,
+    /// The identifier of echo requests and replies.
+    pub identifier: Option<u16>,
+}
+
+impl IcmpPacket {
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let ip = IpHeader::parse(data)?;
+        let (proto, offset) = locate_transport(data)?;
+        let expected_proto = match ip.version {
+            IpVersion::V4 => 1,
+            IpVersion::V6 => 58,
+        };
+        ensure!(proto == expected_proto, "not an ICMP packet");
+        ensure!(offset + 8 <= ip.total_len, "truncated ICMP header");
+
+        let icmp_type = data[offset];
+        let code = data[offset + 1];
+        let is_echo = match ip.version {
+            IpVersion::V4 => matches!(icmp_type, ICMP_ECHO_REQUEST | ICMP_ECHO_REPLY),
+            IpVersion::V6 => matches!(icmp_type, ICMPV6_ECHO_REQUEST | ICMPV6_ECHO_REPLY),
+        };
+        let identifier = is_echo.then(|| u16::from_be_bytes([data[offset + 4], data[offset + 5]]));
+        Ok(IcmpPacket {
+            ip,
+            icmp_type,
+            code,
+            identifier,
+        })
+    }
+
+    /// ICMP has no ports, so we use the echo identifier as a pseudo-port on both ends.
+    /// This way an echo request and its reply produce mirrored endpoints.
+    pub fn src(&self) -> SocketAddr {
+        SocketAddr::new(self.ip.src_ip, self.identifier.unwrap_or(0))
+    }
+
+    pub fn dst(&self) -> SocketAddr {
+        SocketAddr::new(self.ip.dst_ip, self.identifier.unwrap_or(0))
+    }
+
+    /// Whether this is an error message, which quotes the packet that caused it.
+    pub fn is_error(&self) -> bool {
+        match self.ip.version {
+            IpVersion::V4 => matches!(
+                self.icmp_type,
+                ICMP_DEST_UNREACHABLE | ICMP_TIME_EXCEEDED | ICMP_PARAMETER_PROBLEM
+            ),
+            IpVersion::V6 => matches!(
+                self.icmp_type,
+                ICMPV6_DEST_UNREACHABLE
+                    | ICMPV6_PACKET_TOO_BIG
+                    | ICMPV6_TIME_EXCEEDED
+                    | ICMPV6_PARAMETER_PROBLEM
+            ),
+        }
+    }
+
+    /// For error messages, return the connection of the TCP or UDP packet that caused it,
+    /// as seen by the sender of that packet.
+    ///
+    /// The quoted packet is usually truncated, but always includes the ports.
+    pub fn quoted_connection_id(&self, data: &[u8]) -> Option<ConnectionId> {
+        if !self.is_error() {
+            return None;
+        }
+        let (_, offset) = locate_transport(data).ok()?;
+        let quoted = data.get(offset + 8..self.ip.total_len)?;
+        let (proto, src_ip, dst_ip, transport_offset) = match quoted.first()? >> 4 {
+            4 if quoted.len() >= 20 => {
+                let src: [u8; 4] = quoted[12..16].try_into().unwrap();
+                let dst: [u8; 4] = quoted[16..20].try_into().unwrap();
+                (
+                    quoted[9],
+                    IpAddr::V4(Ipv4Addr::from(src)),
+                    IpAddr::V4(Ipv4Addr::from(dst)),
+                    (quoted[0] & 0x0f) as usize * 4,
+                )
+            }
+            6 if quoted.len() >= 40 => {
+                let src: [u8; 16] = quoted[8..24].try_into().unwrap();
+                let dst: [u8; 16] = quoted[24..40].try_into().unwrap();
+                (
+                    quoted[6],
+                    IpAddr::V6(Ipv6Addr::from(src)),
+                    IpAddr::V6(Ipv6Addr::from(dst)),
+                    40,
+                )
+            }
+            _ => return None,
+        };
+        let proto = match proto {
+            6 => TransportProtocol::Tcp,
+            17 => TransportProtocol::Udp,
+            _ => return None,
+        };
+        let ports = quoted.get(transport_offset..transport_offset + 4)?;
+        Some(ConnectionId {
+            proto,
+            src: SocketAddr::new(src_ip, u16::from_be_bytes([ports[0], ports[1]])),
+            dst: SocketAddr::new(dst_ip, u16::from_be_bytes([ports[2], ports[3]])),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::tests::{hex, TCP_V4_SYN, TCP_V6_SYN};
+
+    #[test]
+    fn parse_echo() {
+        let request = IcmpPacket::parse(&hex(
+            b"4500002012344000400114a70a0000010a000002080006fa1234000170696e67",
+        ))
+        .unwrap();
+        assert_eq!(request.icmp_type, ICMP_ECHO_REQUEST);
+        assert_eq!(request.code, 0);
+        assert_eq!(request.identifier, Some(0x1234));
+        assert_eq!(request.src(), "10.0.0.1:4660".parse().unwrap());
+        assert_eq!(request.dst(), "10.0.0.2:4660".parse().unwrap());
+
+        let reply = IcmpPacket::parse(&hex(b"60000000000c3a4020010db800000000000000000000000220010db80000000000000000000000018100323e1234000170696e67")).unwrap();
+        assert_eq!(reply.icmp_type, ICMPV6_ECHO_REPLY);
+        assert_eq!(reply.identifier, Some(0x1234));
+        assert_eq!(reply.src(), "[2001:db8::2]:4660".parse().unwrap());
+    }
+
+    #[test]
+    fn parse_error() {
+        let unreachable = IcmpPacket::parse(&hex(b"450000381234400040015ce7c0a801010a000001030433e20000057845000028123440004006e8c00a0000015db8d822c350005000000001")).unwrap();
+        assert_eq!(unreachable.icmp_type, 3);
+        assert_eq!(unreachable.code, 4);
+        assert_eq!(unreachable.identifier, None);
+
+        assert!(IcmpPacket::parse(&hex(TCP_V4_SYN)).is_err());
+    }
+
+    #[test]
+    fn quoted_connection() {
+        let data = hex(b"450000381234400040015ce7c0a801010a000001030433e20000057845000028123440004006e8c00a0000015db8d822c350005000000001");
+        let unreachable = IcmpPacket::parse(&data).unwrap();
+        assert!(unreachable.is_error());
+        assert_eq!(
+            unreachable.quoted_connection_id(&data),
+            Some(ConnectionId {
+                proto: TransportProtocol::Tcp,
+                src: "10.0.0.1:50000".parse().unwrap(),
+                dst: "93.184.216.34:80".parse().unwrap(),
+            })
+        );
+        // The quoted packet must include the ports.
+        let mut truncated = data[..data.len() - 5].to_vec();
+        let len = truncated.len() as u16;
+        truncated[2..4].copy_from_slice(&len.to_be_bytes());
+        assert_eq!(unreachable.quoted_connection_id(&truncated), None);
+
+        // Packet Too Big, quoting the first 48 bytes of a TCP SYN
+        let quoted = &hex(TCP_V6_SYN)[..48];
+        let mut data = hex(
+            b"60000000000000000000000000000000000000000000000220010db8000000000000000000000001",
+        );
+        data[4..6].copy_from_slice(&(8 + quoted.len() as u16).to_be_bytes());
+        data[6] = 58;
+        data.extend_from_slice(&[ICMPV6_PACKET_TOO_BIG, 0, 0, 0, 0, 0, 0x05, 0x00]);
+        data.extend_from_slice(quoted);
+        let too_big = IcmpPacket::parse(&data).unwrap();
+        assert!(too_big.is_error());
+        assert_eq!(
+            too_big.quoted_connection_id(&data),
+            Some(ConnectionId {
+                proto: TransportProtocol::Tcp,
+                src: "[2001:db8::1]:50000".parse().unwrap(),
+                dst: "[2001:db8::2]:443".parse().unwrap(),
+            })
+        );
+
+        let echo = IcmpPacket::parse(&hex(
+            b"4500002012344000400114a70a0000010a000002080006fa1234000170696e67",
+        ))
+        .unwrap();
+        assert!(!echo.is_error());
+    }
+}
@@ -0,0 +1,752 @@
+//! Helpers for inspecting raw IPv4/IPv6 packets.
+//!
+//! [`internet_packet::InternetPacket`] covers the common TCP/UDP case. The functions in this
+//! module operate on the raw packet bytes and are used wherever we need to look at parts of
+//! the IP headers that `InternetPacket` does not expose.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6};
+
+use anyhow::{bail, ensure, Result};
+
+use crate::MAX_PACKET_SIZE;
+
+mod builder;
+mod checksum;
+mod connection;
+mod dns;
+mod fragment;
+mod icmp;
+mod pcap;
+mod protocol;
+mod quic;
+mod tcp;
+mod tls;
+pub use builder::{tcp_reset, InternetPacketBuilder, TCP_ACK, TCP_FIN, TCP_PSH, TCP_RST, TCP_SYN};
+pub use checksum::{recompute_checksums, validate_checksums};
+pub use connection::{
+    CanonicalConnectionId, ConnectionIdDef, ConnectionIdExt, Direction, TransportProtocolDef,
+};
+pub use dns::dns_qname;
+pub use fragment::{FragmentReassembler, Reassembled};
+pub use icmp::IcmpPacket;
+pub use pcap::PcapWriter;
+pub use protocol::IpProtocol;
+pub use quic::is_quic;
+pub use tcp::{tcp_ack, tcp_options, tcp_seq, tcp_window, TcpOption};
+pub use tls::tls_sni;
+
+pub const IPV4_MIN_HEADER_LEN: usize = 20;
+pub const IPV6_HEADER_LEN: usize = 40;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpVersion {
+    V4,
+    V6,
+}
+
+impl IpVersion {
+    /// Detect the IP version from the first nibble of a packet.
+    pub fn detect(data: &[u8]) -> Result<Self> {
+        match data.first().map(|b| b >> 4) {
+            Some(4) => Ok(IpVersion::V4),
+            Some(6) => Ok(IpVersion::V6),
+            Some(v) => bail!("unknown IP version: {}", v),
+            None => bail!("empty packet"),
+        }
+    }
+}
+
+/// The fixed part of an IPv4 or IPv6 header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IpHeader {
+    pub version: IpVersion,
+    pub src_ip: IpAddr,
+    pub dst_ip: IpAddr,
+    /// The IPv4 protocol or IPv6 next header field.
+    pub next_header: u8,
+    /// The length of the fixed header, including IPv4 options.
+    pub header_len: usize,
+    /// The length of the entire packet as declared in the header.
+    pub total_len: usize,
+}
+
+impl IpHeader {
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        match IpVersion::detect(data)? {
+            IpVersion::V4 => {
+                ensure!(data.len() >= IPV4_MIN_HEADER_LEN, "truncated IPv4 header");
+                let header_len = (data[0] & 0x0f) as usize * 4;
+                let total_len = u16::from_be_bytes([data[2], data[3]]) as usize;
+                ensure!(
+                    header_len >= IPV4_MIN_HEADER_LEN && header_len <= total_len,
+                    "invalid IPv4 header length: {}",
+                    header_len
+                );
+                ensure!(total_len <= data.len(), "truncated IPv4 packet");
+                Ok(IpHeader {
+                    version: IpVersion::V4,
+                    src_ip: IpAddr::V4(Ipv4Addr::new(data[12], data[13], data[14], data[15])),
+                    dst_ip: IpAddr::V4(Ipv4Addr::new(data[16], data[17], data[18], data[19])),
+                    next_header: data[9],
+                    header_len,
+                    total_len,
+                })
+            }
+            IpVersion::V6 => {
+                ensure!(data.len() >= IPV6_HEADER_LEN, "truncated IPv6 header");
+                let payload_len = u16::from_be_bytes([data[4], data[5]]) as usize;
+                let total_len = IPV6_HEADER_LEN + payload_len;
+                ensure!(total_len <= data.len(), "truncated IPv6 packet");
+                let src: [u8; 16] = data[8..24].try_into().unwrap();
+                let dst: [u8; 16] = data[24..40].try_into().unwrap();
+                Ok(IpHeader {
+                    version: IpVersion::V6,
+                    src_ip: IpAddr::V6(Ipv6Addr::from(src)),
+                    dst_ip: IpAddr::V6(Ipv6Addr::from(dst)),
+                    next_header: data[6],
+                    header_len: IPV6_HEADER_LEN,
+                    total_len,
+                })
+            }
+        }
+    }
+}
+
+const IPV6_HOP_BY_HOP: u8 = 0;
+const IPV6_ROUTING: u8 = 43;
+const IPV6_FRAGMENT: u8 = 44;
+const IPV6_AUTH: u8 = 51;
+const IPV6_DEST_OPTS: u8 = 60;
+
+/// Locate the transport layer header of an IPv4 or IPv6 packet.
+///
+/// Returns the IP protocol number of the transport layer and the offset of its header.
+/// For IPv6, the extension header chain is walked until an upper-layer header is found.
+/// Non-first fragments and unknown extension headers are rejected, as there is no transport
+/// header we could reliably point to.
+pub fn locate_transport(data: &[u8]) -> Result<(u8, usize)> {
+    let (proto, offset, _) = locate_transport_in_fragment(data)?;
+    Ok((proto, offset))
+}
+
+/// Like [`locate_transport`], but also returns whether the packet is the first fragment of a
+/// larger datagram, i.e. whether the transport segment continues in other packets.
+pub(crate) fn locate_transport_in_fragment(data: &[u8]) -> Result<(u8, usize, bool)> {
+    let header = IpHeader::parse(data)?;
+    match header.version {
+        IpVersion::V4 => {
+            let fragment_offset = u16::from_be_bytes([data[6], data[7]]) & 0x1fff;
+            ensure!(fragment_offset == 0, "non-first IPv4 fragment");
+            let more_fragments = data[6] & 0x20 != 0;
+            Ok((header.next_header, header.header_len, more_fragments))
+        }
+        IpVersion::V6 => {
+            let mut next_header = header.next_header;
+            let mut offset = header.header_len;
+            let mut more_fragments = false;
+            loop {
+                let ext_len = match next_header {
+                    IPV6_HOP_BY_HOP | IPV6_ROUTING | IPV6_DEST_OPTS => {
+                        ensure!(offset + 2 <= header.total_len, "truncated extension header");
+                        (data[offset + 1] as usize + 1) * 8
+                    }
+                    IPV6_FRAGMENT => {
+                        ensure!(offset + 8 <= header.total_len, "truncated fragment header");
+                        let fragment_offset =
+                            u16::from_be_bytes([data[offset + 2], data[offset + 3]]) >> 3;
+                        ensure!(fragment_offset == 0, "non-first IPv6 fragment");
+                        more_fragments = data[offset + 3] & 1 != 0;
+                        8
+                    }
+                    IPV6_AUTH => {
+                        ensure!(offset + 2 <= header.total_len, "truncated extension header");
+                        (data[offset + 1] as usize + 2) * 4
+                    }
+                    // ICMP, TCP, UDP, ICMPv6, SCTP
+                    1 | 6 | 17 | 58 | 132 => return Ok((next_header, offset, more_fragments)),
+                    other => bail!("unsupported IPv6 next header: {}", other),
+                };
+                ensure!(
+                    offset + ext_len <= header.total_len,
+                    "truncated extension header"
+                );
+                next_header = data[offset];
+                offset += ext_len;
+            }
+        }
+    }
+}
+
+/// Check whether an address is a loopback address.
+///
+/// Unlike [`IpAddr::is_loopback`], this also covers IPv4-mapped IPv6 addresses such as
+/// `::ffff:127.0.0.1`, which dual-stack sockets use for IPv4 loopback connections.
+pub fn is_loopback(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_loopback(),
+        IpAddr::V6(ip) => {
+            ip.is_loopback() || ip.to_ipv4_mapped().is_some_and(|ip| ip.is_loopback())
+        }
+    }
+}
+
+/// Check whether a packet never leaves this machine, i.e. both endpoints are loopback addresses.
+pub fn is_loopback_only(src: IpAddr, dst: IpAddr) -> bool {
+    is_loopback(src) && is_loopback(dst)
+}
+
+/// Clear the IPv6 flow label and scope id of an address.
+pub fn without_scope_id(addr: SocketAddr) -> SocketAddr {
+    with_scope_id(addr, 0)
+}
+
+/// Check whether an address is an IPv6 link-local unicast address (`fe80::/10`).
+///
+/// The same link-local address can be in use on several interfaces at once, so these are only
+/// unique together with a scope id. On Windows, that is the index of the interface.
+pub fn is_link_local(ip: IpAddr) -> bool {
+    matches!(ip, IpAddr::V6(ip) if ip.segments()[0] & 0xffc0 == 0xfe80)
+}
+
+/// Set the scope id of a link-local IPv6 address. Other IPv6 addresses have their scope id
+/// cleared, as it does not tell them apart. The flow label is always cleared.
+pub fn with_scope_id(addr: SocketAddr, scope_id: u32) -> SocketAddr {
+    match addr {
+        SocketAddr::V4(_) => addr,
+        SocketAddr::V6(v6) if is_link_local(addr.ip()) => {
+            SocketAddrV6::new(*v6.ip(), v6.port(), 0, scope_id).into()
+        }
+        SocketAddr::V6(v6) => SocketAddrV6::new(*v6.ip(), v6.port(), 0, 0).into(),
+    }
+}
+
+/// Turn an IPv4-mapped IPv6 address (`::ffff:a.b.c.d`) into the plain IPv4 address.
+///
+/// Dual-stack sockets report IPv4 peers this way, but the packets on the wire are IPv4.
+pub fn unmap_ipv4(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V4(_) => addr,
+        SocketAddr::V6(v6) => match v6.ip().to_ipv4_mapped() {
+            Some(ip) => SocketAddr::new(ip.into(), v6.port()),
+            None => addr,
+        },
+    }
+}
+
+/// Return the offset of the payload of a TCP or UDP packet.
+pub fn payload_offset(data: &[u8]) -> Result<usize> {
+    let ip = IpHeader::parse(data)?;
+    match locate_transport(data)? {
+        (6, offset) => Ok(offset + tcp::tcp_header(data)?.len()),
+        (17, offset) => {
+            ensure!(offset + 8 <= ip.total_len, "truncated UDP header");
+            Ok(offset + 8)
+        }
+        (proto, _) => bail!("not a TCP or UDP packet: {}", proto),
+    }
+}
+
+/// Replace the payload of a TCP or UDP packet.
+///
+/// The IP and UDP length fields are updated and all checksums are recomputed.
+/// Headers are left untouched, so there is no way to shrink a packet below its header size.
+pub fn set_payload(data: &mut Vec<u8>, payload: &[u8]) -> Result<()> {
+    let ip = IpHeader::parse(data)?;
+    let (proto, transport_offset) = locate_transport(data)?;
+    let offset = payload_offset(data)?;
+    let total_len = offset + payload.len();
+    ensure!(
+        total_len <= MAX_PACKET_SIZE,
+        "packet too large: {} bytes",
+        total_len
+    );
+
+    data.truncate(offset);
+    data.extend_from_slice(payload);
+    match ip.version {
+        IpVersion::V4 => data[2..4].copy_from_slice(&(total_len as u16).to_be_bytes()),
+        IpVersion::V6 => {
+            data[4..6].copy_from_slice(&((total_len - IPV6_HEADER_LEN) as u16).to_be_bytes())
+        }
+    }
+    if proto == 17 {
+        let udp_len = (total_len - transport_offset) as u16;
+        data[transport_offset + 4..transport_offset + 6].copy_from_slice(&udp_len.to_be_bytes());
+    }
+    recompute_checksums(data)
+}
+
+/// Decrement the IPv4 TTL or IPv6 hop limit, as a router does before forwarding a packet.
+///
+/// The IPv4 header checksum is updated. Returns `false` and leaves the packet untouched if it
+/// must not be forwarded anymore.
+pub fn decrement_ttl(data: &mut [u8]) -> Result<bool> {
+    let ip = IpHeader::parse(data)?;
+    let field = match ip.version {
+        IpVersion::V4 => 8,
+        IpVersion::V6 => 7,
+    };
+    if data[field] <= 1 {
+        return Ok(false);
+    }
+    data[field] -= 1;
+    if ip.version == IpVersion::V4 {
+        let checksum = checksum::ipv4_header_checksum(data, ip.header_len);
+        data[10..12].copy_from_slice(&checksum.to_be_bytes());
+    }
+    Ok(true)
+}
+
+/// The Explicit Congestion Notification codepoint in the two low bits of the IPv4 TOS or
+/// IPv6 traffic class field (RFC 3168).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ecn {
+    NotEct,
+    Ect1,
+    Ect0,
+    /// Congestion experienced.
+    Ce,
+}
+
+impl Ecn {
+    fn from_bits(bits: u8) -> Self {
+        match bits & 0b11 {
+            0b00 => Ecn::NotEct,
+            0b01 => Ecn::Ect1,
+            0b10 => Ecn::Ect0,
+            _ => Ecn::Ce,
+        }
+    }
+
+    fn bits(self) -> u8 {
+        match self {
+            Ecn::NotEct => 0b00,
+            Ecn::Ect1 => 0b01,
+            Ecn::Ect0 => 0b10,
+            Ecn::Ce => 0b11,
+        }
+    }
+}
+
+/// Read the ECN codepoint of a packet.
+pub fn ecn(data: &[u8]) -> Result<Ecn> {
+    let ip = IpHeader::parse(data)?;
+    Ok(match ip.version {
+        IpVersion::V4 => Ecn::from_bits(data[1]),
+        // The IPv6 traffic class spans the low nibble of the first byte and the high nibble
+        // of the second one.
+        IpVersion::V6 => Ecn::from_bits(data[1] >> 4),
+    })
+}
+
+/// Set the ECN codepoint of a packet, keeping the DSCP bits.
+///
+/// The IPv4 header checksum is updated.
+pub fn set_ecn(data: &mut [u8], ecn: Ecn) -> Result<()> {
+    let ip = IpHeader::parse(data)?;
+    match ip.version {
+        IpVersion::V4 => {
+            data[1] = (data[1] & !0b11) | ecn.bits();
+            let checksum = checksum::ipv4_header_checksum(data, ip.header_len);
+            data[10..12].copy_from_slice(&checksum.to_be_bytes());
+        }
+        IpVersion::V6 => data[1] = (data[1] & !0b11_0000) | (ecn.bits() << 4),
+    }
+    Ok(())
+}
+
+/// Turn an outgoing TCP or UDP packet around so that it arrives back at its sender on `port`,
+/// e.g. to redirect a connection to a local proxy.
+///
+/// Source and destination addresses are swapped and the destination port is set to `port`, so
+/// the proxy sees a connection from the original destination's address and the client's port.
+/// Unlike rewriting the destination to a loopback address, the packet keeps its addresses valid
+/// for the interface it was captured on. All checksums are recomputed.
+pub fn reflect_to(data: &mut [u8], port: u16) -> Result<()> {
+    reflect(data, port, false)
+}
+
+/// The reverse of [`reflect_to`] for the replies of the proxy: source and destination addresses
+/// are swapped back and the source port is set to `port`, the original destination port.
+pub fn reflect_from(data: &mut [u8], port: u16) -> Result<()> {
+    reflect(data, port, true)
+}
+
+fn reflect(data: &mut [u8], port: u16, src_port: bool) -> Result<()> {
+    let ip = IpHeader::parse(data)?;
+    let (proto, offset) = locate_transport(data)?;
+    ensure!(
+        proto == 6 || proto == 17,
+        "cannot reflect packets of IP protocol {}",
+        proto
+    );
+    ensure!(data.len() >= offset + 4, "truncated transport header");
+    let (src, len) = match ip.version {
+        IpVersion::V4 => (12, 4),
+        IpVersion::V6 => (8, 16),
+    };
+    let (src_ip, dst_ip) = data[src..src + 2 * len].split_at_mut(len);
+    src_ip.swap_with_slice(dst_ip);
+    let field = if src_port { offset } else { offset + 2 };
+    data[field..field + 2].copy_from_slice(&port.to_be_bytes());
+    recompute_checksums(data)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use data_encoding::HEXLOWER;
+    use internet_packet::{InternetPacket, TransportProtocol};
+    use std::net::SocketAddr;
+
+    /// 10.0.0.1:50000 -> 93.184.216.34:80, TCP SYN
+    pub const TCP_V4_SYN: &[u8] =
+        b"45000028123440004006e8c00a0000015db8d822c350005000000001000000005002ffffac650000";
+    /// [2001:db8::1]:50000 -> [2001:db8::2]:443, TCP SYN
+    pub const TCP_V6_SYN: &[u8] = b"600000000014064020010db800000000000000000000000120010db8000000000000000000000002c35001bb00000001000000005002ffff8f610000";
+
+    /// 10.0.0.1:1234 -> 10.0.0.2:53, UDP "abcd"
+    pub const UDP_V4: &[u8] = b"4500002012344000401114970a0000010a00000204d20035000c220661626364";
+    /// [2001:db8::1]:1234 -> [2001:db8::2]:53, UDP "abcd"
+    pub const UDP_V6: &[u8] = b"60000000000c114020010db800000000000000000000000120010db800000000000000000000000204d20035000cda9361626364";
+
+    pub fn hex(data: &[u8]) -> Vec<u8> {
+        HEXLOWER.decode(data).unwrap()
+    }
+
+    #[test]
+    fn parse_ipv4_header() {
+        let header = IpHeader::parse(&hex(TCP_V4_SYN)).unwrap();
+        assert_eq!(header.version, IpVersion::V4);
+        assert_eq!(header.src_ip, "10.0.0.1".parse::<IpAddr>().unwrap());
+        assert_eq!(header.dst_ip, "93.184.216.34".parse::<IpAddr>().unwrap());
+        assert_eq!(header.next_header, 6);
+        assert_eq!(header.header_len, 20);
+        assert_eq!(header.total_len, 40);
+    }
+
+    #[test]
+    fn parse_ipv6_header() {
+        let header = IpHeader::parse(&hex(TCP_V6_SYN)).unwrap();
+        assert_eq!(header.version, IpVersion::V6);
+        assert_eq!(header.src_ip, "2001:db8::1".parse::<IpAddr>().unwrap());
+        assert_eq!(header.dst_ip, "2001:db8::2".parse::<IpAddr>().unwrap());
+        assert_eq!(header.next_header, 6);
+        assert_eq!(header.header_len, 40);
+        assert_eq!(header.total_len, 60);
+    }
+
+    #[test]
+    fn parse_invalid_header() {
+        assert!(IpHeader::parse(&[]).is_err());
+        assert!(IpHeader::parse(&[0x50; 40]).is_err());
+        assert!(IpHeader::parse(&hex(TCP_V4_SYN)[..19]).is_err());
+        assert!(IpHeader::parse(&hex(TCP_V6_SYN)[..50]).is_err());
+    }
+
+    #[test]
+    fn locate_transport_layer() {
+        assert_eq!(locate_transport(&hex(TCP_V4_SYN)).unwrap(), (6, 20));
+        assert_eq!(locate_transport(&hex(TCP_V6_SYN)).unwrap(), (6, 40));
+
+        // IPv6 with a Hop-by-Hop Options header
+        let hop_by_hop = hex(b"60000000001c004020010db800000000000000000000000120010db80000000000000000000000020600010400000000c35001bb00000001000000005002ffff8f610000");
+        assert_eq!(locate_transport(&hop_by_hop).unwrap(), (6, 48));
+
+        // IPv6 non-first fragment
+        let fragment = hex(b"60000000001c2c4020010db800000000000000000000000120010db80000000000000000000000020600000800000001c35001bb00000001000000005002ffff8f610000");
+        assert!(locate_transport(&fragment).is_err());
+
+        // IPv6 with "No Next Header"
+        let mut no_next_header = hex(TCP_V6_SYN);
+        no_next_header[6] = 59;
+        assert!(locate_transport(&no_next_header).is_err());
+
+        // Hop-by-Hop header claiming to be longer than the packet
+        let mut truncated = hop_by_hop.clone();
+        truncated[41] = 0xff;
+        assert!(locate_transport(&truncated).is_err());
+
+        // IPv4 non-first fragment
+        let fragment = hex(b"4500002012340001401154960a0000010a00000204d20035000c220661626364");
+        assert!(locate_transport(&fragment).is_err());
+    }
+
+    #[test]
+    fn replace_payload() {
+        let mut data = hex(UDP_V4);
+        assert_eq!(payload_offset(&data).unwrap(), 28);
+        set_payload(&mut data, b"hello world").unwrap();
+        assert_eq!(&data[28..], b"hello world");
+        assert_eq!(IpHeader::parse(&data).unwrap().total_len, 39);
+        assert_eq!(&data[24..26], &[0, 19]);
+        assert!(validate_checksums(&data));
+
+        let mut data = hex(TCP_V6_SYN);
+        assert_eq!(payload_offset(&data).unwrap(), 60);
+        set_payload(&mut data, b"GET / HTTP/1.1").unwrap();
+        assert_eq!(IpHeader::parse(&data).unwrap().total_len, 74);
+        assert!(validate_checksums(&data));
+        set_payload(&mut data, b"").unwrap();
+        assert_eq!(data, hex(TCP_V6_SYN));
+
+        let mut data = hex(UDP_V6);
+        assert!(set_payload(&mut data, &[0; MAX_PACKET_SIZE]).is_err());
+        assert_eq!(data, hex(UDP_V6));
+    }
+
+    #[test]
+    fn reflect_endpoints() {
+        // 10.0.0.1:50000 -> 93.184.216.34:80
+        let mut data = hex(TCP_V4_SYN);
+        let seq = tcp_seq(&data).unwrap();
+        reflect_to(&mut data, 8080).unwrap();
+        assert!(validate_checksums(&data));
+        assert_eq!(tcp_seq(&data).unwrap(), seq);
+        let packet = InternetPacket::try_from(data).unwrap();
+        assert_eq!(packet.src(), "93.184.216.34:50000".parse().unwrap());
+        assert_eq!(packet.dst(), "10.0.0.1:8080".parse().unwrap());
+
+        // The proxy's reply restores the connection the client opened.
+        let reply = InternetPacketBuilder::new(packet.dst(), packet.src(), TransportProtocol::Tcp)
+            .tcp_flags(TCP_SYN | TCP_ACK)
+            .build()
+            .unwrap();
+        let mut data = reply.inner();
+        reflect_from(&mut data, 80).unwrap();
+        assert!(validate_checksums(&data));
+        let packet = InternetPacket::try_from(data).unwrap();
+        assert_eq!(packet.src(), "93.184.216.34:80".parse().unwrap());
+        assert_eq!(packet.dst(), "10.0.0.1:50000".parse().unwrap());
+
+        let mut data = hex(UDP_V6);
+        let original = InternetPacket::try_from(data.clone()).unwrap();
+        reflect_to(&mut data, 8053).unwrap();
+        assert!(validate_checksums(&data));
+        let packet = InternetPacket::try_from(data).unwrap();
+        assert_eq!(packet.src_ip(), original.dst_ip());
+        assert_eq!(packet.dst(), SocketAddr::new(original.src_ip(), 8053));
+        assert_eq!(packet.src().port(), original.src().port());
+        assert_eq!(packet.payload(), b"abcd");
+
+        assert!(reflect_to(&mut [], 8080).is_err());
+    }
+
+    #[test]
+    fn ttl() {
+        let mut data = hex(TCP_V4_SYN);
+        assert!(decrement_ttl(&mut data).unwrap());
+        assert_eq!(data[8], 63);
+        assert!(validate_checksums(&data));
+
+        let mut data = hex(UDP_V6);
+        assert!(decrement_ttl(&mut data).unwrap());
+        assert_eq!(data[7], 63);
+        assert!(validate_checksums(&data));
+
+        let mut expired = hex(TCP_V4_SYN);
+        expired[8] = 1;
+        recompute_checksums(&mut expired).unwrap();
+        let original = expired.clone();
+        assert!(!decrement_ttl(&mut expired).unwrap());
+        assert_eq!(expired, original);
+
+        assert!(decrement_ttl(&mut []).is_err());
+    }
+
+    #[test]
+    fn ecn_codepoints() {
+        for fixture in [TCP_V4_SYN, TCP_V6_SYN] {
+            let mut data = hex(fixture);
+            assert_eq!(ecn(&data).unwrap(), Ecn::NotEct);
+            for codepoint in [Ecn::Ect1, Ecn::Ect0, Ecn::Ce, Ecn::NotEct] {
+                set_ecn(&mut data, codepoint).unwrap();
+                assert_eq!(ecn(&data).unwrap(), codepoint);
+                assert!(validate_checksums(&data));
+            }
+            assert_eq!(data, hex(fixture));
+        }
+
+        // DSCP is left alone: EF is 0b101110.
+        let mut data = hex(TCP_V4_SYN);
+        data[1] = 0b1011_1000;
+        set_ecn(&mut data, Ecn::Ect0).unwrap();
+        assert_eq!(data[1], 0b1011_1010);
+        let mut data = hex(TCP_V6_SYN);
+        data[0] = 0x6b;
+        data[1] = 0x80;
+        set_ecn(&mut data, Ecn::Ect0).unwrap();
+        assert_eq!(&data[..2], &[0x6b, 0xa0]);
+        assert_eq!(ecn(&data).unwrap(), Ecn::Ect0);
+
+        assert!(ecn(&[]).is_err());
+        assert!(set_ecn(&mut [], Ecn::Ce).is_err());
+    }
+
+    /// Passed-through packets are reinjected byte for byte, and none of the changes we make to
+    /// redirected or forwarded packets touch the ECN bits.
+    #[test]
+    fn ecn_preserved() {
+        for fixture in [TCP_V4_SYN, TCP_V6_SYN, UDP_V4, UDP_V6] {
+            let mut data = hex(fixture);
+            set_ecn(&mut data, Ecn::Ect0).unwrap();
+            reflect_to(&mut data, 8080).unwrap();
+            reflect_from(&mut data, 443).unwrap();
+            assert!(decrement_ttl(&mut data).unwrap());
+            set_payload(&mut data, b"hello").unwrap();
+            assert_eq!(ecn(&data).unwrap(), Ecn::Ect0);
+            assert!(validate_checksums(&data));
+        }
+    }
+
+    #[test]
+    fn internet_packet_ipv6() {
+        let packet = InternetPacket::try_from(hex(TCP_V6_SYN)).unwrap();
+        let connection_id = packet.connection_id();
+        assert!(matches!(connection_id.proto, TransportProtocol::Tcp));
+        assert_eq!(
+            connection_id.src,
+            "[2001:db8::1]:50000".parse::<SocketAddr>().unwrap()
+        );
+        assert_eq!(
+            connection_id.dst,
+            "[2001:db8::2]:443".parse::<SocketAddr>().unwrap()
+        );
+    }
+
+    /// `InternetPacket` only needs the raw bytes, so the fixtures above can be parsed directly.
+    #[test]
+    fn internet_packet_fixtures() {
+        for (fixture, proto, src, dst, payload) in [
+            (
+                TCP_V4_SYN,
+                TransportProtocol::Tcp,
+                "10.0.0.1:50000",
+                "93.184.216.34:80",
+                &b""[..],
+            ),
+            (
+                TCP_V6_SYN,
+                TransportProtocol::Tcp,
+                "[2001:db8::1]:50000",
+                "[2001:db8::2]:443",
+                b"",
+            ),
+            (
+                UDP_V4,
+                TransportProtocol::Udp,
+                "10.0.0.1:1234",
+                "10.0.0.2:53",
+                b"abcd",
+            ),
+            (
+                UDP_V6,
+                TransportProtocol::Udp,
+                "[2001:db8::1]:1234",
+                "[2001:db8::2]:53",
+                b"abcd",
+            ),
+        ] {
+            let data = hex(fixture);
+            let packet = InternetPacket::try_from(data.clone()).unwrap();
+            assert_eq!(packet.protocol(), proto);
+            assert_eq!(packet.src(), src.parse::<SocketAddr>().unwrap());
+            assert_eq!(packet.dst(), dst.parse::<SocketAddr>().unwrap());
+            assert_eq!(packet.payload(), payload);
+            assert_eq!(payload_offset(&data).unwrap(), data.len() - payload.len());
+            assert_eq!(packet.inner(), data);
+        }
+    }
+
+    #[test]
+    fn internet_packet_invalid() {
+        assert!(InternetPacket::try_from(vec![]).is_err());
+        for fixture in [TCP_V4_SYN, TCP_V6_SYN, UDP_V4, UDP_V6] {
+            let data = hex(fixture);
+            // truncated transport header
+            let header_len = IpHeader::parse(&data).unwrap().header_len;
+            assert!(InternetPacket::try_from(data[..header_len + 4].to_vec()).is_err());
+        }
+        // ICMP is not supported by InternetPacket, see IpProtocol.
+        let mut icmp = hex(UDP_V4);
+        icmp[9] = u8::from(IpProtocol::Icmp);
+        assert!(InternetPacket::try_from(icmp).is_err());
+    }
+
+    /// A cheap version of the `packet` fuzz target: every prefix of every fixture, and every
+    /// header byte set to 0x00 and 0xff, must be rejected or parsed, but never panic.
+    #[test]
+    fn malformed_input() {
+        fn parse_all(data: &[u8]) {
+            let _ = locate_transport(data);
+            let _ = tcp_options(data);
+            let _ = validate_checksums(data);
+            if let Ok(icmp) = IcmpPacket::parse(data) {
+                let _ = icmp.quoted_connection_id(data);
+            }
+            if let Ok(offset) = payload_offset(data) {
+                let _ = tls_sni(&data[offset..]);
+                let _ = dns_qname(&data[offset..]);
+            }
+            let _ = recompute_checksums(&mut data.to_vec());
+            let _ = decrement_ttl(&mut data.to_vec());
+            let _ = set_payload(&mut data.to_vec(), b"abc");
+            let _ = InternetPacket::try_from(data.to_vec());
+        }
+
+        for fixture in [TCP_V4_SYN, TCP_V6_SYN, UDP_V4, UDP_V6] {
+            let data = hex(fixture);
+            for len in 0..data.len() {
+                parse_all(&data[..len]);
+            }
+            for i in 0..data.len().min(60) {
+                for value in [0x00, 0xff] {
+                    let mut corrupted = data.clone();
+                    corrupted[i] = value;
+                    parse_all(&corrupted);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn internet_packet_udp_reverse() {
+        for fixture in [UDP_V4, UDP_V6] {
+            let request = InternetPacket::try_from(hex(fixture)).unwrap();
+            let response =
+                InternetPacketBuilder::new(request.dst(), request.src(), TransportProtocol::Udp)
+                    .payload(b"response".as_slice())
+                    .build()
+                    .unwrap();
+            assert_eq!(response.connection_id(), request.connection_id().reverse());
+            assert_eq!(
+                response.connection_id().canonical().0,
+                request.connection_id().canonical().0
+            );
+        }
+    }
+
+    #[test]
+    fn loopback() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        assert!(is_loopback(ip("127.0.0.1")));
+        assert!(is_loopback(ip("127.1.2.3")));
+        assert!(is_loopback(ip("::1")));
+        assert!(is_loopback(ip("::ffff:127.0.0.1")));
+        assert!(!is_loopback(ip("10.0.0.1")));
+        assert!(!is_loopback(ip("::ffff:10.0.0.1")));
+        assert!(!is_loopback(ip("2001:db8::1")));
+
+        let packet = InternetPacketBuilder::new(
+            "[::1]:50000".parse().unwrap(),
+            "[::1]:8080".parse().unwrap(),
+            TransportProtocol::Tcp,
+        )
+        .tcp_flags(TCP_SYN)
+        .build()
+        .unwrap();
+        assert!(is_loopback_only(packet.src_ip(), packet.dst_ip()));
+        assert!(is_loopback_only(ip("::ffff:127.0.0.1"), ip("127.0.0.1")));
+        assert!(!is_loopback_only(ip("::1"), ip("2001:db8::1")));
+    }
+}
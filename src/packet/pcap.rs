@@ -0,0 +1,88 @@
+use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+
+use crate::MAX_PACKET_SIZE;
+
+const PCAP_MAGIC: u32 = 0xa1b2c3d4;
+const PCAP_VERSION_MAJOR: u16 = 2;
+const PCAP_VERSION_MINOR: u16 = 4;
+/// Packets start with an IPv4 or IPv6 header, there is no link layer.
+const LINKTYPE_RAW: u32 = 101;
+
+/// Write raw IP packets in the libpcap file format, e.g. for inspection in Wireshark.
+///
+/// Writes are not flushed automatically, wrap the writer in a `BufWriter` and call
+/// [`PcapWriter::flush`] as needed.
+pub struct PcapWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> PcapWriter<W> {
+    /// Create a new writer and write the file header.
+    pub fn new(mut writer: W) -> Result<Self> {
+        let mut header = Vec::with_capacity(24);
+        header.extend_from_slice(&PCAP_MAGIC.to_le_bytes());
+        header.extend_from_slice(&PCAP_VERSION_MAJOR.to_le_bytes());
+        header.extend_from_slice(&PCAP_VERSION_MINOR.to_le_bytes());
+        // timezone offset and timestamp accuracy, both unused
+        header.extend_from_slice(&[0; 8]);
+        header.extend_from_slice(&(MAX_PACKET_SIZE as u32).to_le_bytes());
+        header.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+        writer.write_all(&header)?;
+        Ok(Self { writer })
+    }
+
+    /// Write a single packet, captured at `timestamp`.
+    pub fn write(&mut self, data: &[u8], timestamp: SystemTime) -> Result<()> {
+        let since_epoch = timestamp.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let len = data.len() as u32;
+        let mut header = Vec::with_capacity(16);
+        header.extend_from_slice(&(since_epoch.as_secs() as u32).to_le_bytes());
+        header.extend_from_slice(&since_epoch.subsec_micros().to_le_bytes());
+        // captured length, original length
+        header.extend_from_slice(&len.to_le_bytes());
+        header.extend_from_slice(&len.to_le_bytes());
+        self.writer.write_all(&header)?;
+        self.writer.write_all(data)?;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::tests::{hex, UDP_V4};
+    use std::time::Duration;
+
+    #[test]
+    fn write_pcap() {
+        let packet = hex(UDP_V4);
+        let mut writer = PcapWriter::new(Vec::new()).unwrap();
+        let timestamp = UNIX_EPOCH + Duration::from_micros(1_700_000_000_123_456);
+        writer.write(&packet, timestamp).unwrap();
+        let data = writer.into_inner();
+
+        assert_eq!(data.len(), 24 + 16 + packet.len());
+        assert_eq!(data[..4], [0xd4, 0xc3, 0xb2, 0xa1]);
+        assert_eq!(data[4..8], [2, 0, 4, 0]);
+        assert_eq!(data[20..24], LINKTYPE_RAW.to_le_bytes());
+
+        let record = &data[24..];
+        assert_eq!(record[..4], 1_700_000_000u32.to_le_bytes());
+        assert_eq!(record[4..8], 123_456u32.to_le_bytes());
+        assert_eq!(record[8..12], (packet.len() as u32).to_le_bytes());
+        assert_eq!(record[12..16], (packet.len() as u32).to_le_bytes());
+        assert_eq!(record[16..], packet[..]);
+    }
+}
@@ -0,0 +1,117 @@
+use std::fmt;
+
+use anyhow::{anyhow, Error};
+use internet_packet::TransportProtocol;
+
+/// The IP protocol numbers we know about, in the IPv4 protocol / IPv6 next header field.
+///
+/// This is a superset of [`TransportProtocol`], which only covers the protocols
+/// `InternetPacket` can parse.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum IpProtocol {
+    Icmp,
+    Tcp,
+    Udp,
+    Icmpv6,
+    /// SCTP is only recognized so that it can be passed through, it is never intercepted.
+    Sctp,
+}
+
+impl IpProtocol {
+    /// The transport protocol, if `InternetPacket` supports it.
+    pub fn transport(self) -> Option<TransportProtocol> {
+        match self {
+            IpProtocol::Tcp => Some(TransportProtocol::Tcp),
+            IpProtocol::Udp => Some(TransportProtocol::Udp),
+            IpProtocol::Icmp | IpProtocol::Icmpv6 | IpProtocol::Sctp => None,
+        }
+    }
+}
+
+impl TryFrom<u8> for IpProtocol {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(IpProtocol::Icmp),
+            6 => Ok(IpProtocol::Tcp),
+            17 => Ok(IpProtocol::Udp),
+            58 => Ok(IpProtocol::Icmpv6),
+            132 => Ok(IpProtocol::Sctp),
+            other => Err(anyhow!("unsupported IP protocol number: {}", other)),
+        }
+    }
+}
+
+impl From<IpProtocol> for u8 {
+    fn from(value: IpProtocol) -> Self {
+        match value {
+            IpProtocol::Icmp => 1,
+            IpProtocol::Tcp => 6,
+            IpProtocol::Udp => 17,
+            IpProtocol::Icmpv6 => 58,
+            IpProtocol::Sctp => 132,
+        }
+    }
+}
+
+impl From<TransportProtocol> for IpProtocol {
+    fn from(value: TransportProtocol) -> Self {
+        match value {
+            TransportProtocol::Tcp => IpProtocol::Tcp,
+            TransportProtocol::Udp => IpProtocol::Udp,
+        }
+    }
+}
+
+impl fmt::Display for IpProtocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            IpProtocol::Icmp => "ICMP",
+            IpProtocol::Tcp => "TCP",
+            IpProtocol::Udp => "UDP",
+            IpProtocol::Icmpv6 => "ICMPv6",
+            IpProtocol::Sctp => "SCTP",
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_u8() {
+        assert_eq!(IpProtocol::try_from(1).unwrap(), IpProtocol::Icmp);
+        assert_eq!(IpProtocol::try_from(6).unwrap(), IpProtocol::Tcp);
+        assert_eq!(IpProtocol::try_from(17).unwrap(), IpProtocol::Udp);
+        assert_eq!(IpProtocol::try_from(58).unwrap(), IpProtocol::Icmpv6);
+        assert_eq!(IpProtocol::try_from(132).unwrap(), IpProtocol::Sctp);
+        for unsupported in [0, 2, 41, 255] {
+            let err = IpProtocol::try_from(unsupported).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                format!("unsupported IP protocol number: {}", unsupported)
+            );
+        }
+    }
+
+    #[test]
+    fn roundtrip() {
+        for proto in [
+            IpProtocol::Icmp,
+            IpProtocol::Tcp,
+            IpProtocol::Udp,
+            IpProtocol::Icmpv6,
+            IpProtocol::Sctp,
+        ] {
+            assert_eq!(IpProtocol::try_from(u8::from(proto)).unwrap(), proto);
+        }
+        assert_eq!(IpProtocol::Icmpv6.to_string(), "ICMPv6");
+        assert_eq!(IpProtocol::from(TransportProtocol::Tcp).to_string(), "TCP");
+        assert_eq!(IpProtocol::Udp.transport(), Some(TransportProtocol::Udp));
+        assert_eq!(IpProtocol::Icmp.transport(), None);
+        assert_eq!(IpProtocol::Icmpv6.transport(), None);
+        assert_eq!(IpProtocol::Sctp.transport(), None);
+    }
+}
@@ -0,0 +1,90 @@
+const QUIC_LONG_HEADER: u8 = 0x80;
+const QUIC_FIXED_BIT: u8 = 0x40;
+const QUIC_V1: u32 = 0x00000001;
+const QUIC_V2: u32 = 0x6b3343cf;
+const MAX_CONNECTION_ID_LEN: usize = 20;
+
+/// Check whether a UDP payload is a QUIC long header packet.
+///
+/// Every QUIC connection starts with long header packets (Initial, 0-RTT, Handshake), so this
+/// is enough to recognize new connections. Short header packets carry no version and cannot
+/// be told apart from other UDP traffic without knowing the connection they belong to.
+/// Version negotiation packets and unknown versions are not recognized.
+pub fn is_quic(payload: &[u8]) -> bool {
+    let Some(&first) = payload.first() else {
+        return false;
+    };
+    if first & (QUIC_LONG_HEADER | QUIC_FIXED_BIT) != QUIC_LONG_HEADER | QUIC_FIXED_BIT {
+        return false;
+    }
+    let Some(version) = payload.get(1..5) else {
+        return false;
+    };
+    let version = u32::from_be_bytes([version[0], version[1], version[2], version[3]]);
+    // drafts are 0xff0000xx
+    if !matches!(version, QUIC_V1 | QUIC_V2 | 0xff000000..=0xff0000ff) {
+        return false;
+    }
+    // destination and source connection id
+    let mut offset = 5;
+    for _ in 0..2 {
+        let Some(&len) = payload.get(offset) else {
+            return false;
+        };
+        let len = len as usize;
+        if len > MAX_CONNECTION_ID_LEN || offset + 1 + len > payload.len() {
+            return false;
+        }
+        offset += 1 + len;
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn initial(version: u32) -> Vec<u8> {
+        let mut data = vec![0xc3];
+        data.extend_from_slice(&version.to_be_bytes());
+        // destination connection id
+        data.push(8);
+        data.extend_from_slice(&[0xaa; 8]);
+        // source connection id
+        data.push(0);
+        // token length, length, packet number, payload
+        data.extend_from_slice(&[0x00, 0x44, 0xd0, 0x00]);
+        data.extend_from_slice(&[0; 16]);
+        data
+    }
+
+    #[test]
+    fn detect_long_header() {
+        assert!(is_quic(&initial(QUIC_V1)));
+        assert!(is_quic(&initial(QUIC_V2)));
+        assert!(is_quic(&initial(0xff00001d)));
+        // version negotiation
+        assert!(!is_quic(&initial(0)));
+    }
+
+    #[test]
+    fn detect_invalid() {
+        assert!(!is_quic(b""));
+        // a DNS query
+        assert!(!is_quic(
+            b"\x12\x34\x01\x00\x00\x01\x00\x00\x00\x00\x00\x00"
+        ));
+        // short header
+        let mut short = initial(QUIC_V1);
+        short[0] = 0x43;
+        assert!(!is_quic(&short));
+        // connection id too long
+        let mut long_cid = initial(QUIC_V1);
+        long_cid[5] = 21;
+        assert!(!is_quic(&long_cid));
+        let data = initial(QUIC_V1);
+        for i in 0..15 {
+            assert!(!is_quic(&data[..i]));
+        }
+    }
+}
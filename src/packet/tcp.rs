@@ -0,0 +1,155 @@
+use anyhow::{ensure, Result};
+
+use super::{locate_transport, IpHeader};
+
+pub const TCP_MIN_HEADER_LEN: usize = 20;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TcpOption {
+    MaxSegmentSize(u16),
+    WindowScale(u8),
+    SackPermitted,
+    Sack(Vec<(u32, u32)>),
+    Timestamp { value: u32, echo_reply: u32 },
+    Unknown(u8, Vec<u8>),
+}
+
+/// Return the TCP header (including options) of an IP packet.
+pub(super) fn tcp_header(data: &[u8]) -> Result<&[u8]> {
+    let ip = IpHeader::parse(data)?;
+    let (proto, offset) = locate_transport(data)?;
+    ensure!(proto == 6, "not a TCP packet");
+    ensure!(
+        offset + TCP_MIN_HEADER_LEN <= ip.total_len,
+        "truncated TCP header"
+    );
+    let header_len = (data[offset + 12] >> 4) as usize * 4;
+    ensure!(
+        header_len >= TCP_MIN_HEADER_LEN && offset + header_len <= ip.total_len,
+        "invalid TCP data offset"
+    );
+    Ok(&data[offset..offset + header_len])
+}
+
+/// The sequence number of a TCP packet.
+pub fn tcp_seq(data: &[u8]) -> Result<u32> {
+    let tcp = tcp_header(data)?;
+    Ok(u32::from_be_bytes([tcp[4], tcp[5], tcp[6], tcp[7]]))
+}
+
+/// The acknowledgement number of a TCP packet.
+pub fn tcp_ack(data: &[u8]) -> Result<u32> {
+    let tcp = tcp_header(data)?;
+    Ok(u32::from_be_bytes([tcp[8], tcp[9], tcp[10], tcp[11]]))
+}
+
+/// The (unscaled) window size of a TCP packet.
+pub fn tcp_window(data: &[u8]) -> Result<u16> {
+    let tcp = tcp_header(data)?;
+    Ok(u16::from_be_bytes([tcp[14], tcp[15]]))
+}
+
+/// Parse the TCP options of an IP packet.
+///
+/// Returns `None` if this is not a TCP packet or if the options are malformed.
+pub fn tcp_options(data: &[u8]) -> Option<Vec<TcpOption>> {
+    let mut options = &tcp_header(data).ok()?[TCP_MIN_HEADER_LEN..];
+    let mut ret = Vec::new();
+    while let Some(&kind) = options.first() {
+        match kind {
+            // End of option list
+            0 => break,
+            // No-operation
+            1 => {
+                options = &options[1..];
+                continue;
+            }
+            _ => {}
+        }
+        let len = *options.get(1)? as usize;
+        if len < 2 || len > options.len() {
+            return None;
+        }
+        let value = &options[2..len];
+        ret.push(match (kind, value.len()) {
+            (2, 2) => TcpOption::MaxSegmentSize(u16::from_be_bytes([value[0], value[1]])),
+            (3, 1) => TcpOption::WindowScale(value[0]),
+            (4, 0) => TcpOption::SackPermitted,
+            (5, n) if n % 8 == 0 => TcpOption::Sack(
+                value
+                    .chunks_exact(8)
+                    .map(|c| {
+                        (
+                            u32::from_be_bytes([c[0], c[1], c[2], c[3]]),
+                            u32::from_be_bytes([c[4], c[5], c[6], c[7]]),
+                        )
+                    })
+                    .collect(),
+            ),
+            (8, 8) => TcpOption::Timestamp {
+                value: u32::from_be_bytes([value[0], value[1], value[2], value[3]]),
+                echo_reply: u32::from_be_bytes([value[4], value[5], value[6], value[7]]),
+            },
+            _ => TcpOption::Unknown(kind, value.to_vec()),
+        });
+        options = &options[len..];
+    }
+    Some(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::tests::{hex, TCP_V4_SYN, UDP_V4};
+
+    /// A SYN with MSS 1460, SACK permitted, timestamps, NOP and window scale 7.
+    const TCP_V4_SYN_OPTIONS: &[u8] = b"4500003c123440004006e8ac0a0000015db8d822c35000500000000100000000a002ffff44080000020405b40402080a0000007b0000000001030307";
+
+    #[test]
+    fn parse_options() {
+        assert_eq!(
+            tcp_options(&hex(TCP_V4_SYN_OPTIONS)).unwrap(),
+            vec![
+                TcpOption::MaxSegmentSize(1460),
+                TcpOption::SackPermitted,
+                TcpOption::Timestamp {
+                    value: 123,
+                    echo_reply: 0
+                },
+                TcpOption::WindowScale(7),
+            ]
+        );
+        assert_eq!(tcp_options(&hex(TCP_V4_SYN)).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn seq_ack_window() {
+        let data = hex(TCP_V4_SYN_OPTIONS);
+        assert_eq!(tcp_seq(&data).unwrap(), 1);
+        assert_eq!(tcp_ack(&data).unwrap(), 0);
+        assert_eq!(tcp_window(&data).unwrap(), 65535);
+
+        let udp = hex(UDP_V4);
+        assert!(tcp_seq(&udp).is_err());
+        assert!(tcp_ack(&udp).is_err());
+        assert!(tcp_window(&udp).is_err());
+        assert!(tcp_seq(&hex(TCP_V4_SYN)[..30]).is_err());
+    }
+
+    #[test]
+    fn parse_malformed_options() {
+        // option length exceeds the options region
+        let mut data = hex(TCP_V4_SYN_OPTIONS);
+        data[41] = 0xff;
+        assert_eq!(tcp_options(&data), None);
+
+        // option length below the minimum
+        data[41] = 0x01;
+        assert_eq!(tcp_options(&data), None);
+
+        // data offset exceeds packet length
+        let mut data = hex(TCP_V4_SYN);
+        data[32] = 0xf0;
+        assert_eq!(tcp_options(&data), None);
+    }
+}
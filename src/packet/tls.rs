@@ -0,0 +1,153 @@
+const CONTENT_TYPE_HANDSHAKE: u8 = 0x16;
+const HANDSHAKE_CLIENT_HELLO: u8 = 0x01;
+const EXTENSION_SERVER_NAME: u16 = 0x0000;
+const SERVER_NAME_HOST_NAME: u8 = 0x00;
+
+/// A minimal reader for length-prefixed TLS structures.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if n > self.0.len() {
+            return None;
+        }
+        let (head, tail) = self.0.split_at(n);
+        self.0 = tail;
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        let b = self.take(2)?;
+        Some(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u24(&mut self) -> Option<usize> {
+        let b = self.take(3)?;
+        Some(u32::from_be_bytes([0, b[0], b[1], b[2]]) as usize)
+    }
+
+    fn vec_u8(&mut self) -> Option<Reader<'a>> {
+        let len = self.u8()? as usize;
+        self.take(len).map(Reader)
+    }
+
+    fn vec_u16(&mut self) -> Option<Reader<'a>> {
+        let len = self.u16()? as usize;
+        self.take(len).map(Reader)
+    }
+}
+
+/// Extract the server name indication from a TCP payload that starts with a TLS ClientHello.
+///
+/// Returns `None` if the payload is not a ClientHello, if the ClientHello is split across
+/// multiple records or segments, or if it does not contain a host name.
+pub fn tls_sni(payload: &[u8]) -> Option<String> {
+    let mut record = Reader(payload);
+    if record.u8()? != CONTENT_TYPE_HANDSHAKE {
+        return None;
+    }
+    // legacy record version
+    record.take(2)?;
+    let mut handshake = record.vec_u16()?;
+    if handshake.u8()? != HANDSHAKE_CLIENT_HELLO {
+        return None;
+    }
+    let len = handshake.u24()?;
+    let mut hello = Reader(handshake.take(len)?);
+
+    // legacy version, random
+    hello.take(2 + 32)?;
+    // session id, cipher suites, compression methods
+    hello.vec_u8()?;
+    hello.vec_u16()?;
+    hello.vec_u8()?;
+
+    let mut extensions = hello.vec_u16()?;
+    while !extensions.0.is_empty() {
+        let extension_type = extensions.u16()?;
+        let mut data = extensions.vec_u16()?;
+        if extension_type != EXTENSION_SERVER_NAME {
+            continue;
+        }
+        let mut names = data.vec_u16()?;
+        while !names.0.is_empty() {
+            let name_type = names.u8()?;
+            let name = names.vec_u16()?;
+            if name_type == SERVER_NAME_HOST_NAME {
+                return std::str::from_utf8(name.0).ok().map(str::to_string);
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_u16_len(data: &[u8]) -> Vec<u8> {
+        let mut ret = (data.len() as u16).to_be_bytes().to_vec();
+        ret.extend_from_slice(data);
+        ret
+    }
+
+    fn client_hello(sni: Option<&str>) -> Vec<u8> {
+        let mut extensions = Vec::new();
+        // supported_versions: TLS 1.3
+        extensions.extend_from_slice(&[0x00, 0x2b, 0x00, 0x03, 0x02, 0x03, 0x04]);
+        if let Some(sni) = sni {
+            let mut entry = vec![SERVER_NAME_HOST_NAME];
+            entry.extend(with_u16_len(sni.as_bytes()));
+            extensions.extend_from_slice(&EXTENSION_SERVER_NAME.to_be_bytes());
+            extensions.extend(with_u16_len(&with_u16_len(&entry)));
+        }
+
+        let mut hello = vec![0x03, 0x03];
+        hello.extend_from_slice(&[0xaa; 32]);
+        // session id
+        hello.extend_from_slice(&[0x00]);
+        // cipher suites
+        hello.extend(with_u16_len(&[0x13, 0x01]));
+        // compression methods
+        hello.extend_from_slice(&[0x01, 0x00]);
+        hello.extend(with_u16_len(&extensions));
+
+        let mut handshake = vec![HANDSHAKE_CLIENT_HELLO];
+        handshake.extend_from_slice(&(hello.len() as u32).to_be_bytes()[1..]);
+        handshake.extend(hello);
+
+        let mut record = vec![CONTENT_TYPE_HANDSHAKE, 0x03, 0x01];
+        record.extend(with_u16_len(&handshake));
+        record
+    }
+
+    #[test]
+    fn parse_sni() {
+        assert_eq!(
+            tls_sni(&client_hello(Some("example.com"))),
+            Some("example.com".to_string())
+        );
+        assert_eq!(tls_sni(&client_hello(None)), None);
+    }
+
+    #[test]
+    fn parse_invalid() {
+        assert_eq!(tls_sni(b""), None);
+        assert_eq!(tls_sni(b"GET / HTTP/1.1\r\n\r\n"), None);
+
+        // ClientHello split across TCP segments
+        let hello = client_hello(Some("example.com"));
+        for i in 0..hello.len() {
+            assert_eq!(tls_sni(&hello[..i]), None);
+        }
+
+        // not a ClientHello
+        let mut server_hello = hello.clone();
+        server_hello[5] = 0x02;
+        assert_eq!(tls_sni(&server_hello), None);
+    }
+}
@@ -1,20 +1,22 @@
 use anyhow::{bail, Context, Result};
+use futures_util::{Sink, Stream};
 use log::{debug, error, log, Level};
+use prost::bytes::{Bytes, BytesMut};
 use std::io::Error;
 use std::net::Shutdown;
 use std::path::{Path, PathBuf};
 use std::pin::Pin;
 use std::process::Stdio;
 use std::str::FromStr;
-use std::task::Poll;
+use std::task::{ready, Poll};
 use std::time::Duration;
-use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, BufReader, ReadBuf};
+use tokio::io::{AsyncBufReadExt, BufReader, ReadBuf};
 use tokio::sync::mpsc::Sender;
 use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
 
 use crate::intercept_conf::InterceptConf;
 use crate::messages::{TransportCommand, TransportEvent};
-use crate::packet_sources::{forward_packets, PacketSourceConf, PacketSourceTask};
+use crate::packet_sources::{forward_packets, PacketSourceConf, PacketSourceTask, IPC_BUF_SIZE};
 use crate::shutdown;
 use tempfile::{tempdir, TempDir};
 use tokio::net::UnixDatagram;
@@ -115,40 +117,73 @@ pub struct LinuxConf {
     pub executable_path: PathBuf,
 }
 
-// We implement AsyncRead/AsyncWrite for UnixDatagram to have a common interface
-// with Windows' NamedPipeServer.
-pub struct AsyncUnixDatagram(UnixDatagram);
+// We implement Stream/Sink for UnixDatagram to have a common interface
+// with Windows' framed NamedPipeServer. Datagrams are framed already.
+pub struct UnixDatagramFramed {
+    socket: UnixDatagram,
+    read_buf: Vec<u8>,
+    pending: Option<Bytes>,
+}
 
-impl AsyncRead for AsyncUnixDatagram {
-    fn poll_read(
-        self: Pin<&mut Self>,
+impl UnixDatagramFramed {
+    fn new(socket: UnixDatagram) -> Self {
+        Self {
+            socket,
+            read_buf: vec![0; IPC_BUF_SIZE],
+            pending: None,
+        }
+    }
+}
+
+impl Stream for UnixDatagramFramed {
+    type Item = std::io::Result<BytesMut>;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
-        buf: &mut ReadBuf<'_>,
-    ) -> Poll<std::io::Result<()>> {
-        self.0.poll_recv(cx, buf)
+    ) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        let mut buf = ReadBuf::new(&mut this.read_buf);
+        ready!(this.socket.poll_recv(cx, &mut buf))?;
+        if buf.filled().is_empty() {
+            return Poll::Ready(None);
+        }
+        Poll::Ready(Some(Ok(BytesMut::from(buf.filled()))))
     }
 }
-impl AsyncWrite for AsyncUnixDatagram {
-    fn poll_write(
+
+impl Sink<Bytes> for UnixDatagramFramed {
+    type Error = Error;
+
+    fn poll_ready(
         self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
-        buf: &[u8],
-    ) -> Poll<std::result::Result<usize, Error>> {
-        self.0.poll_send(cx, buf)
+    ) -> Poll<std::result::Result<(), Error>> {
+        self.poll_flush(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: Bytes) -> std::result::Result<(), Error> {
+        self.pending = Some(item);
+        Ok(())
     }
 
     fn poll_flush(
-        self: Pin<&mut Self>,
+        mut self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> Poll<std::result::Result<(), Error>> {
-        self.0.poll_send_ready(cx)
+        if let Some(data) = &self.pending {
+            ready!(self.socket.poll_send(cx, data))?;
+            self.pending = None;
+        }
+        Poll::Ready(Ok(()))
     }
 
-    fn poll_shutdown(
-        self: Pin<&mut Self>,
-        _cx: &mut std::task::Context<'_>,
+    fn poll_close(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
     ) -> Poll<std::result::Result<(), Error>> {
-        Poll::Ready(self.0.shutdown(Shutdown::Write))
+        ready!(self.as_mut().poll_flush(cx))?;
+        Poll::Ready(self.socket.shutdown(Shutdown::Write))
     }
 }
 
@@ -181,7 +216,7 @@ impl PacketSourceConf for LinuxConf {
         Ok((
             LinuxTask {
                 datagram_dir,
-                channel: AsyncUnixDatagram(channel),
+                channel: UnixDatagramFramed::new(channel),
                 transport_events_tx,
                 transport_commands_rx,
                 conf_rx,
@@ -194,7 +229,7 @@ impl PacketSourceConf for LinuxConf {
 
 pub struct LinuxTask {
     datagram_dir: TempDir,
-    channel: AsyncUnixDatagram,
+    channel: UnixDatagramFramed,
     transport_events_tx: Sender<TransportEvent>,
     transport_commands_rx: UnboundedReceiver<TransportCommand>,
     conf_rx: UnboundedReceiver<InterceptConf>,
@@ -1,17 +1,19 @@
 use crate::intercept_conf::InterceptConf;
-use crate::ipc::PacketWithMeta;
+use crate::ipc::{FromRedirector, PacketWithMeta};
 use crate::messages::{
     NetworkCommand, NetworkEvent, SmolPacket, TransportCommand, TransportEvent, TunnelInfo,
 };
 use crate::network::add_network_layer;
 use crate::{ipc, shutdown, MAX_PACKET_SIZE};
 use anyhow::{anyhow, Context, Result};
+use futures_util::{Sink, SinkExt, Stream, StreamExt};
+use pretty_hex::pretty_hex;
 use prost::bytes::{Bytes, BytesMut};
 use prost::Message;
 use std::future::Future;
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::sync::mpsc;
-use tokio::sync::mpsc::{Sender, UnboundedReceiver};
+use tokio::sync::mpsc::{Receiver, Sender, UnboundedReceiver};
+use tokio::task::JoinHandle;
 
 #[cfg(target_os = "linux")]
 pub mod linux;
@@ -44,92 +46,396 @@ pub trait PacketSourceTask: Send {
 
 pub const IPC_BUF_SIZE: usize = MAX_PACKET_SIZE + 1024;
 
+/// The version of the IPC protocol between mitmproxy and the redirectors.
+/// Bump this whenever the protocol changes in an incompatible way.
+pub const PROTOCOL_VERSION: u32 = 6;
+
+/// Exchange [`PROTOCOL_VERSION`] with the redirector and make sure we speak the same protocol.
+///
+/// Returns whether frame compression has been negotiated.
+#[allow(dead_code)]
+async fn handshake<T>(channel: &mut T) -> Result<bool>
+where
+    T: Stream<Item = std::io::Result<BytesMut>> + Sink<Bytes, Error = std::io::Error> + Unpin,
+{
+    let hello = ipc::FromProxy {
+        message: Some(ipc::from_proxy::Message::Hello(ipc::Hello {
+            version: PROTOCOL_VERSION,
+            lz4: true,
+            json: false,
+            pid: Some(std::process::id()),
+            // mitmproxy does not use them yet.
+            connection_events: false,
+        })),
+    };
+    channel
+        .send(Bytes::from(hello.encode_to_vec()))
+        .await
+        .context("failed to send IPC handshake")?;
+
+    let frame = match channel.next().await {
+        Some(Ok(frame)) => frame,
+        Some(Err(e)) => return Err(e).context("failed to read IPC handshake"),
+        None => return Err(anyhow!("redirect daemon exited prematurely.")),
+    };
+    match FromRedirector::decode(frame.as_ref()) {
+        Ok(FromRedirector {
+            message: Some(ipc::from_redirector::Message::Hello(ipc::Hello { version, lz4, .. })),
+        }) => {
+            if version != PROTOCOL_VERSION {
+                return Err(anyhow!(
+                    "Redirector speaks IPC protocol version {}, but mitmproxy expects version {}. \
+                    Make sure that mitmproxy and the redirector are from the same release.",
+                    version,
+                    PROTOCOL_VERSION
+                ));
+            }
+            Ok(lz4)
+        }
+        _ => Err(anyhow!(
+            "Expected IPC handshake from redirector, received: {:?}",
+            &frame
+        )),
+    }
+}
+
 /// Feed packets from a socket into smol, and the other way around.
+///
+/// The channel yields and accepts complete IPC messages.
 #[allow(dead_code)]
-async fn forward_packets<T: AsyncRead + AsyncWrite + Unpin>(
+async fn forward_packets<T>(
     mut channel: T,
     transport_events_tx: Sender<TransportEvent>,
     transport_commands_rx: UnboundedReceiver<TransportCommand>,
-    mut conf_rx: UnboundedReceiver<InterceptConf>,
+    conf_rx: UnboundedReceiver<InterceptConf>,
     shutdown: shutdown::Receiver,
-) -> Result<()> {
-    let mut buf = BytesMut::with_capacity(IPC_BUF_SIZE);
-    let (mut network_task_handle, net_tx, mut net_rx) =
-        add_network_layer(transport_events_tx, transport_commands_rx, shutdown);
-
-    loop {
-        tokio::select! {
-            // Monitor the network task for errors or planned shutdown.
-            // This way we implicitly monitor the shutdown channel.
-            exit = &mut network_task_handle => break exit.context("network task panic")?.context("network task error")?,
-            // pipe through changes to the intercept list
-            Some(conf) = conf_rx.recv() => {
-                let msg = ipc::FromProxy {
-                    message: Some(ipc::from_proxy::Message::InterceptConf(conf.into())),
-                };
-                msg.encode(&mut buf)?;
-
-                // debug!("Sending IPC message to redirector: {} {:?}", buf.len(), buf);
-                channel.write_all_buf(&mut buf).await.context("failed to propagate interception config update")?;
-            },
-            // read packets from the IPC pipe into our network stack.
-            _ = channel.read_buf(&mut buf) => {
-                if buf.is_empty() {
-                    // https://learn.microsoft.com/en-us/windows/win32/ipc/named-pipe-client
-                    // Because the client is reading from the pipe in message-read mode, it is
-                    // possible for the ReadFile operation to return zero after reading a partial
-                    // message. This happens when the message is larger than the read buffer.
-                    //
-                    // We don't support messages larger than the buffer, so this cannot happen.
-                    // Instead, empty reads indicate that the IPC client has disconnected.
-                    return Err(anyhow!("redirect daemon exited prematurely."));
-                }
+) -> Result<()>
+where
+    T: Stream<Item = std::io::Result<BytesMut>> + Sink<Bytes, Error = std::io::Error> + Unpin,
+{
+    let mut forwarder = PacketForwarder::new(
+        transport_events_tx,
+        transport_commands_rx,
+        conf_rx,
+        shutdown,
+    );
+    match forwarder.serve(&mut channel).await? {
+        Served::Shutdown => Ok(()),
+        Served::Disconnected(reason) => Err(reason),
+    }
+}
 
-                let Ok(PacketWithMeta { data, tunnel_info}) = PacketWithMeta::decode(&mut buf) else {
-                    return Err(anyhow!("Received invalid IPC message from redirector: {:?}", &buf));
-                };
-                assert!(buf.is_empty());
+/// Why [`PacketForwarder::serve`] returned.
+#[allow(dead_code)]
+enum Served {
+    /// The network task has exited, usually because mitmproxy is shutting down.
+    Shutdown,
+    /// The connection to the redirector was closed or broke.
+    Disconnected(anyhow::Error),
+}
 
-                // TODO: Use Bytes in SmolPacket to avoid copy
-                let data = data.to_vec();
+/// The network stack behind [`forward_packets`], which outlives individual redirector
+/// connections so that the redirector can reconnect.
+#[allow(dead_code)]
+struct PacketForwarder {
+    network_task_handle: JoinHandle<Result<()>>,
+    net_tx: Sender<NetworkEvent>,
+    net_rx: Receiver<NetworkCommand>,
+    conf_rx: UnboundedReceiver<InterceptConf>,
+    /// The most recent intercept config, replayed when a redirector reconnects.
+    conf: Option<InterceptConf>,
+}
 
-                let Ok(mut packet) = SmolPacket::try_from(data) else {
-                    log::error!("Skipping invalid packet: {:?}", &buf);
-                    continue;
-                };
+#[allow(dead_code)]
+impl PacketForwarder {
+    fn new(
+        transport_events_tx: Sender<TransportEvent>,
+        transport_commands_rx: UnboundedReceiver<TransportCommand>,
+        conf_rx: UnboundedReceiver<InterceptConf>,
+        shutdown: shutdown::Receiver,
+    ) -> Self {
+        let (network_task_handle, net_tx, net_rx) =
+            add_network_layer(transport_events_tx, transport_commands_rx, shutdown);
+        Self {
+            network_task_handle,
+            net_tx,
+            net_rx,
+            conf_rx,
+            conf: None,
+        }
+    }
 
-                // debug!("Receiving packet: {:?}", &packet);
+    /// Handshake with a newly connected redirector and relay packets until either side goes away.
+    async fn serve<T>(&mut self, channel: &mut T) -> Result<Served>
+    where
+        T: Stream<Item = std::io::Result<BytesMut>> + Sink<Bytes, Error = std::io::Error> + Unpin,
+    {
+        let compression = handshake(channel).await?;
 
-                // WinDivert packets do not have correct IP checksums yet, we need fix that here
-                // otherwise smoltcp will be unhappy with us.
-                packet.fill_ip_checksum();
+        if let Some(conf) = self.conf.clone() {
+            if let Err(e) = channel.send(conf_frame(conf, compression)).await {
+                return disconnected(e, "failed to replay interception config");
+            }
+        }
 
-                let event = NetworkEvent::ReceivePacket {
-                    packet,
-                    tunnel_info: TunnelInfo::LocalRedirector {
-                        pid: tunnel_info.as_ref().and_then(|t| t.pid),
-                        process_name: tunnel_info.and_then(|t| t.process_name),
-                        remote_endpoint: None,
-                    },
-                };
-                if net_tx.try_send(event).is_err() {
-                    log::warn!("Dropping incoming packet, TCP channel is full.")
-                };
-            },
-            // write packets from the network stack to the IPC pipe to be reinjected.
-            Some(e) = net_rx.recv() => {
-                match e {
-                    NetworkCommand::SendPacket(packet) => {
-                        let packet = ipc::FromProxy { message: Some(ipc::from_proxy::Message::Packet( ipc::Packet { data: Bytes::from(packet.into_inner()) }))};
-                        assert!(buf.is_empty());
-                        packet.encode(&mut buf)?;
-                        // debug!("Sending packet: {} {:?}", buf.len(), &packet.message.as_ref().unwrap());
-                        channel.write_all_buf(&mut buf).await.context("failed to send packet")?;
+        loop {
+            tokio::select! {
+                // Monitor the network task for errors or planned shutdown.
+                // This way we implicitly monitor the shutdown channel.
+                exit = &mut self.network_task_handle => break exit.context("network task panic")?.context("network task error")?,
+                // pipe through changes to the intercept list
+                Some(conf) = self.conf_rx.recv() => {
+                    self.conf = Some(conf.clone());
+                    if let Err(e) = channel.send(conf_frame(conf, compression)).await {
+                        return disconnected(e, "failed to propagate interception config update");
+                    }
+                },
+                // read packets from the IPC pipe into our network stack.
+                frame = channel.next() => {
+                    let frame = match frame {
+                        Some(Ok(frame)) => frame,
+                        Some(Err(e)) => return disconnected(e, "failed to read from redirect daemon"),
+                        None => return Ok(Served::Disconnected(anyhow!("redirect daemon exited prematurely."))),
+                    };
+                    let frame = ipc::decode_frame(frame, compression).context("failed to decode IPC frame")?;
+                    log::trace!("Received IPC message from redirector:\n{}", pretty_hex(&frame));
+
+                    let Ok(FromRedirector { message: Some(message) }) = FromRedirector::decode(frame.as_ref()) else {
+                        return Err(anyhow!("Received invalid IPC message from redirector: {:?}", &frame));
+                    };
+                    let packets = match message {
+                        ipc::from_redirector::Message::Packet(packet) => vec![packet],
+                        ipc::from_redirector::Message::PacketBatch(batch) => batch.packets,
+                        ipc::from_redirector::Message::Ping(_) => {
+                            let pong = ipc::FromProxy {
+                                message: Some(ipc::from_proxy::Message::Pong(ipc::Pong {})),
+                            };
+                            if let Err(e) = channel.send(ipc::encode_frame(&pong, compression)).await {
+                                return disconnected(e, "failed to send keepalive response");
+                            }
+                            continue;
+                        }
+                        ipc::from_redirector::Message::Error(ipc::Error { code, message }) => {
+                            return Err(anyhow!("Redirector failed (exit code {}): {}", code, message));
+                        }
+                        ipc::from_redirector::Message::Hello(_) => {
+                            log::warn!("Ignoring repeated IPC handshake from redirector.");
+                            continue;
+                        }
+                        ipc::from_redirector::Message::StatsResponse(stats) => {
+                            log::debug!("Redirector stats: {:?}", stats);
+                            continue;
+                        }
+                        ipc::from_redirector::Message::ConnectionTableResponse(table) => {
+                            log::debug!("Redirector connection table: {:?}", table);
+                            continue;
+                        }
+                        ipc::from_redirector::Message::ConnectionEvent(event) => {
+                            log::debug!("Redirector connection event: {:?}", event);
+                            continue;
+                        }
+                        ipc::from_redirector::Message::Warning(ipc::Warning { message }) => {
+                            log::warn!("{}", message);
+                            continue;
+                        }
+                        ipc::from_redirector::Message::OriginalDstResponse(response) => {
+                            log::debug!("Redirector original destination: {:?}", response);
+                            continue;
+                        }
+                    };
+
+                    for PacketWithMeta { data, tunnel_info } in packets {
+                        // TODO: Use Bytes in SmolPacket to avoid copy
+                        let data = data.to_vec();
+
+                        let Ok(mut packet) = SmolPacket::try_from(data) else {
+                            log::error!("Skipping invalid packet: {:?}", &frame);
+                            continue;
+                        };
+
+                        // debug!("Receiving packet: {:?}", &packet);
+
+                        // WinDivert packets do not have correct IP checksums yet, we need fix that here
+                        // otherwise smoltcp will be unhappy with us.
+                        packet.fill_ip_checksum();
+
+                        let event = NetworkEvent::ReceivePacket {
+                            packet,
+                            tunnel_info: TunnelInfo::LocalRedirector {
+                                pid: tunnel_info.as_ref().and_then(|t| t.pid),
+                                process_name: tunnel_info.and_then(|t| t.process_name),
+                                remote_endpoint: None,
+                            },
+                        };
+                        if self.net_tx.try_send(event).is_err() {
+                            log::warn!("Dropping incoming packet, TCP channel is full.")
+                        };
+                    }
+                },
+                // write packets from the network stack to the IPC pipe to be reinjected.
+                Some(e) = self.net_rx.recv() => {
+                    match e {
+                        NetworkCommand::SendPacket(packet) => {
+                            let packet = ipc::FromProxy { message: Some(ipc::from_proxy::Message::Packet( ipc::Packet { data: Bytes::from(packet.into_inner()) }))};
+                            let data = ipc::encode_frame(&packet, compression);
+                            log::trace!("Sending IPC message to redirector:\n{}", pretty_hex(&data));
+                            if let Err(e) = channel.send(data).await {
+                                return disconnected(e, "failed to send packet");
+                            }
+                        }
                     }
                 }
             }
         }
+        log::info!("Redirector shutting down.");
+        let msg = ipc::FromProxy {
+            message: Some(ipc::from_proxy::Message::Shutdown(ipc::Shutdown {})),
+        };
+        if let Err(e) = channel.send(ipc::encode_frame(&msg, compression)).await {
+            log::debug!("Failed to notify redirector about shutdown: {}", e);
+        }
+        Ok(Served::Shutdown)
+    }
+}
+
+#[allow(dead_code)]
+fn conf_frame(conf: InterceptConf, compression: bool) -> Bytes {
+    let msg = ipc::FromProxy {
+        message: Some(ipc::from_proxy::Message::InterceptConf(conf.into())),
+    };
+    let data = ipc::encode_frame(&msg, compression);
+    log::trace!("Sending IPC message to redirector:\n{}", pretty_hex(&data));
+    data
+}
+
+#[allow(dead_code)]
+fn disconnected(e: std::io::Error, context: &'static str) -> Result<Served> {
+    Ok(Served::Disconnected(
+        anyhow::Error::from(e).context(context),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_util::codec::Framed;
+
+    async fn handshake_with(hello: ipc::Hello) -> Result<bool> {
+        let (proxy, redirector) = tokio::io::duplex(1024);
+        let mut proxy = Framed::new(proxy, ipc::codec());
+        let mut redirector = Framed::new(redirector, ipc::codec());
+
+        let hello = FromRedirector {
+            message: Some(ipc::from_redirector::Message::Hello(hello)),
+        };
+        redirector
+            .send(Bytes::from(hello.encode_to_vec()))
+            .await
+            .unwrap();
+        let result = handshake(&mut proxy).await;
+
+        let frame = redirector.next().await.unwrap().unwrap();
+        assert_eq!(
+            ipc::FromProxy::decode(frame.as_ref()).unwrap().message,
+            Some(ipc::from_proxy::Message::Hello(ipc::Hello {
+                version: PROTOCOL_VERSION,
+                lz4: true,
+                json: false,
+                pid: Some(std::process::id()),
+                connection_events: false,
+            }))
+        );
+        result
+    }
+
+    #[tokio::test]
+    async fn handshake_version() {
+        let hello = ipc::Hello {
+            version: PROTOCOL_VERSION,
+            lz4: false,
+            json: false,
+            pid: None,
+            connection_events: false,
+        };
+        assert!(!handshake_with(hello).await.unwrap());
+        let err = handshake_with(ipc::Hello {
+            version: PROTOCOL_VERSION + 1,
+            ..hello
+        })
+        .await
+        .unwrap_err();
+        assert!(err.to_string().contains("protocol version"));
+    }
+
+    #[tokio::test]
+    async fn replay_conf_after_reconnect() {
+        let (transport_events_tx, _transport_events_rx) = mpsc::channel(1);
+        let (_transport_commands_tx, transport_commands_rx) = mpsc::unbounded_channel();
+        let (conf_tx, conf_rx) = mpsc::unbounded_channel();
+        let (_shutdown_tx, shutdown) = shutdown::channel();
+        let mut forwarder = PacketForwarder::new(
+            transport_events_tx,
+            transport_commands_rx,
+            conf_rx,
+            shutdown,
+        );
+        conf_tx
+            .send(InterceptConf::try_from("curl").unwrap())
+            .unwrap();
+
+        // The first connection receives the update, the second one gets it replayed.
+        for _ in 0..2 {
+            let (proxy, redirector) = tokio::io::duplex(1024);
+            let mut proxy = Framed::new(proxy, ipc::codec());
+            let mut redirector = Framed::new(redirector, ipc::codec());
+            let redirector = async move {
+                let hello = FromRedirector {
+                    message: Some(ipc::from_redirector::Message::Hello(ipc::Hello {
+                        version: PROTOCOL_VERSION,
+                        lz4: false,
+                        json: false,
+                        pid: None,
+                        connection_events: false,
+                    })),
+                };
+                redirector
+                    .send(Bytes::from(hello.encode_to_vec()))
+                    .await
+                    .unwrap();
+                let mut messages = Vec::new();
+                for _ in 0..2 {
+                    let frame = redirector.next().await.unwrap().unwrap();
+                    messages.push(ipc::FromProxy::decode(frame.as_ref()).unwrap().message);
+                }
+                messages
+            };
+
+            let (served, messages) = tokio::join!(forwarder.serve(&mut proxy), redirector);
+            assert!(matches!(served.unwrap(), Served::Disconnected(_)));
+            assert!(matches!(
+                messages[0],
+                Some(ipc::from_proxy::Message::Hello(_))
+            ));
+            assert_eq!(
+                messages[1],
+                Some(ipc::from_proxy::Message::InterceptConf(
+                    ipc::InterceptConf {
+                        actions: vec!["curl".to_string()],
+                    }
+                ))
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn handshake_compression() {
+        let hello = ipc::Hello {
+            version: PROTOCOL_VERSION,
+            lz4: true,
+            json: true,
+            pid: None,
+            connection_events: false,
+        };
+        assert!(handshake_with(hello).await.unwrap());
     }
-    log::info!("Redirector shutting down.");
-    Ok(())
 }
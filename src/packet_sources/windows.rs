@@ -1,11 +1,13 @@
 use std::iter;
 use std::os::windows::ffi::OsStrExt;
 use std::path::PathBuf;
+use std::time::Duration;
 
 use anyhow::{anyhow, Result};
 use tokio::net::windows::named_pipe::{NamedPipeServer, PipeMode, ServerOptions};
 use tokio::sync::mpsc::Sender;
 use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio_util::codec::Framed;
 use windows::core::w;
 use windows::core::PCWSTR;
 use windows::Win32::UI::Shell::ShellExecuteW;
@@ -13,10 +15,16 @@ use windows::Win32::UI::Shell::SE_ERR_ACCESSDENIED;
 use windows::Win32::UI::WindowsAndMessaging::{SW_HIDE, SW_SHOWNORMAL};
 
 use crate::intercept_conf::InterceptConf;
+use crate::ipc;
 use crate::messages::{TransportCommand, TransportEvent};
-use crate::packet_sources::{forward_packets, PacketSourceConf, PacketSourceTask, IPC_BUF_SIZE};
+use crate::packet_sources::{
+    PacketForwarder, PacketSourceConf, PacketSourceTask, Served, IPC_BUF_SIZE,
+};
 use crate::shutdown;
 
+/// How long to wait for the redirector to reopen the pipe after the connection broke.
+const RECONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
 pub struct WindowsConf {
     pub executable_path: PathBuf,
 }
@@ -41,7 +49,7 @@ impl PacketSourceConf for WindowsConf {
         );
 
         let ipc_server = ServerOptions::new()
-            .pipe_mode(PipeMode::Message)
+            .pipe_mode(PipeMode::Byte)
             .first_pipe_instance(true)
             .max_instances(1)
             .in_buffer_size(IPC_BUF_SIZE as u32)
@@ -117,17 +125,37 @@ pub struct WindowsTask {
 
 impl PacketSourceTask for WindowsTask {
     async fn run(self) -> Result<()> {
-        log::debug!("Waiting for IPC connection...");
-        self.ipc_server.connect().await?;
-        log::debug!("IPC connected!");
-
-        forward_packets(
-            self.ipc_server,
+        let mut shutdown = self.shutdown.clone();
+        let mut forwarder = PacketForwarder::new(
             self.transport_events_tx,
             self.transport_commands_rx,
             self.conf_rx,
             self.shutdown,
-        )
-        .await
+        );
+        let mut channel = Framed::new(self.ipc_server, ipc::codec());
+
+        log::debug!("Waiting for IPC connection...");
+        channel.get_ref().connect().await?;
+        loop {
+            log::debug!("IPC connected!");
+            let reason = match forwarder.serve(&mut channel).await? {
+                Served::Shutdown => return Ok(()),
+                Served::Disconnected(reason) => reason,
+            };
+
+            // The redirector tries to reopen the pipe if it is still running.
+            log::warn!(
+                "Lost IPC connection: {:#}. Waiting for the redirector to reconnect...",
+                reason
+            );
+            let ipc_server = channel.into_inner();
+            ipc_server.disconnect()?;
+            tokio::select! {
+                connected = ipc_server.connect() => connected?,
+                _ = shutdown.recv() => return Ok(()),
+                _ = tokio::time::sleep(RECONNECT_TIMEOUT) => return Err(reason),
+            }
+            channel = Framed::new(ipc_server, ipc::codec());
+        }
     }
 }
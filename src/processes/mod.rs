@@ -12,6 +12,10 @@ mod windows_list;
 pub use self::windows_list::active_executables;
 #[cfg(windows)]
 pub use self::windows_list::get_process_name;
+#[cfg(windows)]
+pub use self::windows_list::ParentProcessCache;
+#[cfg(windows)]
+pub use self::windows_list::ProcessNameCache;
 
 #[cfg(target_os = "macos")]
 mod macos_icons;
@@ -6,20 +6,25 @@ use std::mem::size_of;
 use std::os::windows::prelude::{OsStrExt, OsStringExt};
 use std::path::{Path, PathBuf};
 use std::sync::Mutex;
+use std::time::Duration;
 
 use anyhow::{anyhow, Result};
+use lru_time_cache::LruCache;
 use once_cell::sync::Lazy;
 use windows::core::w;
 use windows::core::{PCWSTR, PWSTR};
-use windows::Win32::Foundation::{CloseHandle, BOOL, HANDLE, HWND, LPARAM, MAX_PATH};
+use windows::Win32::Foundation::{CloseHandle, BOOL, FILETIME, HANDLE, HWND, LPARAM, MAX_PATH};
 use windows::Win32::Graphics::Dwm::{DwmGetWindowAttribute, DWMWA_CLOAKED};
 use windows::Win32::Storage::FileSystem::{
     GetFileVersionInfoSizeW, GetFileVersionInfoW, VerQueryValueW,
 };
+use windows::Win32::System::Diagnostics::ToolHelp::{
+    CreateToolhelp32Snapshot, Process32FirstW, Process32NextW, PROCESSENTRY32W, TH32CS_SNAPPROCESS,
+};
 use windows::Win32::System::ProcessStatus::EnumProcesses;
 use windows::Win32::System::Threading::{
-    IsProcessCritical, OpenProcess, QueryFullProcessImageNameW, PROCESS_NAME_NATIVE,
-    PROCESS_NAME_WIN32, PROCESS_QUERY_LIMITED_INFORMATION,
+    GetProcessTimes, IsProcessCritical, OpenProcess, QueryFullProcessImageNameW,
+    PROCESS_NAME_NATIVE, PROCESS_NAME_WIN32, PROCESS_QUERY_LIMITED_INFORMATION,
 };
 use windows::Win32::UI::WindowsAndMessaging::{
     EnumWindows, GetWindowThreadProcessId, IsIconic, IsWindowVisible,
@@ -53,6 +58,126 @@ unsafe fn process_name(handle: HANDLE) -> Result<PathBuf> {
     Ok(PathBuf::from(OsString::from_wide(path.as_wide())))
 }
 
+/// A cache for process names, keyed by PID.
+///
+/// PIDs are reused by the operating system, so each entry also stores the process creation
+/// time. A lookup still needs to open the process, but saves the image name query.
+pub struct ProcessNameCache {
+    names: LruCache<PID, (u64, Option<String>)>,
+}
+
+impl Default for ProcessNameCache {
+    fn default() -> Self {
+        Self {
+            names: LruCache::with_capacity(1024),
+        }
+    }
+}
+
+impl ProcessNameCache {
+    /// Get the executable path of a process, if it can be determined.
+    pub fn get(&mut self, pid: PID) -> Option<String> {
+        unsafe {
+            let handle = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, false, pid).ok()?;
+            let name = self.lookup(pid, handle);
+            let _ = CloseHandle(handle);
+            name
+        }
+    }
+
+    unsafe fn lookup(&mut self, pid: PID, handle: HANDLE) -> Option<String> {
+        let created = creation_time(handle).ok()?;
+        if let Some((cached_created, name)) = self.names.get(&pid) {
+            if *cached_created == created {
+                return name.clone();
+            }
+        }
+        let name = process_name(handle)
+            .ok()
+            .map(|x| x.to_string_lossy().into_owned());
+        self.names.insert(pid, (created, name.clone()));
+        name
+    }
+}
+
+/// How long parent PIDs are cached. Processes rarely change their parent, but PIDs are reused.
+const PARENT_CACHE_EXPIRY: Duration = Duration::from_secs(10);
+/// Stop walking up the process tree after this many ancestors, in case of a cycle.
+const MAX_ANCESTORS: usize = 64;
+
+/// A cache for the parent of each process, keyed by PID.
+///
+/// Looking up a parent requires a snapshot of all processes, so a cache miss refreshes the
+/// parents of all running processes at once. Parent PIDs may refer to processes that have
+/// exited, or that have been replaced by an unrelated process with the same PID.
+pub struct ParentProcessCache {
+    /// `None` for processes that were not running at the last refresh.
+    parents: LruCache<PID, Option<PID>>,
+}
+
+impl Default for ParentProcessCache {
+    fn default() -> Self {
+        Self {
+            parents: LruCache::with_expiry_duration(PARENT_CACHE_EXPIRY),
+        }
+    }
+}
+
+impl ParentProcessCache {
+    /// The parent of a process, its parent, and so on, up to the first one that is not running.
+    pub fn ancestors(&mut self, pid: PID) -> Vec<PID> {
+        let mut ancestors = Vec::new();
+        let mut current = pid;
+        while ancestors.len() < MAX_ANCESTORS {
+            let Some(parent) = self.parent(current) else {
+                break;
+            };
+            // The idle process is its own parent, and 0 is used for "no parent".
+            if parent == 0 || parent == current || ancestors.contains(&parent) {
+                break;
+            }
+            ancestors.push(parent);
+            current = parent;
+        }
+        ancestors
+    }
+
+    fn parent(&mut self, pid: PID) -> Option<PID> {
+        if let Some(parent) = self.parents.get(&pid) {
+            return *parent;
+        }
+        if let Err(e) = unsafe { self.refresh() } {
+            log::debug!("Failed to take a process snapshot: {:#}", e);
+        }
+        *self.parents.entry(pid).or_insert(None)
+    }
+
+    unsafe fn refresh(&mut self) -> Result<()> {
+        let snapshot = CreateToolhelp32Snapshot(TH32CS_SNAPPROCESS, 0)?;
+        let mut entry = PROCESSENTRY32W {
+            dwSize: size_of::<PROCESSENTRY32W>() as u32,
+            ..Default::default()
+        };
+        let mut next = Process32FirstW(snapshot, &mut entry);
+        while next.is_ok() {
+            self.parents
+                .insert(entry.th32ProcessID, Some(entry.th32ParentProcessID));
+            next = Process32NextW(snapshot, &mut entry);
+        }
+        CloseHandle(snapshot)?;
+        Ok(())
+    }
+}
+
+unsafe fn creation_time(handle: HANDLE) -> Result<u64> {
+    let mut creation = FILETIME::default();
+    let mut exit = FILETIME::default();
+    let mut kernel = FILETIME::default();
+    let mut user = FILETIME::default();
+    GetProcessTimes(handle, &mut creation, &mut exit, &mut kernel, &mut user)?;
+    Ok(((creation.dwHighDateTime as u64) << 32) | creation.dwLowDateTime as u64)
+}
+
 pub fn get_is_critical(pid: PID) -> Result<bool> {
     unsafe {
         let handle = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, false, pid)?;
@@ -311,6 +436,17 @@ mod tests {
         assert!(name.as_os_str().to_string_lossy().contains("mitmproxy"));
     }
 
+    #[test]
+    fn process_name_cache() {
+        let mut cache = super::ProcessNameCache::default();
+        let name = super::get_process_name(std::process::id())
+            .unwrap()
+            .to_string_lossy()
+            .into_owned();
+        assert_eq!(cache.get(std::process::id()), Some(name.clone()));
+        assert_eq!(cache.get(std::process::id()), Some(name));
+    }
+
     #[test]
     fn get_executable_name() {
         let mut d = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
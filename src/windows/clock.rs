@@ -0,0 +1,26 @@
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use windows::Win32::System::Performance::{QueryPerformanceCounter, QueryPerformanceFrequency};
+
+/// The time elapsed since `timestamp`, a value of the performance counter.
+///
+/// WinDivert timestamps packets with the performance counter when it captures them.
+/// Returns `None` for unset (zero) timestamps and timestamps in the future.
+pub fn elapsed_since(timestamp: i64) -> Option<Duration> {
+    static FREQUENCY: OnceLock<i64> = OnceLock::new();
+    let frequency = *FREQUENCY.get_or_init(|| {
+        let mut frequency = 0;
+        // Cannot fail on Windows XP and later.
+        let _ = unsafe { QueryPerformanceFrequency(&mut frequency) };
+        frequency
+    });
+    let mut now = 0;
+    unsafe { QueryPerformanceCounter(&mut now) }.ok()?;
+    if timestamp <= 0 || frequency <= 0 || now < timestamp {
+        return None;
+    }
+    let ticks = (now - timestamp) as u128;
+    let nanos = ticks * 1_000_000_000 / frequency as u128;
+    Some(Duration::from_nanos(nanos as u64))
+}
@@ -0,0 +1,26 @@
+use std::mem::size_of;
+
+use anyhow::Result;
+use windows::Win32::Foundation::{CloseHandle, HANDLE};
+use windows::Win32::Security::{GetTokenInformation, TokenElevation, TOKEN_ELEVATION, TOKEN_QUERY};
+use windows::Win32::System::Threading::{GetCurrentProcess, OpenProcessToken};
+
+/// Whether the current process runs with administrator privileges.
+pub fn is_elevated() -> Result<bool> {
+    unsafe {
+        let mut token = HANDLE::default();
+        OpenProcessToken(GetCurrentProcess(), TOKEN_QUERY, &mut token)?;
+        let mut elevation = TOKEN_ELEVATION::default();
+        let mut len = 0;
+        let result = GetTokenInformation(
+            token,
+            TokenElevation,
+            Some(&mut elevation as *mut TOKEN_ELEVATION as *mut _),
+            size_of::<TOKEN_ELEVATION>() as u32,
+            &mut len,
+        );
+        CloseHandle(token)?;
+        result?;
+        Ok(elevation.TokenIsElevated != 0)
+    }
+}
@@ -1 +1,4 @@
+pub mod clock;
+pub mod elevation;
 pub mod network;
+pub mod pipe;
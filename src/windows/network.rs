@@ -1,12 +1,16 @@
 use anyhow::{anyhow, Result};
-use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6};
 use windows::Win32::Foundation::{ERROR_INSUFFICIENT_BUFFER, NO_ERROR};
 use windows::Win32::NetworkManagement::IpHelper::{
-    GetExtendedTcpTable, GetExtendedUdpTable, MIB_TCP6TABLE_OWNER_PID, MIB_TCPTABLE_OWNER_PID,
-    MIB_UDP6TABLE_OWNER_PID, MIB_UDPTABLE_OWNER_PID, TCP_TABLE_OWNER_PID_ALL, UDP_TABLE_OWNER_PID,
+    FreeMibTable, GetExtendedTcpTable, GetExtendedUdpTable, GetUnicastIpAddressTable,
+    MIB_TCP6TABLE_OWNER_PID, MIB_TCPTABLE_OWNER_PID, MIB_UDP6TABLE_OWNER_PID,
+    MIB_UDPTABLE_OWNER_PID, MIB_UNICASTIPADDRESS_TABLE, TCP_TABLE_OWNER_PID_ALL,
+    UDP_TABLE_OWNER_PID,
 };
 use windows::Win32::Networking::WinSock::{AF_INET, AF_INET6};
 
+use crate::packet::is_link_local;
+
 #[derive(Debug, Clone)]
 pub struct NetworkTableEntry {
     pub protocol: u8,
@@ -84,14 +88,21 @@ pub fn network_table() -> Result<Vec<NetworkTableEntry>> {
     let table = unsafe { &*(buf.as_ptr() as *const MIB_TCP6TABLE_OWNER_PID) };
     for i in 0..table.dwNumEntries {
         let row = unsafe { &*(table.table.as_ptr().add(i as usize)) };
-        let local_addr = SocketAddr::new(
-            Ipv6Addr::from(row.ucLocalAddr).into(),
+        // Scope ids are only set for link-local addresses.
+        let local_addr = SocketAddrV6::new(
+            Ipv6Addr::from(row.ucLocalAddr),
             (row.dwLocalPort as u16).to_be(),
-        );
-        let remote_addr = SocketAddr::new(
-            Ipv6Addr::from(row.ucRemoteAddr).into(),
+            0,
+            row.dwLocalScopeId,
+        )
+        .into();
+        let remote_addr = SocketAddrV6::new(
+            Ipv6Addr::from(row.ucRemoteAddr),
             (row.dwRemotePort as u16).to_be(),
-        );
+            0,
+            row.dwRemoteScopeId,
+        )
+        .into();
         entries.push(NetworkTableEntry {
             protocol: 0x06,
             pid: row.dwOwningPid,
@@ -159,10 +170,13 @@ pub fn network_table() -> Result<Vec<NetworkTableEntry>> {
     let table = unsafe { &*(buf.as_ptr() as *const MIB_UDP6TABLE_OWNER_PID) };
     for i in 0..table.dwNumEntries {
         let row = unsafe { &*(table.table.as_ptr().add(i as usize)) };
-        let local_addr = SocketAddr::new(
-            Ipv6Addr::from(row.ucLocalAddr).into(),
+        let local_addr = SocketAddrV6::new(
+            Ipv6Addr::from(row.ucLocalAddr),
             (row.dwLocalPort as u16).to_be(),
-        );
+            0,
+            row.dwLocalScopeId,
+        )
+        .into();
         let remote_addr = SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0);
         entries.push(NetworkTableEntry {
             protocol: 0x11,
@@ -204,3 +218,24 @@ mod tests {
         assert_eq!(udp_pid, std::process::id());
     }
 }
+
+/// Return the link-local IPv6 addresses of this machine, with the index of their interface.
+pub fn link_local_addresses() -> Result<Vec<(Ipv6Addr, u32)>> {
+    let mut table: *mut MIB_UNICASTIPADDRESS_TABLE = std::ptr::null_mut();
+    let res = unsafe { GetUnicastIpAddressTable(AF_INET6, &mut table) };
+    if res != NO_ERROR {
+        return Err(anyhow!("failed to get unicast address table"));
+    }
+    let mut addresses = Vec::new();
+    unsafe {
+        for i in 0..(*table).NumEntries {
+            let row = &*((*table).Table.as_ptr().add(i as usize));
+            let ip = Ipv6Addr::from(row.Address.Ipv6.sin6_addr.u.Byte);
+            if is_link_local(ip.into()) {
+                addresses.push((ip, row.InterfaceIndex));
+            }
+        }
+        FreeMibTable(table as *const _);
+    }
+    Ok(addresses)
+}
@@ -0,0 +1,55 @@
+use std::ffi::c_void;
+use std::mem::size_of;
+
+use anyhow::{Context, Result};
+use windows::core::w;
+use windows::Win32::Foundation::{LocalFree, BOOL, HLOCAL};
+use windows::Win32::Security::Authorization::{
+    ConvertStringSecurityDescriptorToSecurityDescriptorW, SDDL_REVISION_1,
+};
+use windows::Win32::Security::{PSECURITY_DESCRIPTOR, SECURITY_ATTRIBUTES};
+
+/// Security attributes for named pipes that only Administrators and SYSTEM may open.
+///
+/// By default, named pipes created by an elevated process can still be read by everyone.
+pub struct PipeSecurity {
+    descriptor: PSECURITY_DESCRIPTOR,
+    attributes: SECURITY_ATTRIBUTES,
+}
+
+impl PipeSecurity {
+    pub fn admin_only() -> Result<Self> {
+        let mut descriptor = PSECURITY_DESCRIPTOR::default();
+        unsafe {
+            // Protected DACL, full access for SYSTEM and the built-in Administrators group.
+            ConvertStringSecurityDescriptorToSecurityDescriptorW(
+                w!("D:P(A;;GA;;;SY)(A;;GA;;;BA)"),
+                SDDL_REVISION_1,
+                &mut descriptor,
+                None,
+            )
+        }
+        .context("Cannot create security descriptor")?;
+        Ok(Self {
+            descriptor,
+            attributes: SECURITY_ATTRIBUTES {
+                nLength: size_of::<SECURITY_ATTRIBUTES>() as u32,
+                lpSecurityDescriptor: descriptor.0,
+                bInheritHandle: BOOL(0),
+            },
+        })
+    }
+
+    /// A pointer to the `SECURITY_ATTRIBUTES`, valid for as long as `self` is.
+    pub fn as_raw(&mut self) -> *mut c_void {
+        &mut self.attributes as *mut SECURITY_ATTRIBUTES as *mut c_void
+    }
+}
+
+impl Drop for PipeSecurity {
+    fn drop(&mut self) {
+        unsafe {
+            LocalFree(HLOCAL(self.descriptor.0));
+        }
+    }
+}